@@ -0,0 +1,283 @@
+//! Admission control of the API routes.
+//!
+//! Each route has a limit of requests handled concurrently and of requests
+//! waiting for their turn; requests beyond that are shed with
+//! `503 Service Unavailable`, as are requests which waited too long. Profile
+//! queries have the tightest latency requirement, so lower priority routes
+//! (ingestion and aggregates) shed their requests whenever profile queries
+//! have to wait.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::log;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RouteLimits {
+    /// Requests handled concurrently.
+    pub max_in_flight: usize,
+    /// Requests waiting for one of those handled to finish.
+    pub max_queued: usize,
+    /// How long a request may wait before it is shed.
+    pub max_wait: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub user_tags: RouteLimits,
+    pub user_profiles: RouteLimits,
+    pub aggregates: RouteLimits,
+    /// Sent to shed clients in the `Retry-After` header.
+    pub retry_after: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            user_tags: RouteLimits {
+                max_in_flight: 512,
+                max_queued: 2048,
+                max_wait: Duration::from_millis(150),
+            },
+            user_profiles: RouteLimits {
+                max_in_flight: 256,
+                max_queued: 1024,
+                max_wait: Duration::from_millis(150),
+            },
+            aggregates: RouteLimits {
+                max_in_flight: 64,
+                max_queued: 256,
+                max_wait: Duration::from_secs(1),
+            },
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    UserTags,
+    UserProfiles,
+    Aggregates,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Rejection {
+    QueueFull,
+    TimedOut,
+    /// Gave way to a higher priority route.
+    Yielded,
+}
+
+#[derive(Debug)]
+struct Gate {
+    limits: RouteLimits,
+    permits: Semaphore,
+    queued: AtomicUsize,
+    shed: AtomicU64,
+}
+
+impl Gate {
+    fn new(limits: RouteLimits) -> Self {
+        Self {
+            limits,
+            permits: Semaphore::new(limits.max_in_flight),
+            queued: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    fn is_congested(&self) -> bool {
+        self.queued.load(Ordering::Relaxed) > 0
+    }
+
+    async fn enter(&self) -> Result<SemaphorePermit<'_>, Rejection> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Ok(permit);
+        }
+        let queued = Queued::new(&self.queued);
+        if queued.position >= self.limits.max_queued {
+            return Err(Rejection::QueueFull);
+        }
+        let permit = tokio::time::timeout(self.limits.max_wait, self.permits.acquire()).await;
+        drop(queued);
+        match permit {
+            Ok(permit) => Ok(permit.expect("Admission semaphore closed")),
+            Err(_) => Err(Rejection::TimedOut),
+        }
+    }
+}
+
+/// Place of a request in the queue of a gate, left when dropped, also when
+/// the request is dropped while waiting.
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+    /// Number of requests queued before this one.
+    position: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        Self {
+            queued,
+            position: queued.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub user_tags_shed: u64,
+    pub user_profiles_shed: u64,
+    pub aggregates_shed: u64,
+}
+
+#[derive(Debug)]
+pub struct Admission {
+    retry_after: Duration,
+    user_tags: Gate,
+    user_profiles: Gate,
+    aggregates: Gate,
+}
+
+impl Admission {
+    pub fn new(config: Config) -> Self {
+        Self {
+            retry_after: config.retry_after,
+            user_tags: Gate::new(config.user_tags),
+            user_profiles: Gate::new(config.user_profiles),
+            aggregates: Gate::new(config.aggregates),
+        }
+    }
+
+    fn gate(&self, route: Route) -> &Gate {
+        match route {
+            Route::UserTags => &self.user_tags,
+            Route::UserProfiles => &self.user_profiles,
+            Route::Aggregates => &self.aggregates,
+        }
+    }
+
+    async fn enter(&self, route: Route) -> Result<SemaphorePermit<'_>, Rejection> {
+        if route != Route::UserProfiles && self.user_profiles.is_congested() {
+            return Err(Rejection::Yielded);
+        }
+        self.gate(route).enter().await
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            user_tags_shed: self.user_tags.shed.load(Ordering::Relaxed),
+            user_profiles_shed: self.user_profiles.shed.load(Ordering::Relaxed),
+            aggregates_shed: self.aggregates.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Middleware admitting requests to `route`.
+pub async fn admit<B>(
+    State((admission, route)): State<(Arc<Admission>, Route)>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match admission.enter(route).await {
+        Ok(_permit) => next.run(request).await,
+        Err(rejection) => {
+            log::debug!("Shedding {:?} request: {:?}", route, rejection);
+            admission.gate(route).shed.fetch_add(1, Ordering::Relaxed);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(
+                    header::RETRY_AFTER,
+                    admission.retry_after.as_secs().max(1).to_string(),
+                )],
+                "server is overloaded, retry later",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_in_flight: usize, max_queued: usize) -> RouteLimits {
+        RouteLimits {
+            max_in_flight,
+            max_queued,
+            max_wait: Duration::from_millis(50),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_limits_are_shed() {
+        let gate = Gate::new(limits(1, 1));
+
+        let in_flight = gate.enter().await.unwrap();
+        let (queued, full) = tokio::join!(gate.enter(), async {
+            tokio::task::yield_now().await;
+            gate.enter().await
+        });
+        assert_eq!(queued.unwrap_err(), Rejection::TimedOut);
+        assert_eq!(full.unwrap_err(), Rejection::QueueFull);
+
+        drop(in_flight);
+        assert!(gate.enter().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ingestion_yields_to_waiting_profile_queries() {
+        let admission = Admission::new(Config {
+            user_tags: limits(1, 1),
+            user_profiles: limits(1, 1),
+            aggregates: limits(1, 1),
+            retry_after: Duration::from_secs(1),
+        });
+
+        let _profile = admission.enter(Route::UserProfiles).await.unwrap();
+        assert!(admission.enter(Route::UserTags).await.is_ok());
+
+        let (waiting_profile, tags) = tokio::join!(admission.enter(Route::UserProfiles), async {
+            tokio::task::yield_now().await;
+            admission.enter(Route::UserTags).await
+        });
+        assert_eq!(tags.unwrap_err(), Rejection::Yielded);
+        assert_eq!(waiting_profile.unwrap_err(), Rejection::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn dropped_waiters_leave_the_queue() {
+        let admission = Admission::new(Config {
+            user_tags: limits(1, 1),
+            user_profiles: limits(1, 1),
+            aggregates: limits(1, 1),
+            retry_after: Duration::from_secs(1),
+        });
+
+        let _profile = admission.enter(Route::UserProfiles).await.unwrap();
+        // As when the client of a waiting profile query disconnects.
+        let waiting_profile = admission.enter(Route::UserProfiles);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), waiting_profile)
+                .await
+                .is_err()
+        );
+        assert!(admission.enter(Route::UserTags).await.is_ok());
+    }
+}
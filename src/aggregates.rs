@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Action, Bucket, Device, UserTag, UtcMinute};
+
+/// Property of tags that use case 3 queries may filter on.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Dimension {
+    Origin,
+    BrandId,
+    CategoryId,
+    Country,
+    Device,
+    /// Entry of the tag's `attributes`; tags without it have an empty value.
+    Attribute(String),
+}
+
+const ATTRIBUTE_PREFIX: &str = "attributes.";
+
+impl Dimension {
+    pub fn value_of<'a>(&self, tag: &'a UserTag) -> &'a str {
+        match self {
+            Dimension::Origin => &tag.origin,
+            Dimension::BrandId => &tag.product_info.brand_id,
+            Dimension::CategoryId => &tag.product_info.category_id,
+            Dimension::Country => &tag.country,
+            Dimension::Device => match tag.device {
+                Device::Pc => "PC",
+                Device::Mobile => "MOBILE",
+                Device::Tv => "TV",
+            },
+            Dimension::Attribute(name) => tag.attributes.get(name).map_or("", String::as_str),
+        }
+    }
+}
+
+/// Name of the query parameter and of the result column.
+impl Display for Dimension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dimension::Origin => f.write_str("origin"),
+            Dimension::BrandId => f.write_str("brand_id"),
+            Dimension::CategoryId => f.write_str("category_id"),
+            Dimension::Country => f.write_str("country"),
+            Dimension::Device => f.write_str("device"),
+            Dimension::Attribute(name) => write!(f, "{}{}", ATTRIBUTE_PREFIX, name),
+        }
+    }
+}
+
+impl FromStr for Dimension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "origin" => Ok(Dimension::Origin),
+            "brand_id" => Ok(Dimension::BrandId),
+            "category_id" => Ok(Dimension::CategoryId),
+            "country" => Ok(Dimension::Country),
+            "device" => Ok(Dimension::Device),
+            _ => match s.strip_prefix(ATTRIBUTE_PREFIX) {
+                // Attribute names end up in column names of the storage.
+                Some(name)
+                    if !name.is_empty()
+                        && name.bytes().all(|byte| {
+                            byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_'
+                        }) =>
+                {
+                    Ok(Dimension::Attribute(name.to_owned()))
+                }
+                Some(_) => Err(format!(
+                    "invalid dimension {:?}: attribute names consist of a-z, 0-9 and _",
+                    s
+                )),
+                None => Err(format!("unknown dimension {:?}", s)),
+            },
+        }
+    }
+}
+
+impl Serialize for Dimension {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Dimension {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Dimensions which aggregates are kept for, in the order of [`AggregateKey::values`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dimensions(Arc<[Dimension]>);
+
+/// The legacy dimensions, so that aggregates persisted before they were
+/// configurable stay readable without `--dimensions`.
+impl Default for Dimensions {
+    fn default() -> Self {
+        Self::legacy()
+    }
+}
+
+impl Dimensions {
+    pub fn new(dimensions: Vec<Dimension>) -> Result<Self, String> {
+        for (i, dimension) in dimensions.iter().enumerate() {
+            if dimensions[..i].contains(dimension) {
+                return Err(format!("dimension {} is given twice", dimension));
+            }
+        }
+        Ok(Self(dimensions.into()))
+    }
+
+    /// Dimensions of aggregates persisted before they were configurable.
+    pub fn legacy() -> Self {
+        Self(Arc::new([
+            Dimension::Origin,
+            Dimension::BrandId,
+            Dimension::CategoryId,
+        ]))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Dimension> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn position(&self, dimension: &Dimension) -> Option<usize> {
+        self.0.iter().position(|kept| kept == dimension)
+    }
+
+    /// Tells whether the filter is on kept dimensions only.
+    pub fn check(&self, filter: &Filter) -> Result<(), String> {
+        match filter
+            .values
+            .keys()
+            .find(|dimension| self.position(dimension).is_none())
+        {
+            Some(dimension) => Err(format!("aggregates are not kept for {}", dimension)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Combination of all dimensions a use case 3 query may filter on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AggregateKey {
+    pub action: Action,
+    /// Values of the tag in each of the [`Dimensions`].
+    pub values: Vec<String>,
+}
+
+impl AggregateKey {
+    pub fn of(tag: &UserTag, dimensions: &Dimensions) -> Self {
+        Self {
+            action: tag.action,
+            values: dimensions
+                .iter()
+                .map(|dimension| dimension.value_of(tag).to_owned())
+                .collect(),
+        }
+    }
+
+    /// The key for other dimensions; dimensions missing in `from` get empty values.
+    fn project(&self, from: &Dimensions, to: &Dimensions) -> Self {
+        Self {
+            action: self.action,
+            values: to
+                .iter()
+                .map(|dimension| {
+                    from.position(dimension)
+                        .map(|i| self.values[i].clone())
+                        .unwrap_or_default()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Filter of a use case 3 query.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Filter {
+    pub action: Action,
+    /// Values of the filtered dimensions; the rest are summed over.
+    pub values: BTreeMap<Dimension, String>,
+}
+
+impl Filter {
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            values: BTreeMap::new(),
+        }
+    }
+
+    pub fn matches(&self, tag: &UserTag) -> bool {
+        self.action == tag.action
+            && self
+                .values
+                .iter()
+                .all(|(dimension, value)| dimension.value_of(tag) == value)
+    }
+
+    /// Positions of the filtered values in keys of `dimensions`, or `None`
+    /// if some filtered dimension is not kept.
+    fn positions(&self, dimensions: &Dimensions) -> Option<Vec<(usize, &str)>> {
+        self.values
+            .iter()
+            .map(|(dimension, value)| Some((dimensions.position(dimension)?, value.as_str())))
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub count: i64,
+    pub sum_price: i64,
+}
+
+impl Counters {
+    pub fn add(&mut self, other: Counters) {
+        self.count += other.count;
+        self.sum_price += other.sum_price;
+    }
+
+    pub fn into_bucket(self, minute: UtcMinute) -> Bucket {
+        Bucket {
+            minute,
+            count: self.count,
+            sum_price: self.sum_price,
+        }
+    }
+}
+
+/// Counters of all tags that happened within a single minute,
+/// grouped by every possible filter value combination.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MinuteAggregates {
+    counters: HashMap<AggregateKey, Counters>,
+}
+
+impl MinuteAggregates {
+    pub fn register(&mut self, tag: &UserTag, dimensions: &Dimensions) {
+        self.add(
+            AggregateKey::of(tag, dimensions),
+            Counters {
+                count: 1,
+                sum_price: tag.product_info.price as i64,
+            },
+        );
+    }
+
+    pub fn add(&mut self, key: AggregateKey, counters: Counters) {
+        self.counters.entry(key).or_default().add(counters);
+    }
+
+    pub fn select(&self, filter: &Filter, dimensions: &Dimensions) -> Counters {
+        let Some(positions) = filter.positions(dimensions) else {
+            return Counters::default();
+        };
+        self.counters
+            .iter()
+            .filter(|(key, _)| {
+                key.action == filter.action
+                    && positions.iter().all(|(i, value)| key.values[*i] == *value)
+            })
+            .fold(Counters::default(), |mut acc, (_, counters)| {
+                acc.add(*counters);
+                acc
+            })
+    }
+
+    /// The counters grouped by other dimensions.
+    pub fn project(self, from: &Dimensions, to: &Dimensions) -> Self {
+        if from == to {
+            return self;
+        }
+        let mut projected = Self::default();
+        for (key, counters) in self.counters {
+            projected.add(key.project(from, to), counters);
+        }
+        projected
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&AggregateKey, &Counters)> {
+        self.counters.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    pub fn remove(&mut self, key: &AggregateKey) {
+        self.counters.remove(key);
+    }
+
+    /// Approximate number of bytes the counters occupy in memory.
+    pub fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .counters
+                .keys()
+                .map(|key| {
+                    std::mem::size_of::<(AggregateKey, Counters)>()
+                        + key
+                            .values
+                            .iter()
+                            .map(|value| std::mem::size_of::<String>() + value.len())
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+    }
+}
+
+/// Iterates over all minutes in `[from, to)`.
+pub fn minutes(from: UtcMinute, to: UtcMinute) -> impl Iterator<Item = UtcMinute> {
+    std::iter::successors(Some(from), |last| Some(last.next())).take_while(move |min| *min < to)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::tests::default_tag;
+
+    use super::*;
+
+    #[test]
+    fn buckets_keep_sums_beyond_i32() {
+        let mut counters = Counters::default();
+        for _ in 0..3 {
+            counters.add(Counters {
+                count: 1,
+                sum_price: i32::MAX as i64,
+            });
+        }
+        let minute = UtcMinute::from(crate::mock::tests::moment_middle());
+        assert_eq!(counters.into_bucket(minute).sum_price, 3 * i32::MAX as i64);
+    }
+
+    #[test]
+    fn aggregates_are_selected_and_projected_by_dimensions() {
+        let dimensions = Dimensions::new(vec![
+            Dimension::Country,
+            Dimension::Attribute("campaign".to_owned()),
+        ])
+        .unwrap();
+        let mut aggregates = MinuteAggregates::default();
+        let mut tag = default_tag();
+        aggregates.register(&tag, &dimensions);
+        tag.attributes
+            .insert("campaign".to_owned(), "spring".to_owned());
+        aggregates.register(&tag, &dimensions);
+        tag.country = "DE".to_owned();
+        aggregates.register(&tag, &dimensions);
+
+        let mut filter = Filter::new(tag.action);
+        assert_eq!(aggregates.select(&filter, &dimensions).count, 3);
+        filter
+            .values
+            .insert("attributes.campaign".parse().unwrap(), "spring".to_owned());
+        assert_eq!(aggregates.select(&filter, &dimensions).count, 2);
+        assert!(filter.matches(&tag));
+        filter
+            .values
+            .insert(Dimension::Country, default_tag().country);
+        assert_eq!(aggregates.select(&filter, &dimensions).count, 1);
+        assert!(!filter.matches(&tag));
+
+        // Origins were not kept, so they are all empty once projected.
+        let projected = aggregates.project(&dimensions, &Dimensions::legacy());
+        let mut filter = Filter::new(tag.action);
+        filter.values.insert(Dimension::Origin, String::new());
+        assert_eq!(projected.select(&filter, &Dimensions::legacy()).count, 3);
+        assert_eq!(projected.len(), 1);
+        assert!(Dimensions::legacy().check(&filter).is_ok());
+        filter.values.insert(Dimension::Device, "PC".to_owned());
+        assert!(Dimensions::legacy().check(&filter).is_err());
+
+        assert!("attributes.Campaign".parse::<Dimension>().is_err());
+        assert!("attributes.".parse::<Dimension>().is_err());
+        assert!(Dimensions::new(vec![Dimension::Device, Dimension::Device]).is_err());
+    }
+}
@@ -0,0 +1,532 @@
+//! HTTP load balancer in front of a pool of allezon servers.
+//!
+//! Tags and profile queries of a cookie are routed to the same backend
+//! (by rendezvous hashing over the healthy ones), so its caches and, in cluster
+//! mode, its partition are hit without an extra hop. The cookie of a tag is
+//! only read from uncompressed JSON; tags in the other formats accepted by
+//! the servers are treated like other requests, which are distributed
+//! round-robin. Backends are health checked with `GET /health`
+//! and are also marked down when a connection to them fails or they do not
+//! answer in time.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tracing::log;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(short, long, default_value = "127.0.0.1")]
+    address: String,
+
+    #[arg(short, long, default_value_t = 8000)]
+    port: u16,
+
+    /// Base URLs of the allezon servers, e.g. `http://10.0.0.1:8080`.
+    #[arg(short, long, num_args = 1.., required = true)]
+    backend: Vec<String>,
+
+    /// Interval between health checks of each backend.
+    #[arg(long, default_value_t = 1000)]
+    health_interval_ms: u64,
+
+    /// How long a backend may take to answer a health check.
+    #[arg(long, default_value_t = 500)]
+    health_timeout_ms: u64,
+
+    /// How long connecting to a backend may take.
+    #[arg(long, default_value_t = 500)]
+    connect_timeout_ms: u64,
+
+    /// How long a backend may take to answer a proxied request.
+    #[arg(long, default_value_t = 10_000)]
+    request_timeout_ms: u64,
+}
+
+/// Upper bounds of the latency histogram buckets; the last bucket is unbounded.
+const LATENCY_BOUNDS_MS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+const RETRY_AFTER_SECS: u64 = 1;
+
+#[derive(Debug, Default)]
+struct Latency {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    total_micros: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BOUNDS_MS.len() + 1],
+}
+
+impl Latency {
+    fn record(&self, elapsed: Duration, failed: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.total_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let millis = elapsed.as_millis() as u64;
+        let bucket = LATENCY_BOUNDS_MS
+            .iter()
+            .position(|&bound| millis < bound)
+            .unwrap_or(LATENCY_BOUNDS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Upper bound of the bucket containing the quantile; `None` if there are
+    /// no requests or it is in the unbounded bucket.
+    fn quantile_ms(&self, quantile: f64) -> Option<u64> {
+        let counts = self
+            .buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let total = counts.iter().sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in counts.into_iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BOUNDS_MS.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug)]
+struct Backend {
+    url: String,
+    healthy: AtomicBool,
+    latency: Latency,
+}
+
+#[derive(Debug, Serialize)]
+struct BackendStats {
+    url: String,
+    healthy: bool,
+    requests: u64,
+    errors: u64,
+    mean_ms: Option<f64>,
+    /// `null` if above the largest histogram bound (1 s).
+    p50_ms: Option<u64>,
+    p99_ms: Option<u64>,
+}
+
+impl Backend {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            // Until the first health check says otherwise.
+            healthy: AtomicBool::new(true),
+            latency: Default::default(),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                log::info!("Backend {} is up", self.url);
+            } else {
+                log::warn!("Backend {} is down", self.url);
+            }
+        }
+    }
+
+    fn stats(&self) -> BackendStats {
+        let requests = self.latency.requests.load(Ordering::Relaxed);
+        let total_micros = self.latency.total_micros.load(Ordering::Relaxed);
+        BackendStats {
+            url: self.url.clone(),
+            healthy: self.is_healthy(),
+            requests,
+            errors: self.latency.errors.load(Ordering::Relaxed),
+            mean_ms: (requests > 0).then(|| total_micros as f64 / requests as f64 / 1000.),
+            p50_ms: self.latency.quantile_ms(0.5),
+            p99_ms: self.latency.quantile_ms(0.99),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pool {
+    backends: Vec<Backend>,
+    next: AtomicUsize,
+    client: reqwest::Client,
+}
+
+impl Pool {
+    fn new(urls: Vec<String>, connect_timeout: Duration, timeout: Duration) -> Self {
+        Self {
+            backends: urls.into_iter().map(Backend::new).collect(),
+            next: AtomicUsize::new(0),
+            client: reqwest::Client::builder()
+                .connect_timeout(connect_timeout)
+                .timeout(timeout)
+                .build()
+                .expect("Failed to build HTTP client"),
+        }
+    }
+
+    /// Healthy backends ordered by their affinity to the cookie.
+    fn by_cookie(&self, cookie: &str) -> Vec<&Backend> {
+        let score = |backend: &Backend| {
+            let mut hasher = DefaultHasher::new();
+            (cookie, &backend.url).hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut backends = self.healthy().collect::<Vec<_>>();
+        backends.sort_by_cached_key(|backend| std::cmp::Reverse(score(backend)));
+        backends
+    }
+
+    /// Healthy backends, starting with the next one in turn.
+    fn round_robin(&self) -> Vec<&Backend> {
+        let mut backends = self.healthy().collect::<Vec<_>>();
+        if !backends.is_empty() {
+            let len = backends.len();
+            backends.rotate_left(self.next.fetch_add(1, Ordering::Relaxed) % len);
+        }
+        backends
+    }
+
+    fn healthy(&self) -> impl Iterator<Item = &Backend> {
+        self.backends.iter().filter(|backend| backend.is_healthy())
+    }
+
+    /// Sends the request to the first of the backends that accepts a connection
+    /// and answers in time.
+    async fn proxy(
+        &self,
+        backends: Vec<&Backend>,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let path = uri
+            .path_and_query()
+            .map_or(uri.path(), |path| path.as_str());
+        let mut headers = headers.clone();
+        for name in [header::HOST, header::CONTENT_LENGTH, header::CONNECTION] {
+            headers.remove(name);
+        }
+
+        for backend in backends {
+            let started = Instant::now();
+            let result = self
+                .client
+                .post(format!("{}{}", backend.url, path))
+                .headers(headers.clone())
+                .body(body.clone())
+                .send()
+                .await;
+            let response = match result {
+                Ok(response) => response,
+                // The request was not sent, or the backend is stuck, so another
+                // backend is tried. A tag which timed out may end up registered
+                // twice, unless it has an idempotency key.
+                Err(err) if err.is_connect() || err.is_timeout() => {
+                    log::warn!("Failed to get a response of {}: {}", backend.url, err);
+                    backend.latency.record(started.elapsed(), true);
+                    backend.set_healthy(false);
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!("Request to {} failed: {}", backend.url, err);
+                    backend.latency.record(started.elapsed(), true);
+                    return (StatusCode::BAD_GATEWAY, "backend request failed").into_response();
+                }
+            };
+
+            let status = response.status();
+            let mut response_headers = response.headers().clone();
+            for name in [
+                header::CONTENT_LENGTH,
+                header::TRANSFER_ENCODING,
+                header::CONNECTION,
+            ] {
+                response_headers.remove(name);
+            }
+            let body = response.bytes().await;
+            backend
+                .latency
+                .record(started.elapsed(), body.is_err() || status.is_server_error());
+            return match body {
+                Ok(body) => (status, response_headers, body).into_response(),
+                Err(err) => {
+                    log::warn!("Failed to read response of {}: {}", backend.url, err);
+                    (StatusCode::BAD_GATEWAY, "backend response failed").into_response()
+                }
+            };
+        }
+
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            "no backend is available",
+        )
+            .into_response()
+    }
+}
+
+async fn check_health(pool: Weak<Pool>, interval: Duration, timeout: Duration) {
+    loop {
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        let checks = pool.backends.iter().map(|backend| async {
+            let healthy = pool
+                .client
+                .get(format!("{}/health", backend.url))
+                .timeout(timeout)
+                .send()
+                .await
+                .is_ok_and(|response| response.status().is_success());
+            backend.set_healthy(healthy);
+        });
+        futures::future::join_all(checks).await;
+        drop(pool);
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// The only part of a tag needed for routing it.
+#[derive(Deserialize)]
+struct CookieOf {
+    cookie: String,
+}
+
+/// Tags which are not uncompressed JSON are routed round-robin, as the
+/// balancer does not decode the other formats.
+async fn user_tags(
+    State(pool): State<Arc<Pool>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let backends = match serde_json::from_slice::<CookieOf>(&body) {
+        Ok(tag) => pool.by_cookie(&tag.cookie),
+        // Let a backend explain what is wrong with the tag.
+        Err(_) => pool.round_robin(),
+    };
+    pool.proxy(backends, &uri, &headers, body).await
+}
+
+async fn user_profiles(
+    State(pool): State<Arc<Pool>>,
+    Path(cookie): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    pool.proxy(pool.by_cookie(&cookie), &uri, &headers, body)
+        .await
+}
+
+async fn round_robin(
+    State(pool): State<Arc<Pool>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    pool.proxy(pool.round_robin(), &uri, &headers, body).await
+}
+
+async fn metrics(State(pool): State<Arc<Pool>>) -> Json<Vec<BackendStats>> {
+    Json(pool.backends.iter().map(Backend::stats).collect())
+}
+
+fn build_router(pool: Arc<Pool>) -> Router {
+    Router::new()
+        .route("/health", get(|| async { "OK" }))
+        .route("/lb/metrics", get(metrics))
+        .route("/user_tags", post(user_tags))
+        .route("/user_profiles/:cookie", post(user_profiles))
+        .route("/aggregates", post(round_robin))
+        .route("/v2/user_tags", post(user_tags))
+        .route("/v2/user_profiles/:cookie", post(user_profiles))
+        .route("/v2/aggregates", post(round_robin))
+        .route("/clear", post(round_robin))
+        .with_state(pool)
+}
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install CTRL+C signal handler");
+}
+
+#[tokio::main]
+async fn main() {
+    let _ = tracing_subscriber::fmt::try_init();
+    let args = Args::parse();
+
+    let socket_address = (args.address, args.port)
+        .to_socket_addrs()
+        .expect("Failed to parse socket address")
+        .next()
+        .expect("Failed to parse socket address");
+
+    let pool = Arc::new(Pool::new(
+        args.backend,
+        Duration::from_millis(args.connect_timeout_ms),
+        Duration::from_millis(args.request_timeout_ms),
+    ));
+    tokio::spawn(check_health(
+        Arc::downgrade(&pool),
+        Duration::from_millis(args.health_interval_ms),
+        Duration::from_millis(args.health_timeout_ms),
+    ));
+
+    log::info!("Starting load balancer on {}", socket_address);
+    axum::Server::bind(&socket_address)
+        .serve(build_router(pool).into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[test]
+    fn latency_quantiles_are_bucket_bounds() {
+        let latency = Latency::default();
+        assert_eq!(latency.quantile_ms(0.5), None);
+        for _ in 0..98 {
+            latency.record(Duration::from_micros(1500), false);
+        }
+        latency.record(Duration::from_millis(30), false);
+        latency.record(Duration::from_secs(2), true);
+        assert_eq!(latency.quantile_ms(0.5), Some(2));
+        assert_eq!(latency.quantile_ms(0.99), Some(50));
+        assert_eq!(latency.quantile_ms(1.), None);
+        assert_eq!(latency.errors.load(Ordering::Relaxed), 1);
+    }
+
+    /// Backend answering every request with its name.
+    fn start_backend(address: SocketAddr, name: &'static str) {
+        let router = Router::new()
+            .route("/health", get(|| async { "OK" }))
+            .route("/user_tags", post(move || async move { name }))
+            .route("/user_profiles/:cookie", post(move || async move { name }))
+            .route("/aggregates", post(move || async move { name }));
+        tokio::spawn(axum::Server::bind(&address).serve(router.into_make_service()));
+    }
+
+    async fn send(client: &reqwest::Client, url: String, body: &str) -> String {
+        client
+            .post(url)
+            .body(body.to_owned())
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_routed_by_cookie_and_around_failures() {
+        let backends = [("a", 21), ("b", 22)];
+        for (name, ip) in backends {
+            start_backend(SocketAddr::from(([127, 0, 0, ip], 9044)), name);
+        }
+        let mut urls = backends
+            .iter()
+            .map(|(_, ip)| format!("http://127.0.0.{}:9044", ip))
+            .collect::<Vec<_>>();
+        // Nothing listens there.
+        urls.push("http://127.0.0.23:9044".to_owned());
+        let pool = Arc::new(Pool::new(
+            urls,
+            Duration::from_millis(500),
+            Duration::from_secs(10),
+        ));
+        let address = SocketAddr::from(([127, 0, 0, 20], 9044));
+        tokio::spawn(
+            axum::Server::bind(&address).serve(build_router(Arc::clone(&pool)).into_make_service()),
+        );
+        let client = reqwest::Client::new();
+        let lb = |path: &str| format!("http://{}{}", address, path);
+
+        for i in 0..20 {
+            let cookie = format!("cookie{}", i);
+            let tag = format!(r#"{{"cookie": "{}"}}"#, cookie);
+            let first = send(&client, lb("/user_tags"), &tag).await;
+            assert_ne!(first, "");
+            assert_eq!(send(&client, lb("/user_tags"), &tag).await, first);
+            let profile = send(&client, lb(&format!("/user_profiles/{}", cookie)), "").await;
+            assert_eq!(profile, first);
+        }
+
+        let mut aggregates = Vec::new();
+        for _ in 0..4 {
+            aggregates.push(send(&client, lb("/aggregates"), "").await);
+        }
+        assert!(aggregates.contains(&"a".to_owned()));
+        assert!(aggregates.contains(&"b".to_owned()));
+
+        let stats = pool.backends.iter().map(Backend::stats).collect::<Vec<_>>();
+        assert!(stats[0].healthy && stats[1].healthy && !stats[2].healthy);
+        assert_eq!(stats[0].requests + stats[1].requests, 64);
+        assert!(stats[0].p50_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn stuck_backends_are_failed_over() {
+        let stuck = SocketAddr::from(([127, 0, 0, 25], 9044));
+        let router = Router::new().route(
+            "/aggregates",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                "stuck"
+            }),
+        );
+        tokio::spawn(axum::Server::bind(&stuck).serve(router.into_make_service()));
+        start_backend(SocketAddr::from(([127, 0, 0, 26], 9044)), "a");
+        let urls = vec![
+            "http://127.0.0.25:9044".to_owned(),
+            "http://127.0.0.26:9044".to_owned(),
+        ];
+        let pool = Arc::new(Pool::new(
+            urls,
+            Duration::from_millis(500),
+            Duration::from_millis(200),
+        ));
+        let address = SocketAddr::from(([127, 0, 0, 27], 9044));
+        tokio::spawn(
+            axum::Server::bind(&address).serve(build_router(Arc::clone(&pool)).into_make_service()),
+        );
+        let client = reqwest::Client::new();
+
+        for _ in 0..2 {
+            let url = format!("http://{}/aggregates", address);
+            assert_eq!(send(&client, url, "").await, "a");
+        }
+        let stats = pool.backends.iter().map(Backend::stats).collect::<Vec<_>>();
+        assert!(!stats[0].healthy && stats[1].healthy);
+    }
+}
@@ -0,0 +1,1172 @@
+//! Cluster mode: several allezon nodes with a static membership.
+//!
+//! Profiles are partitioned by consistent hashing of the cookie: each tag is
+//! registered on the replicas of its cookie, the first of which (the primary)
+//! also counts it in aggregates, and profiles are read from all replicas and
+//! merged. Writes to replicas which are down are kept as hints and delivered
+//! once they are back; replicas which missed tags are also repaired on reads.
+//! Aggregates of a minute are spread over all nodes, so aggregates queries are
+//! sent to every node and merged; the counts of nodes which are down are
+//! missing from them until those nodes are back. Nodes talk to each other with the
+//! `/internal` routes of [`internal_router`], which require a secret shared
+//! by the nodes.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::log;
+
+use crate::aggregates::{Dimensions, Filter};
+use crate::dedup::IdempotencyKey;
+use crate::types::{
+    self, Action, Bucket, DataScope, MemoryUsage, SnapshotInfo, TagPosition, TagsPage, TimeRange,
+    UserProfile, UserTag, UtcMillis,
+};
+use crate::utils;
+
+/// Points of each node on the ring; more make the partitioning more even.
+const VIRTUAL_NODES: usize = 64;
+const IDEMPOTENCY_KEY_BITS: &str = "x-allezon-idempotency-key";
+const CLUSTER_SECRET: &str = "x-allezon-cluster-secret";
+/// How soon a node which does not accept connections is considered down.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+const HINTS_INTERVAL: Duration = Duration::from_secs(1);
+/// Hints kept for a node which is down; the oldest ones are dropped beyond it.
+const MAX_HINTS_PER_NODE: usize = 100_000;
+
+/// Static assignment of cookies to nodes.
+#[derive(Debug)]
+pub struct Ring {
+    /// Base URLs of all nodes, e.g. `http://10.0.0.1:8080`.
+    nodes: Vec<String>,
+    points: BTreeMap<u64, usize>,
+    replication_factor: usize,
+}
+
+impl Ring {
+    /// Each cookie is assigned to `replication_factor` nodes
+    /// (or all of them, if there are fewer).
+    pub fn new(nodes: Vec<String>, replication_factor: usize) -> Self {
+        assert!(!nodes.is_empty());
+        assert!(replication_factor > 0);
+        let points = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..VIRTUAL_NODES).map(move |i| {
+                    (
+                        utils::stable_hash(format!("{}#{}", node, i).as_bytes()),
+                        index,
+                    )
+                })
+            })
+            .collect();
+        Self {
+            replication_factor: replication_factor.min(nodes.len()),
+            nodes,
+            points,
+        }
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// Indices of the nodes storing the cookie, the primary one first.
+    pub fn replicas(&self, cookie: &str) -> Vec<usize> {
+        let hash = utils::stable_hash(cookie.as_bytes());
+        let mut replicas = Vec::with_capacity(self.replication_factor);
+        for (_, &index) in self.points.range(hash..).chain(self.points.range(..hash)) {
+            if !replicas.contains(&index) {
+                replicas.push(index);
+                if replicas.len() == self.replication_factor {
+                    break;
+                }
+            }
+        }
+        replicas
+    }
+}
+
+/// Registration of a tag on a single replica.
+#[derive(Clone, Debug)]
+struct TagWrite {
+    tag: UserTag,
+    key: Option<IdempotencyKey>,
+    scope: DataScope,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TagQuery {
+    scope: DataScope,
+}
+
+/// Writes not yet delivered to nodes which are down, by node index.
+#[derive(Debug, Default)]
+struct Hints {
+    pending: Mutex<BTreeMap<usize, VecDeque<TagWrite>>>,
+}
+
+impl Hints {
+    fn add(&self, node: usize, write: TagWrite) {
+        let mut pending = self.pending.lock().unwrap();
+        let writes = pending.entry(node).or_default();
+        if writes.len() >= MAX_HINTS_PER_NODE {
+            log::error!("Too many hints for node {}, dropping the oldest one", node);
+            writes.pop_front();
+        }
+        writes.push_back(write);
+    }
+
+    fn first(&self, node: usize) -> Option<TagWrite> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .get(&node)
+            .and_then(|writes| writes.front().cloned())
+    }
+
+    fn delivered(&self, node: usize) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(writes) = pending.get_mut(&node) {
+            writes.pop_front();
+            if writes.is_empty() {
+                pending.remove(&node);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        let pending = self.pending.lock().unwrap();
+        pending.values().map(VecDeque::len).sum()
+    }
+}
+
+/// URL of an `/internal` route of the node, with the path segments escaped.
+fn internal_url(node: &str, segments: &[&str]) -> reqwest::Url {
+    let mut url = reqwest::Url::parse(node).expect("Invalid node URL");
+    url.path_segments_mut()
+        .expect("Invalid node URL")
+        .pop_if_empty()
+        .push("internal")
+        .extend(segments);
+    url
+}
+
+async fn send_tag(client: &reqwest::Client, node: &str, write: &TagWrite) -> reqwest::Result<bool> {
+    let mut request = client
+        .post(internal_url(node, &["user_tags"]))
+        .query(&TagQuery { scope: write.scope })
+        .json(&write.tag);
+    if let Some(key) = write.key {
+        request = request.header(IDEMPOTENCY_KEY_BITS, key.to_bits());
+    }
+    request.send().await?.error_for_status()?.json().await
+}
+
+/// Registers the tag in the node's own backend, unless its key was seen.
+async fn register_local(local: &dyn types::System, write: TagWrite) -> bool {
+    let reservation = match (write.key, local.dedup_window()) {
+        (Some(key), Some(window)) => match window.reserve(key) {
+            Some(reservation) => Some(reservation),
+            None => return false,
+        },
+        _ => None,
+    };
+    local.register_user_tag_in(write.tag, write.scope).await;
+    if let Some(reservation) = reservation {
+        reservation.commit();
+    }
+    true
+}
+
+async fn deliver_hints(hints: Weak<Hints>, client: reqwest::Client, nodes: Vec<String>) {
+    loop {
+        tokio::time::sleep(HINTS_INTERVAL).await;
+        let Some(hints) = hints.upgrade() else {
+            return;
+        };
+        for (index, node) in nodes.iter().enumerate() {
+            let mut delivered = 0;
+            while let Some(write) = hints.first(index) {
+                match send_tag(&client, node, &write).await {
+                    Ok(_) => {
+                        hints.delivered(index);
+                        delivered += 1;
+                    }
+                    // Still down, retried later.
+                    Err(_) => break,
+                }
+            }
+            if delivered > 0 {
+                log::info!(
+                    "Delivered {} hints to node {}, {} hints pending",
+                    delivered,
+                    index,
+                    hints.len()
+                );
+            }
+        }
+    }
+}
+
+/// Most recent `limit` tags of all the replicas' lists.
+fn merge_tags<'a>(lists: impl Iterator<Item = &'a Vec<UserTag>>, limit: usize) -> Vec<UserTag> {
+    let mut merged = BTreeMap::new();
+    for tag in lists.flatten() {
+        merged
+            .entry((tag.time, tag.tie_breaker()))
+            .or_insert_with(|| tag.clone());
+    }
+    merged.into_values().rev().take(limit).collect()
+}
+
+/// Page of the replicas' pages, listing the most recent `limit` of their tags.
+fn merge_pages(pages: Vec<TagsPage>, limit: usize) -> TagsPage {
+    // A replica may miss tags, but not have extra ones.
+    let total = pages.iter().filter_map(|page| page.total).max();
+    let mut more = pages.iter().any(|page| page.next.is_some());
+    let mut merged = BTreeMap::new();
+    for tag in pages.into_iter().flat_map(|page| page.tags) {
+        merged.entry((tag.time, tag.tie_breaker())).or_insert(tag);
+    }
+    let mut merged = merged.into_iter().rev();
+    let listed = merged.by_ref().take(limit).collect::<Vec<_>>();
+    more |= merged.next().is_some();
+    TagsPage {
+        next: listed
+            .last()
+            .map(|(position, _)| *position)
+            .filter(|_| more),
+        tags: listed.into_iter().map(|(_, tag)| tag).collect(),
+        total,
+    }
+}
+
+/// Tags of `merged` which the replica did not return.
+fn missing_tags(merged: &UserProfile, replica: &UserProfile) -> Vec<UserTag> {
+    let present = replica
+        .views
+        .iter()
+        .chain(&replica.buys)
+        .map(|tag| (tag.time, tag.tie_breaker()))
+        .collect::<HashSet<_>>();
+    merged
+        .views
+        .iter()
+        .chain(&merged.buys)
+        .filter(|tag| !present.contains(&(tag.time, tag.tie_breaker())))
+        .cloned()
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProfileQuery {
+    time_from: DateTime<Utc>,
+    time_to: DateTime<Utc>,
+    limit: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PageQuery {
+    action: Action,
+    time_from: DateTime<Utc>,
+    time_to: DateTime<Utc>,
+    after_time: Option<UtcMillis>,
+    after_tie_breaker: Option<i64>,
+    limit: usize,
+}
+
+impl PageQuery {
+    fn time_range(&self) -> TimeRange {
+        TimeRange {
+            from: self.time_from,
+            to: self.time_to,
+        }
+    }
+
+    fn after(&self) -> Option<TagPosition> {
+        self.after_time.zip(self.after_tie_breaker)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AggregatesQuery {
+    time_from: DateTime<Utc>,
+    time_to: DateTime<Utc>,
+    filter: Filter,
+}
+
+/// Minutes are implied by the queried range.
+#[derive(Serialize, Deserialize)]
+struct Counts {
+    count: i64,
+    sum_price: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClearQuery {
+    scope: DataScope,
+    time_from: Option<DateTime<Utc>>,
+    time_to: Option<DateTime<Utc>>,
+}
+
+/// [`types::System`] of a cluster node, storing its partition in `local`.
+pub struct System {
+    local: Arc<dyn types::System>,
+    ring: Ring,
+    self_index: usize,
+    client: reqwest::Client,
+    hints: Arc<Hints>,
+}
+
+impl System {
+    /// Requests to other nodes carry `secret`, as expected by their
+    /// [`internal_router`].
+    pub fn new(local: Arc<dyn types::System>, ring: Ring, self_index: usize, secret: &str) -> Self {
+        assert!(self_index < ring.nodes().len());
+        assert!(
+            local.supports_scopes(),
+            "The storage cannot hold a partition of a cluster"
+        );
+        let mut secret =
+            reqwest::header::HeaderValue::from_str(secret).expect("Invalid cluster secret");
+        secret.set_sensitive(true);
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(CLUSTER_SECRET, secret);
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .expect("Failed to build HTTP client");
+        let hints = Arc::new(Hints::default());
+        tokio::spawn(deliver_hints(
+            Arc::downgrade(&hints),
+            client.clone(),
+            ring.nodes().to_vec(),
+        ));
+        Self {
+            local,
+            ring,
+            self_index,
+            client,
+            hints,
+        }
+    }
+
+    /// Registers the tag on all replicas of its cookie. Returns whether it was
+    /// registered, i.e. its key was not seen, according to the first replica
+    /// which is up.
+    async fn register(&self, tag: UserTag, key: Option<IdempotencyKey>) -> bool {
+        let writes = self
+            .ring
+            .replicas(&tag.cookie)
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| {
+                // Aggregates are merged from all nodes, so only the primary counts the tag.
+                let scope = if i == 0 {
+                    DataScope::All
+                } else {
+                    DataScope::Profiles
+                };
+                let write = TagWrite {
+                    tag: tag.clone(),
+                    key,
+                    scope,
+                };
+                self.register_on(node, write)
+            });
+        futures::future::join_all(writes)
+            .await
+            .into_iter()
+            .flatten()
+            .next()
+            .expect("No replica of the user tag is available")
+    }
+
+    async fn register_on(&self, node: usize, write: TagWrite) -> Option<bool> {
+        if node == self.self_index {
+            return Some(register_local(&*self.local, write).await);
+        }
+        match send_tag(&self.client, &self.ring.nodes()[node], &write).await {
+            Ok(registered) => Some(registered),
+            Err(err) => {
+                log::warn!("Keeping a hint for node {}: {}", node, err);
+                self.hints.add(node, write);
+                None
+            }
+        }
+    }
+
+    async fn profile_on(
+        &self,
+        node: usize,
+        cookie: &str,
+        query: &ProfileQuery,
+    ) -> Option<UserProfile> {
+        if node == self.self_index {
+            return Some(
+                self.local
+                    .last_tags_by_cookie(cookie, query.time_from, query.time_to, query.limit)
+                    .await,
+            );
+        }
+        let response = async {
+            self.client
+                .post(internal_url(
+                    &self.ring.nodes()[node],
+                    &["user_profiles", cookie],
+                ))
+                .query(query)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        };
+        match response.await {
+            Ok(profile) => Some(profile),
+            Err(err) => {
+                log::warn!("Skipping profile of node {}: {}", node, err);
+                None
+            }
+        }
+    }
+
+    async fn page_on(&self, node: usize, cookie: &str, query: &PageQuery) -> Option<TagsPage> {
+        if node == self.self_index {
+            return Some(
+                self.local
+                    .tags_page(
+                        cookie,
+                        query.action,
+                        query.time_range(),
+                        query.after(),
+                        query.limit,
+                    )
+                    .await,
+            );
+        }
+        let response = async {
+            self.client
+                .post(internal_url(
+                    &self.ring.nodes()[node],
+                    &["tags_page", cookie],
+                ))
+                .query(query)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        };
+        match response.await {
+            Ok(page) => Some(page),
+            Err(err) => {
+                log::warn!("Skipping page of node {}: {}", node, err);
+                None
+            }
+        }
+    }
+
+    /// Registers in the background tags which a replica missed.
+    fn repair(&self, node: usize, tags: Vec<UserTag>) {
+        log::debug!("Repairing {} tags on node {}", tags.len(), node);
+        let writes = tags.into_iter().map(|tag| TagWrite {
+            tag,
+            key: None,
+            scope: DataScope::Profiles,
+        });
+        if node == self.self_index {
+            let local = Arc::clone(&self.local);
+            tokio::spawn(async move {
+                for write in writes {
+                    register_local(&*local, write).await;
+                }
+            });
+        } else {
+            let client = self.client.clone();
+            let url = self.ring.nodes()[node].clone();
+            let writes = writes.collect::<Vec<_>>();
+            tokio::spawn(async move {
+                for write in writes {
+                    if let Err(err) = send_tag(&client, &url, &write).await {
+                        // The next read repairs it again.
+                        log::warn!("Failed to repair node {}: {}", node, err);
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    async fn remote_buckets(&self, node: &str, query: &AggregatesQuery) -> Option<Vec<Counts>> {
+        let response = async {
+            self.client
+                .post(internal_url(node, &["aggregates"]))
+                .json(query)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        };
+        match response.await {
+            Ok(counts) => Some(counts),
+            Err(err) => {
+                log::warn!("Skipping aggregates of node {}: {}", node, err);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl types::System for System {
+    async fn register_user_tag(&self, tag: UserTag) {
+        self.register(tag, None).await;
+    }
+
+    /// All nodes are expected to keep aggregates for the same dimensions.
+    fn dimensions(&self) -> &Dimensions {
+        self.local.dimensions()
+    }
+
+    /// Deduplication happens on the replicas.
+    async fn register_user_tag_once(&self, tag: UserTag, key: IdempotencyKey) -> bool {
+        self.register(tag, Some(key)).await
+    }
+
+    async fn last_tags_by_cookie<'a>(
+        &'a self,
+        cookie: &'a str,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        limit: usize,
+    ) -> UserProfile {
+        let query = ProfileQuery {
+            time_from,
+            time_to,
+            limit,
+        };
+        let replicas = self.ring.replicas(cookie);
+        let profiles = futures::future::join_all(
+            replicas
+                .iter()
+                .map(|&node| self.profile_on(node, cookie, &query)),
+        )
+        .await;
+        let profiles = replicas
+            .into_iter()
+            .zip(profiles)
+            .filter_map(|(node, profile)| Some((node, profile?)))
+            .collect::<Vec<_>>();
+        assert!(
+            !profiles.is_empty(),
+            "No replica of the user profile is available"
+        );
+
+        let merged = UserProfile {
+            cookie: cookie.into(),
+            views: merge_tags(profiles.iter().map(|(_, profile)| &profile.views), limit),
+            buys: merge_tags(profiles.iter().map(|(_, profile)| &profile.buys), limit),
+        };
+        for (node, profile) in &profiles {
+            let missing = missing_tags(&merged, profile);
+            if !missing.is_empty() {
+                self.repair(*node, missing);
+            }
+        }
+        merged
+    }
+
+    /// Pages are not read-repaired: reads of profiles repair their newest tags.
+    async fn tags_page(
+        &self,
+        cookie: &str,
+        action: Action,
+        time_range: TimeRange,
+        after: Option<TagPosition>,
+        limit: usize,
+    ) -> TagsPage {
+        let query = PageQuery {
+            action,
+            time_from: time_range.from,
+            time_to: time_range.to,
+            after_time: after.map(|(time, _)| time),
+            after_tie_breaker: after.map(|(_, tie_breaker)| tie_breaker),
+            limit,
+        };
+        let pages = futures::future::join_all(
+            self.ring
+                .replicas(cookie)
+                .into_iter()
+                .map(|node| self.page_on(node, cookie, &query)),
+        )
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        assert!(
+            !pages.is_empty(),
+            "No replica of the user profile is available"
+        );
+        merge_pages(pages, limit)
+    }
+
+    async fn select_bucket_stats(
+        &self,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        filter: &Filter,
+    ) -> Vec<Bucket> {
+        let query = AggregatesQuery {
+            time_from,
+            time_to,
+            filter: filter.clone(),
+        };
+        let remote = self
+            .ring
+            .nodes()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != self.self_index)
+            .map(|(_, node)| self.remote_buckets(node, &query));
+        let (mut buckets, remote) = futures::future::join(
+            self.local.select_bucket_stats(time_from, time_to, filter),
+            futures::future::join_all(remote),
+        )
+        .await;
+
+        for counts in remote.into_iter().flatten() {
+            assert_eq!(counts.len(), buckets.len());
+            for (bucket, counts) in buckets.iter_mut().zip(counts) {
+                bucket.count += counts.count;
+                bucket.sum_price += counts.sum_price;
+            }
+        }
+        buckets
+    }
+
+    async fn clear(&self, scope: DataScope, time_range: Option<TimeRange>) {
+        let query = ClearQuery {
+            scope,
+            time_from: time_range.map(|range| range.from),
+            time_to: time_range.map(|range| range.to),
+        };
+        let remote = self
+            .ring
+            .nodes()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != self.self_index)
+            .map(|(_, node)| {
+                self.client
+                    .post(internal_url(node, &["clear"]))
+                    .query(&query)
+                    .send()
+            });
+        let (_, results) = futures::future::join(
+            self.local.clear(scope, time_range),
+            futures::future::join_all(remote),
+        )
+        .await;
+        for result in results {
+            result
+                .and_then(|response| response.error_for_status())
+                .expect("Failed to clear a node");
+        }
+    }
+
+    /// Snapshots and memory usage are reported for this node only.
+    async fn snapshot(&self) -> Option<io::Result<SnapshotInfo>> {
+        self.local.snapshot().await
+    }
+
+    async fn memory_usage(&self) -> Option<MemoryUsage> {
+        self.local.memory_usage().await
+    }
+}
+
+/// Routes through which nodes access each other's local partitions.
+/// Requests without the cluster's `secret` are rejected.
+pub fn internal_router(local: Arc<dyn types::System>, secret: &str) -> Router {
+    Router::new()
+        .route("/internal/user_tags", post(register_tag))
+        .route("/internal/user_profiles/:cookie", post(profile))
+        .route("/internal/tags_page/:cookie", post(tags_page))
+        .route("/internal/aggregates", post(aggregates))
+        .route("/internal/clear", post(clear))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(secret),
+            authorize_node,
+        ))
+        .with_state(local)
+}
+
+async fn authorize_node<B>(
+    State(secret): State<Arc<str>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let provided = request.headers().get(CLUSTER_SECRET);
+    match provided {
+        Some(provided) if utils::constant_time_eq(provided.as_bytes(), secret.as_bytes()) => {
+            next.run(request).await
+        }
+        Some(_) => (StatusCode::FORBIDDEN, "invalid cluster secret").into_response(),
+        None => (StatusCode::UNAUTHORIZED, "missing cluster secret").into_response(),
+    }
+}
+
+async fn register_tag(
+    State(local): State<Arc<dyn types::System>>,
+    Query(query): Query<TagQuery>,
+    headers: HeaderMap,
+    Json(tag): Json<UserTag>,
+) -> Result<Json<bool>, (StatusCode, String)> {
+    let key = headers
+        .get(IDEMPOTENCY_KEY_BITS)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map(IdempotencyKey::from_bits)
+                .ok_or((
+                    StatusCode::BAD_REQUEST,
+                    "invalid idempotency key".to_owned(),
+                ))
+        })
+        .transpose()?;
+    log::debug!("Registering forwarded user tag");
+    let write = TagWrite {
+        tag,
+        key,
+        scope: query.scope,
+    };
+    Ok(Json(register_local(&*local, write).await))
+}
+
+async fn profile(
+    State(local): State<Arc<dyn types::System>>,
+    Path(cookie): Path<String>,
+    Query(query): Query<ProfileQuery>,
+) -> Json<UserProfile> {
+    Json(
+        local
+            .last_tags_by_cookie(&cookie, query.time_from, query.time_to, query.limit)
+            .await,
+    )
+}
+
+async fn tags_page(
+    State(local): State<Arc<dyn types::System>>,
+    Path(cookie): Path<String>,
+    Query(query): Query<PageQuery>,
+) -> Json<TagsPage> {
+    Json(
+        local
+            .tags_page(
+                &cookie,
+                query.action,
+                query.time_range(),
+                query.after(),
+                query.limit,
+            )
+            .await,
+    )
+}
+
+async fn aggregates(
+    State(local): State<Arc<dyn types::System>>,
+    Json(query): Json<AggregatesQuery>,
+) -> Json<Vec<Counts>> {
+    let buckets = local
+        .select_bucket_stats(query.time_from, query.time_to, &query.filter)
+        .await;
+    Json(
+        buckets
+            .into_iter()
+            .map(|bucket| Counts {
+                count: bucket.count,
+                sum_price: bucket.sum_price,
+            })
+            .collect(),
+    )
+}
+
+async fn clear(
+    State(local): State<Arc<dyn types::System>>,
+    Query(query): Query<ClearQuery>,
+) -> StatusCode {
+    let time_range = query
+        .time_from
+        .zip(query.time_to)
+        .map(|(from, to)| TimeRange { from, to });
+    local.clear(query.scope, time_range).await;
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::endpoints;
+    use crate::mock::{
+        self,
+        tests::{default_tag, moment_middle},
+    };
+    use crate::types::{System as _, UtcMinute};
+
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    fn urls(addresses: &[SocketAddr]) -> Vec<String> {
+        addresses
+            .iter()
+            .map(|address| format!("http://{}", address))
+            .collect()
+    }
+
+    #[test]
+    fn ring_spreads_cookies_and_is_deterministic() {
+        let nodes = (0..3)
+            .map(|i| format!("http://127.0.0.1:{}", 8080 + i))
+            .collect::<Vec<_>>();
+        let ring = Ring::new(nodes.clone(), 2);
+        let same_ring = Ring::new(nodes, 2);
+
+        let mut per_node = [0; 3];
+        for i in 0..3000 {
+            let cookie = format!("cookie{}", i);
+            let replicas = ring.replicas(&cookie);
+            assert_eq!(replicas, same_ring.replicas(&cookie));
+            assert_eq!(replicas.len(), 2);
+            assert_ne!(replicas[0], replicas[1]);
+            per_node[ring.replicas(&cookie)[0]] += 1;
+        }
+        assert!(per_node.iter().all(|&count| count > 500), "{:?}", per_node);
+    }
+
+    #[test]
+    fn merged_pages_skip_duplicates_and_keep_the_newest() {
+        let tags = (0..4)
+            .map(|seconds| UserTag {
+                time: (moment_middle() + chrono::Duration::seconds(seconds)).into(),
+                ..default_tag()
+            })
+            .rev()
+            .collect::<Vec<_>>();
+        let position = |tag: &UserTag| (tag.time, tag.tie_breaker());
+        // One replica missed the newest tag, so it listed an older one instead.
+        let complete = TagsPage {
+            tags: tags[..2].to_vec(),
+            total: Some(4),
+            next: Some(position(&tags[1])),
+        };
+        let lagging = TagsPage {
+            tags: tags[1..3].to_vec(),
+            total: Some(3),
+            next: Some(position(&tags[2])),
+        };
+
+        let merged = merge_pages(vec![complete, lagging], 2);
+        assert_eq!(merged.tags, tags[..2]);
+        assert_eq!(merged.total, Some(4));
+        assert_eq!(merged.next, Some(position(&tags[1])));
+
+        let last = TagsPage {
+            tags: tags[3..].to_vec(),
+            total: None,
+            next: None,
+        };
+        assert_eq!(merge_pages(vec![last], 2).next, None);
+    }
+
+    /// Starts each node as a separate server, as separate processes would be.
+    /// Returns clients of the nodes' systems, sharing their local backends.
+    async fn start_cluster(addresses: &[SocketAddr]) -> Vec<System> {
+        let nodes = urls(addresses);
+        let mut systems = Vec::new();
+        for (index, address) in addresses.iter().enumerate() {
+            let local: Arc<dyn types::System> = Arc::new(mock::System::new());
+            let node = System::new(
+                Arc::clone(&local),
+                Ring::new(nodes.clone(), 2),
+                index,
+                SECRET,
+            );
+            let router = endpoints::build_router(node, Default::default())
+                .merge(internal_router(Arc::clone(&local), SECRET));
+            let server = axum::Server::bind(address).serve(router.into_make_service());
+            tokio::spawn(server);
+            systems.push(System::new(
+                local,
+                Ring::new(nodes.clone(), 2),
+                index,
+                SECRET,
+            ));
+        }
+        systems
+    }
+
+    #[tokio::test]
+    async fn nodes_forward_to_owners_and_merge_aggregates() {
+        let addresses = [8, 9, 10].map(|i| SocketAddr::from(([127, 0, 0, i], 9042)));
+        let nodes = start_cluster(&addresses).await;
+
+        let mut cookies = (0..20).map(|i| format!("cookie{}", i)).collect::<Vec<_>>();
+        // Escaped in the paths of internal routes.
+        cookies.push("a/b?c#d%".to_owned());
+        for (i, cookie) in cookies.iter().enumerate() {
+            nodes[i % 3]
+                .register_user_tag(UserTag {
+                    time: moment_middle().into(),
+                    cookie: cookie.clone(),
+                    ..default_tag()
+                })
+                .await;
+        }
+
+        for cookie in &cookies {
+            let owner = nodes[0].ring.replicas(cookie)[0];
+            for node in &nodes {
+                let profile = node
+                    .last_tags_by_cookie(cookie, moment_middle(), moment_middle(), 10)
+                    .await;
+                assert_eq!(profile.buys.len(), 1);
+            }
+            let stored = nodes[owner]
+                .local
+                .last_tags_by_cookie(cookie, moment_middle(), moment_middle(), 10)
+                .await;
+            assert_eq!(stored.buys.len(), 1);
+        }
+
+        let buckets = nodes[1]
+            .select_bucket_stats(
+                moment_middle(),
+                moment_middle() + chrono::Duration::minutes(1),
+                &Filter::new(Action::Buy),
+            )
+            .await;
+        let count: i64 = buckets.iter().map(|bucket| bucket.count).sum();
+        assert_eq!(count, cookies.len() as i64);
+
+        nodes[2].clear(DataScope::All, None).await;
+        for node in &nodes {
+            assert_eq!(node.local.memory_usage().await.unwrap().cookies, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn internal_routes_require_cluster_secret() {
+        let address = SocketAddr::from(([127, 0, 0, 24], 9042));
+        start_cluster(&[address]).await;
+        let url = format!("http://{}/internal/clear", address);
+        let client = reqwest::Client::new();
+
+        let response = client.post(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .post(&url)
+            .header(CLUSTER_SECRET, "guess")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = client
+            .post(&url)
+            .header(CLUSTER_SECRET, SECRET)
+            .query(&[("scope", "all")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    /// Starts a node in its own runtime, so that shutting the runtime down
+    /// kills it like its process would be killed.
+    fn start_node(
+        address: SocketAddr,
+        nodes: Vec<String>,
+        index: usize,
+        local: Arc<dyn types::System>,
+    ) -> tokio::runtime::Runtime {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+        let node = System::new(Arc::clone(&local), Ring::new(nodes, 2), index, SECRET);
+        let router =
+            endpoints::build_router(node, Default::default()).merge(internal_router(local, SECRET));
+        runtime.spawn(axum::Server::bind(&address).serve(router.into_make_service()));
+        runtime
+    }
+
+    async fn kill(runtime: tokio::runtime::Runtime) {
+        tokio::task::spawn_blocking(move || runtime.shutdown_timeout(Duration::from_secs(5)))
+            .await
+            .unwrap();
+    }
+
+    async fn buys(system: &dyn types::System, cookie: &str) -> usize {
+        system
+            .last_tags_by_cookie(
+                cookie,
+                moment_middle(),
+                moment_middle() + chrono::Duration::minutes(1),
+                200,
+            )
+            .await
+            .buys
+            .len()
+    }
+
+    /// Number of buys counted in aggregates.
+    async fn counted(system: &dyn types::System) -> usize {
+        let minute = UtcMinute::from(moment_middle());
+        system
+            .select_bucket_stats(
+                minute.inner(),
+                minute.next().next().inner(),
+                &Filter::new(Action::Buy),
+            )
+            .await
+            .iter()
+            .map(|bucket| bucket.count as usize)
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn profiles_survive_node_failure() {
+        let addresses = [11, 12, 13].map(|i| SocketAddr::from(([127, 0, 0, i], 9043)));
+        let nodes = urls(&addresses);
+        let locals = (0..3)
+            .map(|_| Arc::new(mock::System::new()) as Arc<dyn types::System>)
+            .collect::<Vec<_>>();
+        let mut runtimes = (0..3)
+            .map(|index| {
+                start_node(
+                    addresses[index],
+                    nodes.clone(),
+                    index,
+                    Arc::clone(&locals[index]),
+                )
+            })
+            .collect::<Vec<_>>();
+        let coordinator = System::new(
+            Arc::clone(&locals[0]),
+            Ring::new(nodes.clone(), 2),
+            0,
+            SECRET,
+        );
+        let reader = System::new(
+            Arc::clone(&locals[1]),
+            Ring::new(nodes.clone(), 2),
+            1,
+            SECRET,
+        );
+
+        let cookies = (0..30).map(|i| format!("cookie{}", i)).collect::<Vec<_>>();
+        let register_round = |round: i64| {
+            let coordinator = &coordinator;
+            let cookies = &cookies;
+            async move {
+                for cookie in cookies {
+                    coordinator
+                        .register_user_tag(UserTag {
+                            time: (moment_middle() + chrono::Duration::seconds(round)).into(),
+                            cookie: cookie.clone(),
+                            ..default_tag()
+                        })
+                        .await;
+                }
+            }
+        };
+
+        let primary_elsewhere = cookies
+            .iter()
+            .filter(|cookie| coordinator.ring.replicas(cookie)[0] != 2)
+            .count();
+
+        register_round(0).await;
+        register_round(1).await;
+        // Node 2 goes down while tags are being registered.
+        let node = runtimes.pop().unwrap();
+        futures::join!(register_round(2), kill(node));
+        for cookie in &cookies {
+            let profile = coordinator
+                .last_tags_by_cookie(cookie, moment_middle(), moment_middle(), 200)
+                .await;
+            let read = reader
+                .last_tags_by_cookie(cookie, moment_middle(), moment_middle(), 200)
+                .await;
+            assert_eq!(profile, read);
+            assert_eq!(buys(&reader, cookie).await, 3);
+        }
+
+        register_round(3).await;
+        for cookie in &cookies {
+            assert_eq!(buys(&reader, cookie).await, 4);
+        }
+        assert!(coordinator.hints.len() > 0);
+        // Aggregates counted by node 2 are missing while it is down.
+        assert_eq!(counted(&coordinator).await, 4 * primary_elsewhere);
+        assert_eq!(counted(&reader).await, 4 * primary_elsewhere);
+
+        // Node 2 comes back empty: hints bring it the tags it missed while down,
+        // and reads repair the older ones.
+        let local = Arc::new(mock::System::new()) as Arc<dyn types::System>;
+        runtimes.push(start_node(
+            addresses[2],
+            nodes.clone(),
+            2,
+            Arc::clone(&local),
+        ));
+        while coordinator.hints.len() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let replicated = cookies
+            .iter()
+            .filter(|cookie| coordinator.ring.replicas(cookie).contains(&2))
+            .collect::<Vec<_>>();
+        assert!(!replicated.is_empty());
+        for cookie in &replicated {
+            // The tag of the round node 2 went down in may have reached it.
+            assert!((1..=2).contains(&buys(&*local, cookie).await));
+            assert_eq!(buys(&coordinator, cookie).await, 4);
+        }
+        for cookie in &replicated {
+            while buys(&*local, cookie).await < 4 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+        // Only the counts node 2 lost with its data stay missing: those of
+        // the first rounds, and maybe some of the round it went down in.
+        let primary_on_2 = cookies.len() - primary_elsewhere;
+        let recovered = counted(&coordinator).await - 4 * primary_elsewhere;
+        assert!((primary_on_2..=2 * primary_on_2).contains(&recovered));
+
+        for runtime in runtimes {
+            kill(runtime).await;
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Compact binary encoding of the data kept on local disk.
+//!
+//! Integers are LEB128 varints (zigzag-encoded if signed), strings are
+//! length-prefixed UTF-8. Whole files are wrapped with [`seal`], which adds
+//! a magic, a format version and a CRC32 of the contents.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use chrono::{DateTime, Utc};
+
+use crate::aggregates::{AggregateKey, Counters, Dimensions};
+use crate::types::{Action, Device, ProductInfo, UserTag};
+
+/// Set in the device byte of tags followed by their attributes, so that tags
+/// without attributes are encoded (and tie-broken) as before they existed.
+const HAS_ATTRIBUTES: u8 = 0x80;
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn put_u64(&mut self, mut v: u64) {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.buf.push(byte);
+                return;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    pub fn put_i64(&mut self, v: i64) {
+        self.put_u64(((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    /// Appends raw bytes, without any length prefix.
+    pub fn put_bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn put_str(&mut self, v: &str) {
+        self.put_u64(v.len() as u64);
+        self.buf.extend_from_slice(v.as_bytes());
+    }
+
+    pub fn put_time(&mut self, v: DateTime<Utc>) {
+        self.put_i64(v.timestamp());
+        self.put_u64(v.timestamp_subsec_nanos() as u64);
+    }
+
+    pub fn put_user_tag(&mut self, tag: &UserTag) {
+        self.put_time(tag.time.inner());
+        self.put_str(&tag.cookie);
+        self.put_str(&tag.country);
+        let device = match tag.device {
+            Device::Pc => 0,
+            Device::Mobile => 1,
+            Device::Tv => 2,
+        };
+        if tag.attributes.is_empty() {
+            self.put_u8(device);
+        } else {
+            self.put_u8(device | HAS_ATTRIBUTES);
+        }
+        self.put_action(tag.action);
+        self.put_str(&tag.origin);
+        self.put_i64(tag.product_info.product_id as i64);
+        self.put_str(&tag.product_info.brand_id);
+        self.put_str(&tag.product_info.category_id);
+        self.put_i64(tag.product_info.price as i64);
+        if !tag.attributes.is_empty() {
+            self.put_u64(tag.attributes.len() as u64);
+            for (name, value) in &tag.attributes {
+                self.put_str(name);
+                self.put_str(value);
+            }
+        }
+    }
+
+    pub fn put_action(&mut self, action: Action) {
+        self.put_u8(match action {
+            Action::View => 0,
+            Action::Buy => 1,
+        });
+    }
+
+    pub fn put_dimensions(&mut self, dimensions: &Dimensions) {
+        self.put_u64(dimensions.len() as u64);
+        for dimension in dimensions.iter() {
+            self.put_str(&dimension.to_string());
+        }
+    }
+
+    /// Values of the key are as many as the dimensions written before.
+    pub fn put_aggregate(&mut self, key: &AggregateKey, counters: &Counters) {
+        self.put_action(key.action);
+        for value in &key.values {
+            self.put_str(value);
+        }
+        self.put_i64(counters.count);
+        self.put_i64(counters.sum_price);
+    }
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        let (&v, rest) = self
+            .buf
+            .split_first()
+            .ok_or_else(|| invalid_data("unexpected end of data"))?;
+        self.buf = rest;
+        Ok(v)
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(invalid_data("varint too long"))
+    }
+
+    pub fn i64(&mut self) -> io::Result<i64> {
+        let v = self.u64()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        i32::try_from(self.i64()?).map_err(|_| invalid_data("i32 out of range"))
+    }
+
+    pub fn str(&mut self) -> io::Result<String> {
+        let len = self.u64()? as usize;
+        if len > self.buf.len() {
+            return Err(invalid_data("string longer than remaining data"));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        String::from_utf8(bytes.to_vec()).map_err(|err| invalid_data(err.to_string()))
+    }
+
+    pub fn time(&mut self) -> io::Result<DateTime<Utc>> {
+        let secs = self.i64()?;
+        let nanos = self.u64()? as u32;
+        DateTime::from_timestamp(secs, nanos).ok_or_else(|| invalid_data("timestamp out of range"))
+    }
+
+    pub fn action(&mut self) -> io::Result<Action> {
+        match self.u8()? {
+            0 => Ok(Action::View),
+            1 => Ok(Action::Buy),
+            other => Err(invalid_data(format!("invalid action: {}", other))),
+        }
+    }
+
+    pub fn user_tag(&mut self) -> io::Result<UserTag> {
+        let time = self.time()?.into();
+        let cookie = self.str()?;
+        let country = self.str()?;
+        let device = self.u8()?;
+        let mut tag = UserTag {
+            time,
+            cookie,
+            country,
+            device: match device & !HAS_ATTRIBUTES {
+                0 => Device::Pc,
+                1 => Device::Mobile,
+                2 => Device::Tv,
+                other => return Err(invalid_data(format!("invalid device: {}", other))),
+            },
+            action: self.action()?,
+            origin: self.str()?,
+            product_info: ProductInfo {
+                product_id: self.i32()?,
+                brand_id: self.str()?,
+                category_id: self.str()?,
+                price: self.i32()?,
+            },
+            attributes: BTreeMap::new(),
+        };
+        if device & HAS_ATTRIBUTES != 0 {
+            for _ in 0..self.u64()? {
+                tag.attributes.insert(self.str()?, self.str()?);
+            }
+        }
+        Ok(tag)
+    }
+
+    pub fn dimensions(&mut self) -> io::Result<Dimensions> {
+        let dimensions = (0..self.u64()?)
+            .map(|_| self.str()?.parse().map_err(invalid_data))
+            .collect::<io::Result<_>>()?;
+        Dimensions::new(dimensions).map_err(invalid_data)
+    }
+
+    /// Reads a key of the `dimensions` written before.
+    pub fn aggregate(&mut self, dimensions: &Dimensions) -> io::Result<(AggregateKey, Counters)> {
+        Ok((
+            AggregateKey {
+                action: self.action()?,
+                values: (0..dimensions.len())
+                    .map(|_| self.str())
+                    .collect::<io::Result<_>>()?,
+            },
+            Counters {
+                count: self.i64()?,
+                sum_price: self.i64()?,
+            },
+        ))
+    }
+}
+
+/// Wraps `body` with a header (`magic`, `version`) and a trailing CRC32.
+pub fn seal(magic: &[u8; 4], version: u8, body: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(body.len() + 9);
+    sealed.extend_from_slice(magic);
+    sealed.push(version);
+    sealed.extend_from_slice(body);
+    let crc = crc32fast::hash(&sealed);
+    sealed.extend_from_slice(&crc.to_le_bytes());
+    sealed
+}
+
+/// Verifies data produced by [`seal`], returning its version and body.
+pub fn unseal<'a>(magic: &[u8; 4], sealed: &'a [u8]) -> io::Result<(u8, &'a [u8])> {
+    if sealed.len() < 9 || &sealed[..4] != magic {
+        return Err(invalid_data("bad magic"));
+    }
+    let (contents, crc) = sealed.split_at(sealed.len() - 4);
+    if crc32fast::hash(contents).to_le_bytes() != crc {
+        return Err(invalid_data("checksum mismatch"));
+    }
+    Ok((contents[4], &contents[5..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_tag_roundtrip() {
+        let tag: UserTag = serde_json::from_str(
+            r#"{
+                "time": "2022-03-22T12:15:00.123Z",
+                "cookie": "user",
+                "country": "PL",
+                "device": "MOBILE",
+                "action": "BUY",
+                "origin": "Rawa",
+                "product_info": {
+                    "product_id": -2137,
+                    "brand_id": "apple",
+                    "category_id": "fruit",
+                    "price": 50
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let mut encoder = Encoder::new();
+        encoder.put_user_tag(&tag);
+        let encoded = encoder.finish();
+        let mut decoder = Decoder::new(&encoded);
+        assert_eq!(decoder.user_tag().unwrap(), tag);
+        assert!(decoder.is_empty());
+
+        let mut with_attributes = tag.clone();
+        with_attributes
+            .attributes
+            .insert("campaign".to_owned(), "spring".to_owned());
+        let mut encoder = Encoder::new();
+        encoder.put_user_tag(&with_attributes);
+        let encoded_with_attributes = encoder.finish();
+        assert!(encoded_with_attributes.len() > encoded.len());
+        let mut decoder = Decoder::new(&encoded_with_attributes);
+        assert_eq!(decoder.user_tag().unwrap(), with_attributes);
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn sealed_data_is_verified() {
+        let mut sealed = seal(b"TEST", 3, b"body");
+        assert_eq!(unseal(b"TEST", &sealed).unwrap(), (3, &b"body"[..]));
+        assert!(unseal(b"ABCD", &sealed).is_err());
+        sealed[6] ^= 1;
+        assert!(unseal(b"TEST", &sealed).is_err());
+    }
+}
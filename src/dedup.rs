@@ -0,0 +1,231 @@
+//! Deduplication of retried ingestion requests.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::codec::Encoder;
+use crate::types::UserTag;
+use crate::utils;
+
+/// Identifies an ingested event for the purpose of deduplication.
+///
+/// Keys are persisted with queued events and sent between nodes, so they are
+/// computed with [`utils::stable_hash`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey(u64);
+
+impl IdempotencyKey {
+    /// Key given explicitly by the client, e.g. in the `Idempotency-Key` header.
+    pub fn explicit(key: &str) -> Self {
+        Self(utils::stable_hash(&[b"explicit:", key.as_bytes()].concat()))
+    }
+
+    /// Key derived from the whole contents of the tag, so that only exact
+    /// repetitions of an event are considered duplicates.
+    pub fn derived(tag: &UserTag) -> Self {
+        let mut encoder = Encoder::new();
+        encoder.put_user_tag(tag);
+        Self(utils::stable_hash(
+            &[b"derived:".as_slice(), &encoder.finish()].concat(),
+        ))
+    }
+
+    /// Raw value, for persisting the key along with its event.
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// How long a key is remembered after its event is registered.
+    pub window: Duration,
+    /// Maximum number of remembered keys; the oldest ones are forgotten first.
+    pub max_keys: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(600),
+            max_keys: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    seen: HashSet<IdempotencyKey>,
+    /// Keys of `seen` in insertion order.
+    order: VecDeque<(Instant, IdempotencyKey)>,
+}
+
+/// Set of keys of recently registered events.
+#[derive(Debug)]
+pub struct DedupWindow {
+    config: Config,
+    state: Mutex<State>,
+}
+
+impl DedupWindow {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Remembers the key. Returns `false` if it is already remembered,
+    /// i.e. the event is a duplicate.
+    pub fn insert(&self, key: IdempotencyKey) -> bool {
+        self.insert_at(key, Instant::now())
+    }
+
+    /// Remembers the key until the returned reservation is dropped, unless
+    /// it is committed, i.e. its event is registered. Returns `None` if the
+    /// key is already remembered.
+    pub fn reserve(&self, key: IdempotencyKey) -> Option<Reservation<'_>> {
+        self.insert(key).then_some(Reservation {
+            window: self,
+            key: Some(key),
+        })
+    }
+
+    fn remove(&self, key: IdempotencyKey) {
+        let mut state = self.state.lock().unwrap();
+        if state.seen.remove(&key) {
+            // Reservations are short-lived, so the key is near the back.
+            if let Some(position) = state.order.iter().rposition(|(_, other)| *other == key) {
+                state.order.remove(position);
+            }
+        }
+    }
+
+    fn insert_at(&self, key: IdempotencyKey, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while let Some(&(inserted, oldest)) = state.order.front() {
+            if now.duration_since(inserted) < self.config.window {
+                break;
+            }
+            state.order.pop_front();
+            state.seen.remove(&oldest);
+        }
+
+        if state.seen.contains(&key) {
+            return false;
+        }
+        if state.order.len() >= self.config.max_keys {
+            if let Some((_, oldest)) = state.order.pop_front() {
+                state.seen.remove(&oldest);
+            }
+        }
+        state.seen.insert(key);
+        state.order.push_back((now, key));
+        true
+    }
+
+    pub fn clear(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+}
+
+/// Key reserved in a [`DedupWindow`], forgotten again when dropped without
+/// being committed, e.g. when registering its event panics.
+#[must_use]
+pub struct Reservation<'a> {
+    window: &'a DedupWindow,
+    key: Option<IdempotencyKey>,
+}
+
+impl Reservation<'_> {
+    pub fn commit(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.window.remove(key);
+        }
+    }
+}
+
+impl Default for DedupWindow {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::tests::default_tag;
+
+    use super::*;
+
+    #[test]
+    fn keys_are_forgotten_after_window() {
+        let window = DedupWindow::new(Config {
+            window: Duration::from_secs(10),
+            max_keys: 2,
+        });
+        let start = Instant::now();
+        let (a, b, c) = (
+            IdempotencyKey::explicit("a"),
+            IdempotencyKey::explicit("b"),
+            IdempotencyKey::explicit("c"),
+        );
+
+        assert!(window.insert_at(a, start));
+        assert!(!window.insert_at(a, start + Duration::from_secs(9)));
+        assert!(window.insert_at(a, start + Duration::from_secs(10)));
+
+        // Beyond `max_keys`, the oldest key is forgotten.
+        assert!(window.insert_at(b, start + Duration::from_secs(11)));
+        assert!(window.insert_at(c, start + Duration::from_secs(12)));
+        assert!(window.insert_at(a, start + Duration::from_secs(13)));
+        assert!(!window.insert_at(c, start + Duration::from_secs(13)));
+    }
+
+    #[test]
+    fn uncommitted_reservations_are_forgotten() {
+        let window = DedupWindow::default();
+        let key = IdempotencyKey::explicit("a");
+
+        let reservation = window.reserve(key).unwrap();
+        assert!(window.reserve(key).is_none());
+        drop(reservation);
+
+        window.reserve(key).unwrap().commit();
+        assert!(window.reserve(key).is_none());
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        // Persisted and sent between nodes, so must not change with the toolchain.
+        assert_eq!(IdempotencyKey::explicit("a").to_bits(), 0x3f32f42358f70b98);
+    }
+
+    #[test]
+    fn derived_keys_depend_on_whole_tag() {
+        let tag = default_tag();
+        let other = UserTag {
+            country: "DE".to_owned(),
+            ..default_tag()
+        };
+        assert_eq!(IdempotencyKey::derived(&tag), IdempotencyKey::derived(&tag));
+        assert_ne!(
+            IdempotencyKey::derived(&tag),
+            IdempotencyKey::derived(&other)
+        );
+        assert_ne!(
+            IdempotencyKey::derived(&tag),
+            IdempotencyKey::explicit("cookie")
+        );
+    }
+}
@@ -0,0 +1,815 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, error, info};
+
+use crate::aggregates::{self, Dimensions, Filter, MinuteAggregates};
+use crate::codec::{self, Decoder, Encoder};
+use crate::segment_log::SegmentLog;
+use crate::types::{self, Action, Bucket, DataScope, TimeRange, UserProfile, UserTag, UtcMinute};
+use crate::utils::{self, write_atomically};
+
+const MAX_TAGS_BY_COOKIE: usize = 200;
+
+const INDEX_FILE: &str = "profiles.idx";
+const INDEX_MAGIC: &[u8; 4] = b"ALZI";
+const AGGREGATES_DIR: &str = "aggregates";
+const AGGREGATES_EXTENSION: &str = "agg";
+const AGGREGATES_MAGIC: &[u8; 4] = b"ALZA";
+const LOG_DIR: &str = "log";
+/// Aggregate files of version 1 did not list their dimensions, which were
+/// the legacy ones.
+const FORMAT_VERSION: u8 = 2;
+
+fn retention() -> chrono::Duration {
+    chrono::Duration::hours(24)
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How often the in-memory state is compacted into the index
+    /// and aggregate files, which lets old log segments be deleted.
+    pub compaction_interval: Duration,
+    pub max_segment_len: u64,
+    /// Dimensions which aggregates are kept for. Aggregates stored with
+    /// more dimensions are regrouped when loaded, while ones stored without
+    /// some of them are kept as they are (and cannot be filtered on those).
+    pub dimensions: Dimensions,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            compaction_interval: Duration::from_secs(60),
+            max_segment_len: 64 * 1024 * 1024,
+            dimensions: Dimensions::default(),
+        }
+    }
+}
+
+/// Embedded storage persisting to a local directory.
+///
+/// Every registered tag is first appended to a segment log. The state built
+/// from the tags (200 latest tags of each action per cookie and per-minute
+/// aggregates) is kept in memory and periodically compacted to disk: profiles
+/// into a single index file, aggregates into one file per minute. Each of
+/// those files records the log offset it covers, so after a restart only the
+/// log suffix not yet compacted is replayed.
+///
+/// Data older than 24 h before the newest registered event is discarded.
+pub struct System {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    /// Appended to under the state write lock, so that the log offset
+    /// taken by a compaction matches its snapshot of the state.
+    log: Arc<std::sync::Mutex<SegmentLog>>,
+    state: RwLock<State>,
+    dimensions: Dimensions,
+    /// Serializes compactions, so that their file writes do not interleave.
+    compaction: Mutex<()>,
+}
+
+/// Tags are ordered by time and then by [`UserTag::tie_breaker`].
+type TagKey = (DateTime<Utc>, i64);
+
+fn tag_key(tag: &UserTag) -> TagKey {
+    (tag.time.inner(), tag.tie_breaker())
+}
+
+#[derive(Debug, Default, Clone)]
+struct Profile {
+    views: BTreeMap<TagKey, UserTag>,
+    buys: BTreeMap<TagKey, UserTag>,
+}
+
+impl Profile {
+    fn tags_mut(&mut self, action: Action) -> &mut BTreeMap<TagKey, UserTag> {
+        match action {
+            Action::View => &mut self.views,
+            Action::Buy => &mut self.buys,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.views.is_empty() && self.buys.is_empty()
+    }
+}
+
+struct State {
+    profiles: HashMap<String, Profile>,
+    aggregates: BTreeMap<UtcMinute, MinuteAggregates>,
+    /// Dimensions of minutes stored without some of the configured ones,
+    /// whose values are unknown, so those minutes cannot be regrouped.
+    stored_dimensions: BTreeMap<UtcMinute, Dimensions>,
+    /// Minutes changed (or removed) since the last compaction.
+    dirty_minutes: BTreeSet<UtcMinute>,
+    newest_event: Option<DateTime<Utc>>,
+}
+
+/// State to be compacted, taken under the state lock and encoded outside it.
+struct Snapshot {
+    offset: u64,
+    newest_event: Option<DateTime<Utc>>,
+    profiles: HashMap<String, Profile>,
+    /// `None` stands for a minute whose file should be removed.
+    minutes: Vec<(UtcMinute, Option<(Dimensions, MinuteAggregates)>)>,
+}
+
+/// Files produced by a single compaction.
+struct Compacted {
+    offset: u64,
+    index: Vec<u8>,
+    /// `None` stands for a minute whose file should be removed.
+    minutes: Vec<(UtcMinute, Option<Vec<u8>>)>,
+}
+
+impl State {
+    fn retention_cutoff(&self) -> Option<DateTime<Utc>> {
+        self.newest_event.map(|newest| newest - retention())
+    }
+
+    fn apply_to_profiles(&mut self, tag: &UserTag) {
+        let tags = self
+            .profiles
+            .entry(tag.cookie.clone())
+            .or_default()
+            .tags_mut(tag.action);
+        tags.insert(tag_key(tag), tag.clone());
+        if tags.len() > MAX_TAGS_BY_COOKIE {
+            tags.pop_first();
+        }
+    }
+
+    /// Dimensions which aggregates of the minute are kept for.
+    fn dimensions_of<'a>(
+        &'a self,
+        minute: UtcMinute,
+        dimensions: &'a Dimensions,
+    ) -> &'a Dimensions {
+        self.stored_dimensions.get(&minute).unwrap_or(dimensions)
+    }
+
+    fn apply_to_aggregates(&mut self, tag: &UserTag, dimensions: &Dimensions) {
+        let minute = UtcMinute::from(tag.time);
+        let dimensions = self.stored_dimensions.get(&minute).unwrap_or(dimensions);
+        self.aggregates
+            .entry(minute)
+            .or_default()
+            .register(tag, dimensions);
+        self.dirty_minutes.insert(minute);
+    }
+
+    fn observe(&mut self, time: DateTime<Utc>) {
+        self.newest_event = Some(self.newest_event.map_or(time, |newest| newest.max(time)));
+    }
+
+    fn enforce_retention(&mut self) {
+        let Some(cutoff) = self.retention_cutoff() else {
+            return;
+        };
+
+        let kept = self.aggregates.split_off(&UtcMinute::from(cutoff));
+        let expired = std::mem::replace(&mut self.aggregates, kept);
+        self.dirty_minutes.extend(expired.into_keys());
+        self.stored_dimensions = self.stored_dimensions.split_off(&UtcMinute::from(cutoff));
+
+        self.profiles.retain(|_, profile| {
+            profile.views = profile.views.split_off(&(cutoff, i64::MIN));
+            profile.buys = profile.buys.split_off(&(cutoff, i64::MIN));
+            !profile.is_empty()
+        });
+    }
+
+    fn snapshot(&mut self, offset: u64, dimensions: &Dimensions) -> Snapshot {
+        self.enforce_retention();
+
+        let minutes = std::mem::take(&mut self.dirty_minutes)
+            .into_iter()
+            .map(|minute| {
+                let aggregates = self.aggregates.get(&minute).map(|aggregates| {
+                    (
+                        self.dimensions_of(minute, dimensions).clone(),
+                        aggregates.clone(),
+                    )
+                });
+                (minute, aggregates)
+            })
+            .collect();
+
+        Snapshot {
+            offset,
+            newest_event: self.newest_event,
+            profiles: self.profiles.clone(),
+            minutes,
+        }
+    }
+}
+
+impl Snapshot {
+    fn encode(&self) -> Compacted {
+        let mut encoder = Encoder::new();
+        encoder.put_u64(self.offset);
+        match self.newest_event {
+            Some(newest) => {
+                encoder.put_u8(1);
+                encoder.put_time(newest);
+            }
+            None => encoder.put_u8(0),
+        }
+        encoder.put_u64(self.profiles.len() as u64);
+        for (cookie, profile) in &self.profiles {
+            encoder.put_str(cookie);
+            for tags in [&profile.views, &profile.buys] {
+                encoder.put_u64(tags.len() as u64);
+                for tag in tags.values() {
+                    encoder.put_user_tag(tag);
+                }
+            }
+        }
+        let index = codec::seal(INDEX_MAGIC, FORMAT_VERSION, &encoder.finish());
+
+        let minutes = self
+            .minutes
+            .iter()
+            .map(|(minute, aggregates)| {
+                let file = aggregates.as_ref().map(|(dimensions, aggregates)| {
+                    let mut encoder = Encoder::new();
+                    encoder.put_u64(self.offset);
+                    encoder.put_dimensions(dimensions);
+                    encoder.put_u64(aggregates.len() as u64);
+                    for (key, counters) in aggregates.iter() {
+                        encoder.put_aggregate(key, counters);
+                    }
+                    codec::seal(AGGREGATES_MAGIC, FORMAT_VERSION, &encoder.finish())
+                });
+                (*minute, file)
+            })
+            .collect();
+
+        Compacted {
+            offset: self.offset,
+            index,
+            minutes,
+        }
+    }
+}
+
+fn minute_path(dir: &Path, minute: UtcMinute) -> PathBuf {
+    dir.join(AGGREGATES_DIR).join(format!(
+        "{}.{}",
+        minute.inner().timestamp(),
+        AGGREGATES_EXTENSION
+    ))
+}
+
+fn write_compacted(dir: &Path, compacted: &Compacted) -> io::Result<()> {
+    // Aggregates go first: the index offset determines which part of the log
+    // is replayed, so it must not get ahead of the aggregates on disk.
+    for (minute, file) in &compacted.minutes {
+        let path = minute_path(dir, *minute);
+        match file {
+            Some(contents) => write_atomically(&path, contents)?,
+            None => match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            },
+        }
+    }
+    write_atomically(&dir.join(INDEX_FILE), &compacted.index)
+}
+
+/// Syncs the log up to the snapshot and writes its files, returning the log
+/// offset they cover. `active` is the log segment the snapshot was taken in.
+fn write_snapshot(dir: &Path, active: &File, snapshot: &Snapshot) -> io::Result<u64> {
+    // Makes the compaction a durability checkpoint of the log as well.
+    active.sync_data()?;
+    let compacted = snapshot.encode();
+    write_compacted(dir, &compacted)?;
+    Ok(compacted.offset)
+}
+
+fn load_index(path: &Path, state: &mut State) -> io::Result<u64> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let (_version, body) = codec::unseal(INDEX_MAGIC, &contents)?;
+    let mut decoder = Decoder::new(body);
+
+    let offset = decoder.u64()?;
+    if decoder.u8()? == 1 {
+        state.observe(decoder.time()?);
+    }
+    for _ in 0..decoder.u64()? {
+        let cookie = decoder.str()?;
+        let mut profile = Profile::default();
+        for tags in [&mut profile.views, &mut profile.buys] {
+            for _ in 0..decoder.u64()? {
+                let tag = decoder.user_tag()?;
+                tags.insert(tag_key(&tag), tag);
+            }
+        }
+        state.profiles.insert(cookie, profile);
+    }
+    if !decoder.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trailing data in profiles index",
+        ));
+    }
+    Ok(offset)
+}
+
+/// Loads all aggregate files, returning the log offset covered by each of them.
+fn load_aggregates(
+    dir: &Path,
+    state: &mut State,
+    dimensions: &Dimensions,
+) -> io::Result<HashMap<UtcMinute, u64>> {
+    let mut offsets = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_none_or(|ext| ext != AGGREGATES_EXTENSION)
+        {
+            continue;
+        }
+        let Some(minute) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<i64>().ok())
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            .map(UtcMinute::from)
+        else {
+            continue;
+        };
+
+        let contents = fs::read(&path)?;
+        let (version, body) = codec::unseal(AGGREGATES_MAGIC, &contents)?;
+        let mut decoder = Decoder::new(body);
+        offsets.insert(minute, decoder.u64()?);
+        let stored = match version {
+            1 => Dimensions::legacy(),
+            _ => decoder.dimensions()?,
+        };
+        let mut aggregates = MinuteAggregates::default();
+        for _ in 0..decoder.u64()? {
+            let (key, counters) = decoder.aggregate(&stored)?;
+            aggregates.add(key, counters);
+        }
+        if dimensions
+            .iter()
+            .any(|dimension| stored.position(dimension).is_none())
+        {
+            // Projecting would make up values of the missing dimensions.
+            state.stored_dimensions.insert(minute, stored);
+            state.aggregates.insert(minute, aggregates);
+            continue;
+        }
+        if stored != *dimensions {
+            // Rewritten with the new dimensions by the next compaction.
+            state.dirty_minutes.insert(minute);
+        }
+        state
+            .aggregates
+            .insert(minute, aggregates.project(&stored, dimensions));
+    }
+    Ok(offsets)
+}
+
+impl System {
+    /// Opens (or creates) the storage in `dir` and starts its background compaction.
+    pub async fn open(dir: impl Into<PathBuf>, config: Config) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(AGGREGATES_DIR))?;
+
+        let log = SegmentLog::open(dir.join(LOG_DIR), config.max_segment_len)?;
+        let mut state = State {
+            profiles: Default::default(),
+            aggregates: Default::default(),
+            stored_dimensions: Default::default(),
+            dirty_minutes: Default::default(),
+            newest_event: None,
+        };
+
+        let index_offset = load_index(&dir.join(INDEX_FILE), &mut state)?;
+        let minute_offsets =
+            load_aggregates(&dir.join(AGGREGATES_DIR), &mut state, &config.dimensions)?;
+
+        let records = log.read_from(index_offset)?;
+        info!(
+            "Loaded {} profiles and {} minutes of aggregates from {}, replaying {} logged tags",
+            state.profiles.len(),
+            state.aggregates.len(),
+            dir.display(),
+            records.len()
+        );
+        for (offset, record) in records {
+            let tag = Decoder::new(&record).user_tag()?;
+            state.observe(tag.time.inner());
+            state.apply_to_profiles(&tag);
+            let minute = UtcMinute::from(tag.time);
+            if offset >= minute_offsets.get(&minute).copied().unwrap_or(0) {
+                state.apply_to_aggregates(&tag, &config.dimensions);
+            }
+        }
+
+        let inner = Arc::new(Inner {
+            dir,
+            log: Arc::new(std::sync::Mutex::new(log)),
+            state: RwLock::new(state),
+            dimensions: config.dimensions,
+            compaction: Mutex::new(()),
+        });
+        tokio::spawn(compaction_loop(
+            Arc::downgrade(&inner),
+            config.compaction_interval,
+        ));
+
+        Ok(Self { inner })
+    }
+}
+
+impl Inner {
+    async fn register_user_tag(&self, tag: UserTag) {
+        let mut state = self.state.write().await;
+
+        if state
+            .retention_cutoff()
+            .is_some_and(|cutoff| tag.time.inner() < cutoff)
+        {
+            debug!("Dropping user tag from {} as outside retention", tag.time);
+            return;
+        }
+
+        let mut encoder = Encoder::new();
+        encoder.put_user_tag(&tag);
+        let record = encoder.finish();
+        let log = Arc::clone(&self.log);
+        tokio::task::spawn_blocking(move || log.lock().unwrap().append(&record))
+            .await
+            .expect("Log append task panicked")
+            .expect("Failed to append user tag to log");
+
+        state.observe(tag.time.inner());
+        state.apply_to_profiles(&tag);
+        state.apply_to_aggregates(&tag, &self.dimensions);
+    }
+
+    /// Persists the whole in-memory state and drops log segments it covers.
+    async fn compact(&self) -> io::Result<()> {
+        let _compaction = self.compaction.lock().await;
+
+        let (snapshot, active) = {
+            let mut state = self.state.write().await;
+            let log = self.log.lock().unwrap();
+            let active = log.active_segment()?;
+            (state.snapshot(log.next_offset(), &self.dimensions), active)
+        };
+        debug!(
+            "Compacting up to log offset {} ({} dirty minutes)",
+            snapshot.offset,
+            snapshot.minutes.len()
+        );
+
+        let dir = self.dir.clone();
+        let (minutes, result) = tokio::task::spawn_blocking(move || {
+            let result = write_snapshot(&dir, &active, &snapshot);
+            let minutes: Vec<_> = snapshot
+                .minutes
+                .into_iter()
+                .map(|(minute, _)| minute)
+                .collect();
+            (minutes, result)
+        })
+        .await
+        .expect("Compaction task panicked");
+
+        match result {
+            Ok(offset) => {
+                let log = Arc::clone(&self.log);
+                tokio::task::spawn_blocking(move || log.lock().unwrap().remove_before(offset))
+                    .await
+                    .expect("Compaction task panicked")
+            }
+            Err(err) => {
+                // Those minutes have to be written by the next compaction.
+                self.state.write().await.dirty_minutes.extend(minutes);
+                Err(err)
+            }
+        }
+    }
+}
+
+async fn compaction_loop(inner: Weak<Inner>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        if let Err(err) = inner.compact().await {
+            error!("Compaction of {} failed: {}", inner.dir.display(), err);
+        }
+    }
+}
+
+#[async_trait]
+impl types::System for System {
+    async fn register_user_tag(&self, tag: UserTag) {
+        let inner = Arc::clone(&self.inner);
+        // Spawned, so that a logged tag is applied even if the request is
+        // dropped while it is being appended.
+        tokio::spawn(async move { inner.register_user_tag(tag).await })
+            .await
+            .expect("User tag registration panicked")
+    }
+
+    fn dimensions(&self) -> &Dimensions {
+        &self.inner.dimensions
+    }
+
+    async fn last_tags_by_cookie<'a>(
+        &'a self,
+        cookie: &'a str,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        limit: usize,
+    ) -> UserProfile {
+        assert!(limit <= MAX_TAGS_BY_COOKIE);
+
+        let state = self.inner.state.read().await;
+        let mut profile = UserProfile {
+            cookie: cookie.into(),
+            views: Default::default(),
+            buys: Default::default(),
+        };
+        if let Some(stored) = state.profiles.get(cookie) {
+            let last = |tags: &BTreeMap<TagKey, UserTag>| {
+                tags.range((time_from, i64::MIN)..=(time_to, i64::MAX))
+                    .rev()
+                    .take(limit)
+                    .map(|(_, tag)| tag.clone())
+                    .collect()
+            };
+            profile.views = last(&stored.views);
+            profile.buys = last(&stored.buys);
+        }
+
+        utils::check_user_profile(&profile, time_from, time_to, limit);
+        profile
+    }
+
+    async fn check_filter(
+        &self,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        filter: &Filter,
+    ) -> Result<(), String> {
+        self.inner.dimensions.check(filter)?;
+        let state = self.inner.state.read().await;
+        let range = UtcMinute::from(time_from)..UtcMinute::from(time_to);
+        // The newest minute lacking a dimension tells how far back it is missing.
+        for (minute, dimensions) in state.stored_dimensions.range(range).rev() {
+            dimensions
+                .check(filter)
+                .map_err(|err| format!("{} before {}", err, minute.next().inner()))?;
+        }
+        Ok(())
+    }
+
+    async fn select_bucket_stats(
+        &self,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        filter: &Filter,
+    ) -> Vec<Bucket> {
+        let state = self.inner.state.read().await;
+        aggregates::minutes(time_from.into(), time_to.into())
+            .map(|minute| {
+                let dimensions = state.dimensions_of(minute, &self.inner.dimensions);
+                state
+                    .aggregates
+                    .get(&minute)
+                    .map(|aggregates| aggregates.select(filter, dimensions))
+                    .unwrap_or_default()
+                    .into_bucket(minute)
+            })
+            .collect()
+    }
+
+    async fn clear(&self, scope: DataScope, time_range: Option<TimeRange>) {
+        {
+            let mut state = self.inner.state.write().await;
+            let state = &mut *state;
+
+            if scope.includes_profiles() {
+                match time_range {
+                    None => state.profiles.clear(),
+                    Some(TimeRange { from, to }) => state.profiles.retain(|_, profile| {
+                        for tags in [&mut profile.views, &mut profile.buys] {
+                            tags.retain(|(time, _), _| !(from <= *time && *time < to));
+                        }
+                        !profile.is_empty()
+                    }),
+                }
+            }
+
+            if scope.includes_aggregates() {
+                let removed: Vec<_> = match time_range {
+                    None => state.aggregates.keys().copied().collect(),
+                    Some(TimeRange { from, to }) => state
+                        .aggregates
+                        .range(UtcMinute::from(from)..)
+                        .map(|(minute, _)| *minute)
+                        .take_while(|minute| minute.inner() < to)
+                        .collect(),
+                };
+                for minute in removed {
+                    state.aggregates.remove(&minute);
+                    state.stored_dimensions.remove(&minute);
+                    state.dirty_minutes.insert(minute);
+                }
+            }
+        }
+
+        // Otherwise cleared data would come back with the log replay after a restart.
+        self.inner
+            .compact()
+            .await
+            .expect("Failed to persist cleared state");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregates::Dimension;
+    use crate::mock::tests::{default_tag, moment_middle};
+    use crate::types::System as _;
+
+    use super::*;
+
+    fn tag_at(time: DateTime<Utc>, price: i32) -> UserTag {
+        let mut tag = default_tag();
+        tag.time = time.into();
+        tag.product_info.price = price;
+        tag
+    }
+
+    async fn profile_and_buckets(system: &System) -> (UserProfile, Vec<Bucket>) {
+        let minute = UtcMinute::from(moment_middle());
+        let profile = system
+            .last_tags_by_cookie("cookie", minute.inner(), minute.next().next().inner(), 200)
+            .await;
+        let mut filter = Filter::new(Action::Buy);
+        filter.values.insert(Dimension::BrandId, "2137".to_owned());
+        let buckets = system
+            .select_bucket_stats(minute.inner(), minute.next().next().inner(), &filter)
+            .await;
+        (profile, buckets)
+    }
+
+    #[tokio::test]
+    async fn state_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let before_restart = {
+            let system = System::open(dir.path(), Config::default()).await.unwrap();
+            system.register_user_tag(tag_at(moment_middle(), 10)).await;
+            // Tags of the same millisecond are all kept.
+            system.register_user_tag(tag_at(moment_middle(), 5)).await;
+            system.inner.compact().await.unwrap();
+            // This one is only in the log.
+            system
+                .register_user_tag(tag_at(moment_middle() + chrono::Duration::minutes(1), 20))
+                .await;
+            profile_and_buckets(&system).await
+        };
+        assert_eq!(before_restart.0.buys.len(), 3);
+        assert_eq!(before_restart.1[0].sum_price, 15);
+        assert_eq!(before_restart.1[1].sum_price, 20);
+
+        let system = System::open(dir.path(), Config::default()).await.unwrap();
+        assert_eq!(profile_and_buckets(&system).await, before_restart);
+
+        // Compacting again must not count replayed tags twice.
+        system.inner.compact().await.unwrap();
+        let system = System::open(dir.path(), Config::default()).await.unwrap();
+        assert_eq!(profile_and_buckets(&system).await, before_restart);
+
+        // Aggregates are regrouped when dimensions are dropped.
+        let regrouped = Config {
+            dimensions: Dimensions::new(vec![Dimension::BrandId]).unwrap(),
+            ..Config::default()
+        };
+        let system = System::open(dir.path(), regrouped.clone()).await.unwrap();
+        assert_eq!(profile_and_buckets(&system).await, before_restart);
+        system.inner.compact().await.unwrap();
+        let system = System::open(dir.path(), regrouped).await.unwrap();
+        assert_eq!(profile_and_buckets(&system).await, before_restart);
+    }
+
+    #[tokio::test]
+    async fn minutes_lacking_dimensions_are_not_regrouped() {
+        let dir = tempfile::tempdir().unwrap();
+        let minute = UtcMinute::from(moment_middle());
+        {
+            let system = System::open(dir.path(), Config::default()).await.unwrap();
+            system.register_user_tag(tag_at(moment_middle(), 10)).await;
+            system.inner.compact().await.unwrap();
+        }
+
+        let mut dimensions = Dimensions::legacy().iter().cloned().collect::<Vec<_>>();
+        dimensions.push(Dimension::Country);
+        let widened = Config {
+            dimensions: Dimensions::new(dimensions).unwrap(),
+            ..Config::default()
+        };
+        let system = System::open(dir.path(), widened.clone()).await.unwrap();
+        system
+            .register_user_tag(tag_at(moment_middle() + chrono::Duration::minutes(1), 20))
+            .await;
+        system.inner.compact().await.unwrap();
+        let system = System::open(dir.path(), widened).await.unwrap();
+
+        let mut filter = Filter::new(Action::Buy);
+        filter.values.insert(Dimension::Country, "".to_owned());
+        let err = system
+            .check_filter(minute.inner(), minute.next().next().inner(), &filter)
+            .await
+            .unwrap_err();
+        assert!(err.contains("country"));
+        let next = minute.next();
+        assert!(system
+            .check_filter(next.inner(), next.next().inner(), &filter)
+            .await
+            .is_ok());
+        filter.values.insert(Dimension::Country, "PL".to_owned());
+        let buckets = system
+            .select_bucket_stats(next.inner(), next.next().inner(), &filter)
+            .await;
+        assert_eq!(buckets[0].sum_price, 20);
+
+        // The old minute still counts tags by the dimensions it has.
+        system.register_user_tag(tag_at(moment_middle(), 5)).await;
+        let (_, buckets) = profile_and_buckets(&system).await;
+        assert_eq!(buckets[0].sum_price, 15);
+    }
+
+    #[tokio::test]
+    async fn profiles_are_capped_and_old_data_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = System::open(dir.path(), Config::default()).await.unwrap();
+
+        for i in 0..(MAX_TAGS_BY_COOKIE as i64 + 10) {
+            system
+                .register_user_tag(tag_at(
+                    moment_middle() + chrono::Duration::milliseconds(i),
+                    1,
+                ))
+                .await;
+        }
+        let (profile, buckets) = profile_and_buckets(&system).await;
+        assert_eq!(profile.buys.len(), MAX_TAGS_BY_COOKIE);
+        assert_eq!(buckets[0].count, MAX_TAGS_BY_COOKIE as i64 + 10);
+
+        system
+            .register_user_tag(tag_at(moment_middle() + chrono::Duration::days(2), 1))
+            .await;
+        system.inner.compact().await.unwrap();
+        let (profile, buckets) = profile_and_buckets(&system).await;
+        assert!(profile.buys.is_empty());
+        assert_eq!(buckets[0].count, 0);
+
+        // Tags older than the retention are not accepted anymore.
+        system.register_user_tag(tag_at(moment_middle(), 1)).await;
+        let (profile, _) = profile_and_buckets(&system).await;
+        assert!(profile.buys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clear_is_persistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = System::open(dir.path(), Config::default()).await.unwrap();
+        system.register_user_tag(tag_at(moment_middle(), 10)).await;
+        system.clear(DataScope::All, None).await;
+
+        let system = System::open(dir.path(), Config::default()).await.unwrap();
+        let (profile, buckets) = profile_and_buckets(&system).await;
+        assert!(profile.buys.is_empty());
+        assert_eq!(buckets[0].count, 0);
+    }
+}
@@ -1,71 +1,498 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    fmt::Display,
+    io,
+    sync::Arc,
+};
 
 use axum::{
-    extract::{rejection::QueryRejection, Json, Path, Query, State},
+    body::Bytes,
+    extract::{
+        rejection::QueryRejection, ws::WebSocketUpgrade, FromRequest, Json, Path, Query, State,
+    },
+    http::{header, HeaderMap, Request},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Router,
 };
+use chrono_tz::Tz;
+use futures::{stream, Stream, StreamExt};
 use reqwest::StatusCode;
 use serde::{de::Visitor, Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
 
 use tracing::log;
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+
+use crate::admission::{self, Admission, Route};
+use crate::aggregates::{Dimension, Filter};
+use crate::dedup::IdempotencyKey;
+use crate::event_log::{self, EmbeddedLog, Ingestion};
+use crate::export::{Cell, Column, Format, Kind, Table};
+use crate::ingest_queue::{self, IngestQueue};
+use crate::input::TagBody;
+use crate::live::{self, MinuteClock};
+use crate::subscriptions::{self, ClientMessage, Overflow, ServerMessage};
+use crate::types::{
+    Action, Bucket, DataScope, Device, MemoryUsage, Precision, ProductInfo, ProfileCursor,
+    SnapshotInfo, System, TagPosition, TagsPage, TimeRange, TimeRangeParam, UserProfile, UserTag,
+    UtcMinute,
+};
+use crate::utils;
+use crate::watermark::{self, Watermark};
+
+pub type SharedSystem = Arc<dyn System>;
 
-use crate::types::{Action, Bucket, System, TimeRange, UserProfile, UserTag};
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Token expected in `Authorization: Bearer <token>` by administrative
+    /// endpoints. When unset, those endpoints are disabled altogether.
+    pub admin_token: Option<String>,
+    /// When set, tags are classified against the watermark, and the ones
+    /// too late or too far in the future are not applied.
+    pub watermark: Option<watermark::Config>,
+    /// Whether tags without an `Idempotency-Key` header are deduplicated
+    /// by their contents.
+    pub derive_idempotency_keys: bool,
+    /// When set, tags are acknowledged once they are durably enqueued,
+    /// and are applied to the system in the background.
+    pub ingest_queue: Option<ingest_queue::Config>,
+    /// When set, tags are acknowledged once they are published to a
+    /// partitioned event log, and are applied to the system by its consumers.
+    pub event_log: Option<event_log::Config>,
+    pub admission: admission::Config,
+    /// Whether times finer than the spec allows are truncated or rejected.
+    pub time_precision: Precision,
+}
+
+#[derive(Clone, axum_macros::FromRef)]
+struct AppState {
+    system: SharedSystem,
+    config: Arc<Config>,
+    watermark: Option<Arc<Watermark>>,
+    queue: Option<Arc<IngestQueue>>,
+    event_log: Option<Arc<Ingestion>>,
+    admission: Arc<Admission>,
+    feed: Arc<live::Feed>,
+    precision: Precision,
+}
 
-type AppState = Arc<dyn System>;
+pub fn build_router(initial_session: impl System + 'static, config: Config) -> Router {
+    build_shared_router(Arc::new(initial_session), config)
+}
 
-pub fn build_router(initial_session: impl System + 'static) -> Router {
+/// Like [`build_router`], for a system that is also used elsewhere.
+pub fn build_shared_router(system: SharedSystem, config: Config) -> Router {
+    let feed = Arc::new(live::Feed::new());
+    let system: SharedSystem = Arc::new(live::System::new(system, Arc::clone(&feed)));
+    let queue = config.ingest_queue.clone().map(|queue_config| {
+        Arc::new(
+            IngestQueue::open(queue_config, Arc::clone(&system))
+                .expect("Failed to open ingest queue"),
+        )
+    });
+    let event_log = config.event_log.as_ref().map(|log_config| {
+        let log = EmbeddedLog::open(log_config).expect("Failed to open event log");
+        Arc::new(Ingestion::start(
+            Arc::new(log),
+            Arc::clone(&system),
+            log_config.max_lag,
+        ))
+    });
+    let admission = Arc::new(Admission::new(config.admission));
+    let admit =
+        |route| middleware::from_fn_with_state((Arc::clone(&admission), route), admission::admit);
     Router::new()
         .route("/echo", get(|| async { "ECHO!" }))
-        .route("/user_tags", post(use_case_1))
-        .route("/user_profiles/:cookie", post(use_case_2))
-        .route("/aggregates", post(use_case_3))
+        .route("/health", get(health))
+        .route("/openapi.json", get(openapi))
+        .route("/user_tags", post(use_case_1).layer(admit(Route::UserTags)))
+        .route(
+            "/user_profiles/:cookie",
+            post(use_case_2).layer(admit(Route::UserProfiles)),
+        )
+        .route(
+            "/aggregates",
+            post(use_case_3).layer(admit(Route::Aggregates)),
+        )
+        // Not admitted, as it would hold its slot for as long as it is open.
+        .route("/aggregates/stream", get(aggregates_stream))
+        .route("/user_tags/stream", get(user_tags_stream))
         .route("/clear", post(clear))
-        .with_state(Arc::new(initial_session))
+        .route("/admin/snapshot", post(snapshot))
+        .route("/admin/memory", get(memory_usage))
+        .route("/admin/watermark", get(watermark_stats))
+        .route("/admin/admission", get(admission_stats))
+        .nest(
+            "/v2",
+            Router::new()
+                .route("/user_tags", post(use_case_1).layer(admit(Route::UserTags)))
+                .route(
+                    "/user_profiles/:cookie",
+                    post(user_profile_v2).layer(admit(Route::UserProfiles)),
+                )
+                .route(
+                    "/aggregates",
+                    post(aggregates_v2).layer(admit(Route::Aggregates)),
+                )
+                .layer(middleware::from_fn(problems)),
+        )
+        .with_state(AppState {
+            system,
+            queue,
+            event_log,
+            admission,
+            feed,
+            precision: config.time_precision,
+            watermark: config.watermark.clone().map(|watermark_config| {
+                Arc::new(Watermark::new(watermark_config).expect("Failed to open dead-letter file"))
+            }),
+            config: Arc::new(config),
+        })
+}
+
+/// OpenAPI document of the endpoints of the spec and of API v2.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Allezon"),
+    paths(
+        health,
+        openapi,
+        use_case_1,
+        use_case_2,
+        use_case_3,
+        aggregates_stream,
+        user_tags_stream,
+        user_profile_v2,
+        aggregates_v2
+    ),
+    components(schemas(
+        UserTag,
+        Device,
+        Action,
+        ProductInfo,
+        UserProfile,
+        Aggregate,
+        Format,
+        UseCase3Response,
+        Problem,
+        UserProfileV2Response,
+        UserProfileMeta,
+        TagsMeta,
+        Cell,
+        AggregatesV2Response,
+        Overflow,
+        ClientMessage,
+        ServerMessage
+    )),
+    modifiers(&UserTagsV2)
+)]
+pub struct ApiDoc;
+
+/// Documents `/v2/user_tags`, which is served by the handler of `/user_tags`.
+struct UserTagsV2;
+
+impl Modify for UserTagsV2 {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let mut item = openapi.paths.paths["/user_tags"].clone();
+        for operation in item.operations.values_mut() {
+            operation.operation_id = Some("use_case_1_v2".to_owned());
+        }
+        openapi.paths.paths.insert("/v2/user_tags".to_owned(), item);
+    }
+}
+
+#[utoipa::path(get, path = "/health", responses((status = 200, body = String)))]
+async fn health() -> &'static str {
+    "OK"
+}
+
+#[utoipa::path(get, path = "/openapi.json", responses((status = 200, description = "This document")))]
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
 }
 
-async fn clear(State(system): State<AppState>) {
-    log::info!("Clearing the system");
-    system.clear().await;
+fn authorize_admin(config: &Config, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let Some(expected) = config.admin_token.as_deref() else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "administrative endpoints are disabled: no admin token configured".to_owned(),
+        ));
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if utils::constant_time_eq(provided.as_bytes(), expected.as_bytes()) => {
+            Ok(())
+        }
+        Some(_) => Err((StatusCode::FORBIDDEN, "invalid admin token".to_owned())),
+        None => Err((StatusCode::UNAUTHORIZED, "missing admin token".to_owned())),
+    }
+}
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClearParams {
+    #[serde(default)]
+    scope: DataScope,
+    time_range: Option<TimeRange>,
+}
+
+async fn clear(
+    State(system): State<SharedSystem>,
+    State(config): State<Arc<Config>>,
+    State(queue): State<Option<Arc<IngestQueue>>>,
+    State(event_log): State<Option<Arc<Ingestion>>>,
+    headers: HeaderMap,
+    Query(params): Query<ClearParams>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    authorize_admin(&config, &headers)?;
+
+    // Tags acknowledged before are applied first, so that they are cleared too.
+    if let Some(queue) = queue {
+        queue.drain().await;
+    }
+    if let Some(event_log) = event_log {
+        event_log.drain().await.map_err(|err| {
+            log::error!("Failed to drain the event log: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })?;
+    }
+    log::info!(
+        "Clearing the system (scope: {:?}, time range: {:?})",
+        params.scope,
+        params.time_range
+    );
+    system.clear(params.scope, params.time_range).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn snapshot(
+    State(system): State<SharedSystem>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<SnapshotInfo>, (StatusCode, String)> {
+    authorize_admin(&config, &headers)?;
+
+    match system.snapshot().await {
+        Some(Ok(info)) => {
+            log::info!("Written snapshot to {}", info.path.display());
+            Ok(Json(info))
+        }
+        Some(Err(err)) => {
+            log::error!("Failed to write snapshot: {}", err);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+        }
+        None => Err((
+            StatusCode::NOT_IMPLEMENTED,
+            "snapshots are not enabled for this backend".to_owned(),
+        )),
+    }
+}
+
+async fn memory_usage(
+    State(system): State<SharedSystem>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<MemoryUsage>, (StatusCode, String)> {
+    authorize_admin(&config, &headers)?;
+
+    system.memory_usage().await.map(Json).ok_or((
+        StatusCode::NOT_IMPLEMENTED,
+        "memory usage is not tracked by this backend".to_owned(),
+    ))
+}
+
+async fn watermark_stats(
+    State(watermark): State<Option<Arc<Watermark>>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<watermark::Stats>, (StatusCode, String)> {
+    authorize_admin(&config, &headers)?;
+
+    watermark.map(|watermark| Json(watermark.stats())).ok_or((
+        StatusCode::NOT_IMPLEMENTED,
+        "the watermark is disabled".to_owned(),
+    ))
+}
+
+async fn admission_stats(
+    State(admission): State<Arc<Admission>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> Result<Json<admission::Stats>, (StatusCode, String)> {
+    authorize_admin(&config, &headers)?;
+
+    Ok(Json(admission.stats()))
+}
+
+/// Response to a tag which could not be handed over to be applied in the
+/// background: 503 if there are too many tags waiting already.
+fn ingestion_error(err: io::Error) -> (StatusCode, String) {
+    let status = match err.kind() {
+        io::ErrorKind::WouldBlock => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, err.to_string())
 }
 
 // `StatusCode` implement `IntoResponse` and therefore
 // `Result<Status, StatusCode>` also implements `IntoResponse`
-#[axum_macros::debug_handler] // <- this provides better error messages
+#[utoipa::path(
+    post,
+    path = "/user_tags",
+    request_body(
+        content = UserTag,
+        content_type = "application/json",
+        description = "Also accepted as MessagePack (`application/msgpack`) or protobuf \
+            (`application/x-protobuf`, see `proto/user_tag.proto`), optionally compressed \
+            with gzip or zstd."
+    ),
+    params(("Idempotency-Key" = Option<String>, Header, description = "Makes retries safe")),
+    responses(
+        (status = 204, description = "Tag registered"),
+        (status = 415, description = "Unsupported content type or encoding"),
+        (status = 422, description = "Malformed tag, or its time is outside of the accepted range"),
+        (status = 503, description = "Too many tags are waiting in the ingest queue or event log"),
+    )
+)]
+#[axum_macros::debug_handler(state = AppState)] // <- this provides better error messages
+#[allow(clippy::too_many_arguments)] // one per extractor
 async fn use_case_1(
-    State(system): State<AppState>, // extract state in this handler
-    Query(_params): Query<()>,      // this asserts that the params are empty
-    Json(tag): Json<UserTag>,
-) -> Result<StatusCode, StatusCode> {
+    State(system): State<SharedSystem>, // extract state in this handler
+    State(watermark): State<Option<Arc<Watermark>>>,
+    State(config): State<Arc<Config>>,
+    State(queue): State<Option<Arc<IngestQueue>>>,
+    State(event_log): State<Option<Arc<Ingestion>>>,
+    headers: HeaderMap,
+    Query(_params): Query<()>, // this asserts that the params are empty
+    TagBody(tag): TagBody,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if let Some(watermark) = watermark {
+        let class = watermark.observe(tag.time.inner(), chrono::Utc::now());
+        if !class.is_accepted() {
+            log::warn!("Not applying {:?} user tag from {}", class, tag.time);
+            if !watermark.diverts() {
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("user tag time {} is {:?}", tag.time, class),
+                ));
+            }
+            watermark.divert(&tag, class).await.map_err(|err| {
+                log::error!("Failed to store dead letter: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+            })?;
+            return Ok(StatusCode::NO_CONTENT);
+        }
+    }
+
+    let key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(value) => Some(IdempotencyKey::explicit(value.to_str().map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "invalid Idempotency-Key header".to_owned(),
+            )
+        })?)),
+        None => config
+            .derive_idempotency_keys
+            .then(|| IdempotencyKey::derived(&tag)),
+    };
+
+    if let Some(event_log) = event_log {
+        log::info!("Publishing user tag");
+        event_log.publish(&tag, key).await.map_err(|err| {
+            log::error!("Failed to publish user tag: {}", err);
+            ingestion_error(err)
+        })?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    if let Some(queue) = queue {
+        log::info!("Enqueuing user tag");
+        queue.push(tag, key).await.map_err(|err| {
+            log::error!("Failed to enqueue user tag: {}", err);
+            ingestion_error(err)
+        })?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
     log::info!("Registering user tag");
-    system.register_user_tag(tag).await;
+    match key {
+        Some(key) => {
+            if !system.register_user_tag_once(tag, key).await {
+                log::debug!("Skipping duplicate user tag");
+            }
+        }
+        None => system.register_user_tag(tag).await,
+    }
 
     Ok(StatusCode::NO_CONTENT)
 }
 
 #[cfg_attr(test, derive(Serialize))]
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 #[serde(deny_unknown_fields)]
 struct UseCase2Params {
-    time_range: TimeRange,
+    /// Bounds without an offset are in `tz`; bounds may also have one, or be
+    /// relative to the current time, e.g. `now-1h_now`.
+    #[param(value_type = String, example = "2022-03-22T12:15:00.000_2022-03-22T12:16:00.000")]
+    time_range: TimeRangeParam,
+    /// Time zone of `time_range`, e.g. `Europe/Warsaw`, UTC by default.
+    #[param(value_type = Option<String>)]
+    tz: Option<Tz>,
+    /// Limit on the number of tags of each kind, 200 by default.
     limit: Option<i32>,
+    /// Pages through all the tags: empty for the first page, or the
+    /// `Next-Cursor` header of the previous page.
+    cursor: Option<String>,
 }
 
-#[axum_macros::debug_handler] // <- this provides better error messages
+/// Header with the cursor of the next page of a profile.
+const NEXT_CURSOR: &str = "next-cursor";
+
+#[utoipa::path(
+    post,
+    path = "/user_profiles/{cookie}",
+    params(("cookie" = String, Path, description = "Cookie of the user"), UseCase2Params),
+    responses((
+        status = 200,
+        body = UserProfile,
+        headers(("Next-Cursor" = String, description = "Cursor of the next page, when paging with `cursor` and more tags follow"))
+    ))
+)]
+#[axum_macros::debug_handler(state = AppState)] // <- this provides better error messages
 async fn use_case_2(
-    State(session): State<AppState>, // extract state in this handler
+    State(session): State<SharedSystem>, // extract state in this handler
+    State(precision): State<Precision>,
     Path(cookie): Path<String>,
     Query(params): Query<UseCase2Params>,
-) -> Result<Json<UserProfile>, (StatusCode, String)> {
+) -> Result<Response, (StatusCode, String)> {
     log::info!("Getting user profile");
 
     let UseCase2Params {
-        time_range: TimeRange {
-            from: time_from,
-            to: time_to,
-        },
+        time_range,
+        tz,
         limit,
+        cursor,
     } = params;
+    let time_range = time_range
+        .in_zone(tz.unwrap_or(Tz::UTC))
+        .and_then(|time_range| time_range.in_millis(precision))
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
 
     if let Some(limit) = limit {
         if !(0..=200).contains(&limit) {
@@ -76,14 +503,41 @@ async fn use_case_2(
         }
     }
 
-    let user_profile = session
-        .last_tags_by_cookie(&cookie, time_from, time_to, limit.unwrap_or(200) as usize)
-        .await;
+    let limit = limit.unwrap_or(200) as usize;
 
-    Ok(Json(user_profile))
+    let Some(cursor) = cursor else {
+        let user_profile = session
+            .last_tags_by_cookie(&cookie, time_range.from, time_range.to, limit)
+            .await;
+        return Ok(Json(user_profile).into_response());
+    };
+    let cursor = match cursor.as_str() {
+        "" => None,
+        cursor => Some(
+            ProfileCursor::decode(cursor)
+                .ok_or((StatusCode::BAD_REQUEST, "invalid cursor".to_owned()))?,
+        ),
+    };
+    let (views, buys, next) =
+        profile_page(session.as_ref(), &cookie, time_range, cursor, limit).await;
+    let user_profile = UserProfile {
+        cookie,
+        views: views.tags,
+        buys: buys.tags,
+    };
+    let mut response = Json(user_profile).into_response();
+    if let Some(next) = next {
+        response.headers_mut().insert(
+            NEXT_CURSOR,
+            next.encode()
+                .parse()
+                .expect("cursor is a valid header value"),
+        );
+    }
+    Ok(response)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Aggregate {
     Count,
@@ -140,14 +594,28 @@ impl Aggregates {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct UseCase3Params {
+    /// As in `/user_profiles`.
+    #[param(value_type = String, example = "2022-03-22T12:15:00_2022-03-22T12:16:00")]
     time_range: TimeRange,
+    /// Time zone of `time_range` and of the bucket times, UTC by default.
+    #[param(value_type = Option<String>)]
+    tz: Tz,
+    #[param(inline, example = "BUY")]
     action: Action,
+    /// At most two, in the order of the result columns.
+    #[param(value_type = Option<Vec<Aggregate>>)]
     aggregates: Aggregates,
-    origin: Option<String>,
-    brand_id: Option<String>,
-    category_id: Option<String>,
+    /// Values of the dimensions to filter on, each given as a parameter:
+    /// `origin`, `brand_id`, `category_id`, `country`, `device` or
+    /// `attributes.[name]`, among the ones aggregates are kept for.
+    #[param(value_type = Option<BTreeMap<String, String>>, style = Form, explode)]
+    filters: BTreeMap<Dimension, String>,
+    /// Overrides the `Accept` header.
+    #[param(inline)]
+    format: Option<Format>,
 }
 
 use std::fmt;
@@ -165,9 +633,10 @@ impl<'de> Deserialize<'de> for UseCase3Params {
             TimeRange,
             Action,
             Aggregates,
-            Origin,
-            BrandId,
-            CategoryId,
+            Format,
+            Tz,
+            /// Any other parameter filters on a dimension.
+            Dimension(String),
         }
 
         struct UseCase3ParamsVisitor;
@@ -183,10 +652,10 @@ impl<'de> Deserialize<'de> for UseCase3Params {
                 V: MapAccess<'de>,
             {
                 let mut action = None;
-                let mut origin = None;
                 let mut time_range = None;
-                let mut brand_id = None;
-                let mut category_id = None;
+                let mut filters = BTreeMap::new();
+                let mut format = None;
+                let mut tz = None;
                 let mut aggregates = Aggregates::new();
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -196,29 +665,33 @@ impl<'de> Deserialize<'de> for UseCase3Params {
                             }
                             action = Some(map.next_value()?);
                         }
-                        Field::Origin => {
-                            if origin.is_some() {
-                                return Err(de::Error::duplicate_field("origin"));
+                        Field::Dimension(name) => {
+                            let dimension = name.parse::<Dimension>().map_err(de::Error::custom)?;
+                            if filters.contains_key(&dimension) {
+                                return Err(de::Error::custom(format!(
+                                    "duplicate field `{}`",
+                                    name
+                                )));
                             }
-                            origin = Some(map.next_value()?);
+                            filters.insert(dimension, map.next_value()?);
                         }
                         Field::TimeRange => {
                             if time_range.is_some() {
                                 return Err(de::Error::duplicate_field("time_range"));
                             }
-                            time_range = Some(map.next_value()?);
+                            time_range = Some(map.next_value::<TimeRangeParam>()?);
                         }
-                        Field::BrandId => {
-                            if brand_id.is_some() {
-                                return Err(de::Error::duplicate_field("brand_id"));
+                        Field::Format => {
+                            if format.is_some() {
+                                return Err(de::Error::duplicate_field("format"));
                             }
-                            brand_id = Some(map.next_value()?);
+                            format = Some(map.next_value()?);
                         }
-                        Field::CategoryId => {
-                            if category_id.is_some() {
-                                return Err(de::Error::duplicate_field("category_id"));
+                        Field::Tz => {
+                            if tz.is_some() {
+                                return Err(de::Error::duplicate_field("tz"));
                             }
-                            category_id = Some(map.next_value()?);
+                            tz = Some(map.next_value()?);
                         }
                         Field::Aggregates => aggregates
                             .add(map.next_value()?)
@@ -226,27 +699,23 @@ impl<'de> Deserialize<'de> for UseCase3Params {
                     }
                 }
                 let action = action.ok_or_else(|| de::Error::missing_field("action"))?;
-                let time_range =
-                    time_range.ok_or_else(|| de::Error::missing_field("time_range"))?;
+                let tz = tz.unwrap_or(Tz::UTC);
+                let time_range = time_range
+                    .ok_or_else(|| de::Error::missing_field("time_range"))?
+                    .in_zone(tz)
+                    .map_err(de::Error::custom)?;
                 Ok(UseCase3Params {
                     time_range,
+                    tz,
                     action,
                     aggregates,
-                    origin,
-                    brand_id,
-                    category_id,
+                    filters,
+                    format,
                 })
             }
         }
 
-        const FIELDS: &[&str] = &[
-            "origin",
-            "action",
-            "time_range",
-            "brand_id",
-            "category_id",
-            "aggregates",
-        ];
+        const FIELDS: &[&str] = &["action", "time_range", "aggregates", "format", "tz"];
         deserializer.deserialize_struct("UseCase3Params", FIELDS, UseCase3ParamsVisitor)
     }
 }
@@ -259,7 +728,7 @@ impl<'de> Deserialize<'de> for UseCase3Params {
 //       ["2022-03-01T00:06:00", "BUY", "Nike", "1500", "4"],
 //       ["2022-03-01T00:07:00", "BUY", "Nike", "1200", "2"]
 // }
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
 struct UseCase3Response {
     /*
     ▪ First column is called "1m_bucket" .
@@ -272,6 +741,7 @@ struct UseCase3Response {
     their ends.
     ▪ Filter columns are in the following order: "action", "origin",
     "brand_id", "category_id" .
+    (Beyond the spec, then "country", "device" and "attributes.[name]".)
     ▪ Include only those with not-null values (i.e. present in the
     query, but with the order defined above).
     ▪ Aggregate columns are listed in the order from the query.
@@ -284,101 +754,682 @@ struct UseCase3Response {
 
 impl UseCase3Response {
     fn new(params: UseCase3Params, buckets: Vec<Bucket>) -> Self {
-        let UseCase3Params {
-            action,
-            aggregates: Aggregates { fst, snd },
-            origin,
-            brand_id,
-            category_id,
-            ..
-        } = params;
-
-        // ▪ First column is called "1m_bucket".
-        // Action is mandatory as well.
-        let mut columns = vec!["1m_bucket".to_owned(), "action".to_owned()];
-
-        // ▪ Filter columns are in the following order: "action", "origin", "brand_id", "category_id".
-        if origin.is_some() {
-            columns.push("origin".to_owned());
-        }
-        if brand_id.is_some() {
-            columns.push("brand_id".to_owned());
-        }
-        if category_id.is_some() {
-            columns.push("category_id".to_owned());
+        // ▪ ALL VALUES ARE STRINGS (including aggregates: count, sum_price).
+        let Table { columns, rows } = aggregates_table(params, buckets);
+        Self {
+            columns: columns.into_iter().map(|column| column.name).collect(),
+            rows: rows
+                .into_iter()
+                .map(|row| row.iter().map(Cell::to_string).collect())
+                .collect(),
         }
+    }
+}
 
-        for agg in [fst, snd].into_iter().flatten() {
-            columns.push(agg.display().to_owned());
+impl UseCase3Params {
+    fn filter(&self) -> Filter {
+        Filter {
+            action: self.action,
+            values: self.filters.clone(),
         }
+    }
+
+    async fn check_filter(&self, system: &dyn System) -> Result<(), String> {
+        system
+            .check_filter(self.time_range.from, self.time_range.to, &self.filter())
+            .await
+    }
+
+    async fn select_buckets(&self, system: &dyn System) -> Vec<Bucket> {
+        system
+            .select_bucket_stats(self.time_range.from, self.time_range.to, &self.filter())
+            .await
+    }
+}
+
+/// Use case 3 result with aggregates kept numeric, for the non-spec formats.
+fn aggregates_table(params: UseCase3Params, buckets: Vec<Bucket>) -> Table {
+    let UseCase3Params {
+        action,
+        aggregates: Aggregates { fst, snd },
+        filters,
+        tz,
+        ..
+    } = params;
+    let text = |name: &str| Column {
+        name: name.to_owned(),
+        kind: Kind::Text,
+    };
+
+    // ▪ First column is called "1m_bucket".
+    // Action is mandatory as well.
+    let mut columns = vec![text("1m_bucket"), text("action")];
+
+    // ▪ Filter columns are in the following order: "action", "origin", "brand_id", "category_id".
+    // Dimensions are ordered that way.
+    for dimension in filters.keys() {
+        columns.push(text(&dimension.to_string()));
+    }
 
-        let rows = buckets
-            .into_iter()
-            .map(
-                |Bucket {
-                     minute,
-                     count,
-                     sum_price,
-                 }| {
-                    let mut columns = vec![
+    for agg in [fst, snd].into_iter().flatten() {
+        columns.push(Column {
+            name: agg.display().to_owned(),
+            kind: Kind::Integer,
+        });
+    }
+
+    let rows = buckets
+        .into_iter()
+        .map(
+            |Bucket {
+                 minute,
+                 count,
+                 sum_price,
+             }| {
+                let mut columns = vec![
+                    Cell::Text(
                         minute
                             .inner()
-                            .naive_utc()
+                            .with_timezone(&tz)
+                            .naive_local()
                             .format("%Y-%m-%dT%H:%M:%S")
                             .to_string(),
-                        action.to_string(),
-                    ];
+                    ),
+                    Cell::Text(action.to_string()),
+                ];
 
-                    // ▪ Filter columns are in the following order: "action", "origin", "brand_id", "category_id".
-                    if let Some(origin) = origin.clone() {
-                        columns.push(origin);
-                    }
-                    if let Some(brand_id) = brand_id.clone() {
-                        columns.push(brand_id);
-                    }
-                    if let Some(category_id) = category_id.clone() {
-                        columns.push(category_id);
-                    }
+                // ▪ Filter columns are in the following order: "action", "origin", "brand_id", "category_id".
+                for value in filters.values() {
+                    columns.push(Cell::Text(value.clone()));
+                }
 
-                    for agg in [fst, snd].into_iter().flatten() {
-                        let agg_val = match agg {
-                            Aggregate::Count => count.to_string(),
-                            Aggregate::SumPrice => sum_price.to_string(),
-                        };
-                        columns.push(agg_val);
-                    }
+                for agg in [fst, snd].into_iter().flatten() {
+                    let agg_val = match agg {
+                        Aggregate::Count => count,
+                        Aggregate::SumPrice => sum_price,
+                    };
+                    columns.push(Cell::Integer(agg_val));
+                }
 
-                    columns
-                },
-            )
-            .collect();
+                columns
+            },
+        )
+        .collect();
 
-        Self { columns, rows }
-    }
+    Table { columns, rows }
 }
 
-#[axum_macros::debug_handler] // <- this provides better error messages
+#[utoipa::path(
+    post,
+    path = "/aggregates",
+    params(UseCase3Params),
+    request_body(
+        content = Option<UseCase3Response>,
+        description = "Expected response, compared with the actual one by the spec's test clients"
+    ),
+    responses((status = 200, description = "Aggregates per minute, as negotiated by `Accept` or `format`",
+        content(
+            ("application/json" = UseCase3Response),
+            ("text/csv" = String),
+            ("application/x-ndjson" = String),
+            ("application/vnd.apache.arrow.stream" = Vec<u8>),
+        )
+    ))
+)]
+#[axum_macros::debug_handler(state = AppState)] // <- this provides better error messages
 async fn use_case_3(
-    State(system): State<AppState>, // extract state in this handler
+    State(system): State<SharedSystem>, // extract state in this handler
+    State(precision): State<Precision>,
+    headers: HeaderMap,
     params: Result<Query<UseCase3Params>, QueryRejection>, // <-- for debug
-    Json(expected_response): Json<UseCase3Response>,
+    // Only the spec's test clients send the expected response.
+    expected_response: Option<Json<UseCase3Response>>,
     // Query(params): Query<UseCase3Params>,
-) -> Result<Json<UseCase3Response>, StatusCode> {
-    let Query(params) = params.unwrap();
-    let buckets = system
-        .select_bucket_stats(
-            params.time_range.from,
-            params.time_range.to,
-            params.action,
-            params.origin.as_deref(),
-            params.brand_id.as_deref(),
-            params.category_id.as_deref(),
+) -> Result<Response, StatusCode> {
+    let Query(mut params) = params.map_err(|rejection| {
+        log::debug!("Rejecting aggregates query: {}", rejection);
+        StatusCode::BAD_REQUEST
+    })?;
+    params.time_range = params
+        .time_range
+        .in_minutes(precision)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    params
+        .check_filter(&*system)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let format = params
+        .format
+        .or_else(|| Format::from_accept(&headers))
+        .unwrap_or(Format::Json);
+    let buckets = params.select_buckets(&*system).await;
+
+    if format == Format::Json {
+        let response = UseCase3Response::new(params, buckets);
+        if let Some(Json(expected_response)) = expected_response {
+            assert_eq!(response, expected_response);
+        }
+        return Ok(Json(response).into_response());
+    }
+
+    let table = aggregates_table(params, buckets);
+    let body = match format {
+        Format::Json => unreachable!(),
+        Format::Csv => table.to_csv(),
+        Format::Ndjson => table.to_ndjson(),
+        Format::Arrow => table.to_arrow(),
+    };
+    Ok(([(header::CONTENT_TYPE, format.content_type())], body).into_response())
+}
+
+#[derive(Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AggregatesStreamParams {
+    #[param(inline, example = "BUY")]
+    action: Action,
+    /// At most two, in the order of the result columns.
+    #[param(value_type = Option<Vec<Aggregate>>)]
+    aggregates: Aggregates,
+    /// Values of the dimensions to filter on, each given as a parameter:
+    /// `origin`, `brand_id`, `category_id`, `country`, `device` or
+    /// `attributes.[name]`, among the ones aggregates are kept for.
+    #[param(value_type = Option<BTreeMap<String, String>>, style = Form, explode)]
+    filters: BTreeMap<Dimension, String>,
+    /// Whether to also push rows of the open minutes whenever matching
+    /// tags arrive, `false` by default.
+    provisional: Option<bool>,
+}
+
+impl<'de> Deserialize<'de> for AggregatesStreamParams {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(field_identifier, rename_all = "snake_case")]
+        enum Field {
+            Action,
+            Aggregates,
+            Provisional,
+            /// Any other parameter filters on a dimension.
+            Dimension(String),
+        }
+
+        struct AggregatesStreamParamsVisitor;
+        impl<'de> Visitor<'de> for AggregatesStreamParamsVisitor {
+            type Value = AggregatesStreamParams;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("struct AggregatesStreamParams")
+            }
+
+            fn visit_map<V>(self, mut map: V) -> Result<AggregatesStreamParams, V::Error>
+            where
+                V: MapAccess<'de>,
+            {
+                let mut action = None;
+                let mut filters = BTreeMap::new();
+                let mut provisional = None;
+                let mut aggregates = Aggregates::new();
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        Field::Action => {
+                            if action.is_some() {
+                                return Err(de::Error::duplicate_field("action"));
+                            }
+                            action = Some(map.next_value()?);
+                        }
+                        Field::Dimension(name) => {
+                            let dimension = name.parse::<Dimension>().map_err(de::Error::custom)?;
+                            if filters.contains_key(&dimension) {
+                                return Err(de::Error::custom(format!(
+                                    "duplicate field `{}`",
+                                    name
+                                )));
+                            }
+                            filters.insert(dimension, map.next_value()?);
+                        }
+                        Field::Provisional => {
+                            if provisional.is_some() {
+                                return Err(de::Error::duplicate_field("provisional"));
+                            }
+                            provisional = Some(map.next_value()?);
+                        }
+                        Field::Aggregates => aggregates
+                            .add(map.next_value()?)
+                            .map_err(de::Error::custom)?,
+                    }
+                }
+                let action = action.ok_or_else(|| de::Error::missing_field("action"))?;
+                Ok(AggregatesStreamParams {
+                    action,
+                    aggregates,
+                    filters,
+                    provisional,
+                })
+            }
+        }
+
+        const FIELDS: &[&str] = &["action", "aggregates", "provisional"];
+        deserializer.deserialize_struct(
+            "AggregatesStreamParams",
+            FIELDS,
+            AggregatesStreamParamsVisitor,
         )
-        .await;
+    }
+}
+
+impl AggregatesStreamParams {
+    fn filter(&self) -> Filter {
+        Filter {
+            action: self.action,
+            values: self.filters.clone(),
+        }
+    }
 
-    let response = UseCase3Response::new(params, buckets);
-    assert_eq!(response, expected_response);
-    Ok(Json(response))
+    /// Use case 3 query of the minutes in `[from, to)`.
+    fn query(&self, from: UtcMinute, to: UtcMinute) -> UseCase3Params {
+        UseCase3Params {
+            time_range: TimeRange {
+                from: from.inner(),
+                to: to.inner(),
+            },
+            action: self.action,
+            aggregates: self.aggregates.clone(),
+            filters: self.filters.clone(),
+            format: None,
+            tz: Tz::UTC,
+        }
+    }
+
+    async fn event(
+        &self,
+        name: &str,
+        (from, to): (UtcMinute, UtcMinute),
+        system: &dyn System,
+    ) -> Event {
+        let params = self.query(from, to);
+        let buckets = params.select_buckets(system).await;
+        Event::default()
+            .event(name)
+            .json_data(UseCase3Response::new(params, buckets))
+            .expect("Serializing aggregates failed")
+    }
+
+    /// Waits for tags closing minutes (or, with `provisional`, matching the
+    /// filter) and returns the events with their rows. Returns `None` once
+    /// the feed is gone.
+    async fn next_events(
+        &self,
+        tags: &mut broadcast::Receiver<UserTag>,
+        clock: &mut MinuteClock,
+        watermark: &Watermark,
+        system: &dyn System,
+    ) -> Option<Vec<Event>> {
+        let filter = self.filter();
+        loop {
+            let mut received = match tags.recv().await {
+                Ok(tag) => vec![tag],
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Aggregates stream skipped {} tags", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            // Tags which have arrived in the meantime are handled at once,
+            // so that a burst of them results in a single row per minute.
+            while let Ok(tag) = tags.try_recv() {
+                received.push(tag);
+            }
+
+            // The watermark has already seen the tags, as they are published
+            // once registered.
+            let closed = clock.advance(watermark.current());
+            let touched = received
+                .iter()
+                .filter(|tag| self.provisional.unwrap_or_default() && filter.matches(tag))
+                .map(|tag| UtcMinute::from(tag.time))
+                .collect::<BTreeSet<_>>();
+
+            let mut events = Vec::new();
+            if let Some(closed) = closed {
+                events.push(self.event("closed", closed, system).await);
+            }
+            for minute in touched.into_iter().filter(|minute| clock.is_open(*minute)) {
+                events.push(
+                    self.event("provisional", (minute, minute.next()), system)
+                        .await,
+                );
+            }
+            if !events.is_empty() {
+                return Some(events);
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/aggregates/stream",
+    params(AggregatesStreamParams),
+    responses(
+        (status = 200, content_type = "text/event-stream",
+            description = "Server-sent events with a `UseCase3Response` each: `closed` ones with the rows \
+                of minutes closed by the watermark, and `provisional` ones with the row of an open minute"
+        ),
+        (status = 400, description = "Filter on a dimension which is not aggregated"),
+        (status = 501, description = "The watermark is disabled"),
+    )
+)]
+async fn aggregates_stream(
+    State(system): State<SharedSystem>,
+    State(feed): State<Arc<live::Feed>>,
+    State(watermark): State<Option<Arc<Watermark>>>,
+    Query(params): Query<AggregatesStreamParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let watermark = watermark.ok_or((
+        StatusCode::NOT_IMPLEMENTED,
+        "the watermark is disabled".to_owned(),
+    ))?;
+    system
+        .dimensions()
+        .check(&params.filter())
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    log::info!("Streaming aggregates");
+
+    // Subscribed first, so that no tag closing a minute after the clock is
+    // started is missed.
+    let tags = feed.subscribe();
+    let clock = MinuteClock::new(watermark.current());
+    let state = (tags, clock, watermark, system, params);
+    let events = stream::unfold(
+        state,
+        |(mut tags, mut clock, watermark, system, params)| async move {
+            let events = params
+                .next_events(&mut tags, &mut clock, &watermark, system.as_ref())
+                .await?;
+            Some((events, (tags, clock, watermark, system, params)))
+        },
+    )
+    .flat_map(stream::iter)
+    .map(Ok);
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+struct UserTagsStreamParams {
+    /// Number of messages waiting to be sent to a slow client,
+    /// within `[1, 4096]`, 256 by default.
+    buffer: Option<usize>,
+    /// What happens to tags once `buffer` is full, `drop` by default.
+    #[param(inline)]
+    on_overflow: Option<Overflow>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/user_tags/stream",
+    params(UserTagsStreamParams),
+    responses(
+        (status = 101, description = "WebSocket of JSON messages: `ClientMessage`s subscribe to \
+            cookies, and `ServerMessage`s carry their tags as they are registered"),
+        (status = 400, description = "Invalid parameters, or not a WebSocket handshake"),
+    )
+)]
+async fn user_tags_stream(
+    State(feed): State<Arc<live::Feed>>,
+    Query(params): Query<UserTagsStreamParams>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, String)> {
+    let buffer = params.buffer.unwrap_or(subscriptions::DEFAULT_BUFFER);
+    if !subscriptions::BUFFERS.contains(&buffer) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "'buffer' out of accepted bounds '[{}, {}]'",
+                subscriptions::BUFFERS.start(),
+                subscriptions::BUFFERS.end()
+            ),
+        ));
+    }
+    log::info!("Subscribing to user tags");
+
+    let tags = feed.subscribe();
+    let overflow = params.on_overflow.unwrap_or_default();
+    Ok(upgrade.on_upgrade(move |socket| subscriptions::serve(socket, tags, buffer, overflow)))
+}
+
+///////// API v2: beyond the spec, free to evolve.
+
+/// Error of a `/v2` endpoint, in the format of RFC 7807.
+#[derive(Debug, Serialize, ToSchema)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    status: u16,
+    detail: String,
+}
+
+impl Problem {
+    fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or_default().to_owned(),
+            status: status.as_u16(),
+            detail: detail.into(),
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::from_u16(self.status).unwrap(),
+            [(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)],
+            serde_json::to_vec(&self).expect("Serializing a problem failed"),
+        )
+            .into_response()
+    }
+}
+
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Turns errors of `/v2` endpoints which are not problems yet, e.g.
+/// rejections of extractors or of admission control, into problems.
+async fn problems<B>(request: Request<B>, next: Next<B>) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    let is_problem = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == PROBLEM_CONTENT_TYPE);
+    if !(status.is_client_error() || status.is_server_error()) || is_problem {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let detail = Bytes::from_request(Request::new(body), &())
+        .await
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+        .unwrap_or_default();
+    let mut response = Problem::new(status, detail).into_response();
+    if let Some(retry_after) = parts.headers.get(header::RETRY_AFTER) {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.clone());
+    }
+    response
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+struct UserProfileV2Params {
+    /// As in `/user_profiles`.
+    #[param(value_type = String, example = "2022-03-22T12:15:00.000_2022-03-22T12:16:00.000")]
+    time_range: TimeRangeParam,
+    /// Time zone of `time_range`, UTC by default.
+    #[param(value_type = Option<String>)]
+    tz: Option<Tz>,
+    /// Limit on the number of tags of each kind in a page, 200 by default.
+    limit: Option<i32>,
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct UserProfileV2Response {
+    cookie: String,
+    views: Vec<UserTag>,
+    buys: Vec<UserTag>,
+    meta: UserProfileMeta,
+    /// Cursor of the next page, if there are more tags.
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct UserProfileMeta {
+    views: TagsMeta,
+    buys: TagsMeta,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct TagsMeta {
+    /// Number of tags of the kind in the time range, on all pages.
+    /// Only given on the first page.
+    total: Option<usize>,
+    /// Whether there are more tags of the kind on the next pages.
+    truncated: bool,
+}
+
+/// Pages of views and buys of the cookie, starting where `cursor` tells
+/// (from the newest without one), and the cursor of the next pages.
+async fn profile_page(
+    system: &dyn System,
+    cookie: &str,
+    time_range: TimeRange,
+    cursor: Option<ProfileCursor>,
+    limit: usize,
+) -> (TagsPage, TagsPage, Option<ProfileCursor>) {
+    let page = |action, position: Option<Option<TagPosition>>| async move {
+        match position {
+            None => {
+                system
+                    .tags_page(cookie, action, time_range, None, limit)
+                    .await
+            }
+            Some(Some(after)) => {
+                system
+                    .tags_page(cookie, action, time_range, Some(after), limit)
+                    .await
+            }
+            // All tags of the kind have been listed.
+            Some(None) => TagsPage::default(),
+        }
+    };
+    let (views, buys) = futures::join!(
+        page(Action::View, cursor.map(|cursor| cursor.views)),
+        page(Action::Buy, cursor.map(|cursor| cursor.buys)),
+    );
+    let next = (views.next.is_some() || buys.next.is_some()).then_some(ProfileCursor {
+        views: views.next,
+        buys: buys.next,
+    });
+    (views, buys, next)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/user_profiles/{cookie}",
+    params(("cookie" = String, Path, description = "Cookie of the user"), UserProfileV2Params),
+    responses(
+        (status = 200, body = UserProfileV2Response),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn user_profile_v2(
+    State(system): State<SharedSystem>,
+    State(precision): State<Precision>,
+    Path(cookie): Path<String>,
+    Query(params): Query<UserProfileV2Params>,
+) -> Result<Json<UserProfileV2Response>, Problem> {
+    let limit = params.limit.unwrap_or(200);
+    if !(1..=200).contains(&limit) {
+        return Err(Problem::new(
+            StatusCode::BAD_REQUEST,
+            "'limit' out of accepted bounds '[1, 200]'",
+        ));
+    }
+    let cursor = params
+        .cursor
+        .map(|cursor| {
+            ProfileCursor::decode(&cursor)
+                .ok_or_else(|| Problem::new(StatusCode::BAD_REQUEST, "invalid cursor"))
+        })
+        .transpose()?;
+    let time_range = params
+        .time_range
+        .in_zone(params.tz.unwrap_or(Tz::UTC))
+        .and_then(|time_range| time_range.in_millis(precision))
+        .map_err(|err| Problem::new(StatusCode::BAD_REQUEST, err))?;
+
+    let (views, buys, next) =
+        profile_page(system.as_ref(), &cookie, time_range, cursor, limit as usize).await;
+    let meta = |page: &TagsPage| TagsMeta {
+        total: page.total,
+        truncated: page.next.is_some(),
+    };
+
+    Ok(Json(UserProfileV2Response {
+        meta: UserProfileMeta {
+            views: meta(&views),
+            buys: meta(&buys),
+        },
+        cookie,
+        views: views.tags,
+        buys: buys.tags,
+        next_cursor: next.map(|cursor| cursor.encode()),
+    }))
+}
+
+/// Like [`UseCase3Response`], with aggregates as numbers.
+#[derive(Debug, Serialize, ToSchema)]
+struct AggregatesV2Response {
+    columns: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v2/aggregates",
+    params(UseCase3Params),
+    responses(
+        (status = 200, body = AggregatesV2Response),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn aggregates_v2(
+    State(system): State<SharedSystem>,
+    State(precision): State<Precision>,
+    Query(mut params): Query<UseCase3Params>,
+) -> Result<Json<AggregatesV2Response>, Problem> {
+    params.time_range = params
+        .time_range
+        .in_minutes(precision)
+        .map_err(|err| Problem::new(StatusCode::BAD_REQUEST, err))?;
+    params
+        .check_filter(&*system)
+        .await
+        .map_err(|err| Problem::new(StatusCode::BAD_REQUEST, err))?;
+    let buckets = params.select_buckets(&*system).await;
+    let Table { columns, rows } = aggregates_table(params, buckets);
+    Ok(Json(AggregatesV2Response {
+        columns: columns.into_iter().map(|column| column.name).collect(),
+        rows,
+    }))
 }
 
 #[cfg(test)]
@@ -388,13 +1439,19 @@ mod tests {
     use tokio::sync::oneshot;
     use tracing::instrument::WithSubscriber;
 
-    use crate::mock::{self, tests::build_system_and_register_tags};
+    use crate::aggregates::Dimensions;
+    use crate::mock::{
+        self,
+        tests::{build_system_and_register_tags, default_tag, moment_middle},
+    };
 
     use super::*;
 
+    use chrono::{DateTime, NaiveDateTime};
+
     #[tokio::test]
     async fn simplest_echo() {
-        let router = build_router(mock::System::new());
+        let router = build_router(mock::System::new(), Config::default());
         tokio::spawn(
             axum::Server::bind(&SocketAddr::from(([127, 0, 0, 4], 9042)))
                 .serve(router.into_make_service()),
@@ -421,7 +1478,7 @@ mod tests {
     async fn test_use_case_1() {
         init_logger();
         let (tx, rx) = oneshot::channel::<()>();
-        let router = build_router(mock::System::new());
+        let router = build_router(mock::System::new(), Config::default());
         let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 5], 9042)))
             .serve(router.into_make_service())
             .with_graceful_shutdown(async move {
@@ -466,7 +1523,7 @@ mod tests {
         init_logger();
         let (tx, rx) = oneshot::channel::<()>();
         let (system, test_minutes) = build_system_and_register_tags().await;
-        let router = build_router(system);
+        let router = build_router(system, Config::default());
         let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 6], 9042)))
             .serve(router.into_make_service())
             .with_graceful_shutdown(async move {
@@ -480,10 +1537,13 @@ mod tests {
                 .post("http://127.0.0.6:9042/user_profiles/cookie")
                 .query(&UseCase2Params {
                     limit: Some(1),
+                    cursor: None,
                     time_range: TimeRange {
                         from: test_minutes.minute_middle.inner(),
                         to: test_minutes.minute_after.inner(),
-                    },
+                    }
+                    .into(),
+                    tz: None,
                 })
                 .send()
                 .await
@@ -496,6 +1556,785 @@ mod tests {
         let _ = futures::future::join(server, request_fut).await;
     }
 
+    #[tokio::test]
+    async fn clear_requires_admin_token() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let router = build_router(
+            mock::System::new(),
+            Config {
+                admin_token: Some("secret".to_owned()),
+                ..Default::default()
+            },
+        );
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 7], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let clear = |token: Option<&str>| {
+                let request = client
+                    .post("http://127.0.0.7:9042/clear")
+                    .query(&[("scope", "profiles")]);
+                match token {
+                    Some(token) => request.bearer_auth(token),
+                    None => request,
+                }
+                .send()
+            };
+            let missing = clear(None).await.unwrap().status();
+            let invalid = clear(Some("guess")).await.unwrap().status();
+            let valid = clear(Some("secret")).await.unwrap().status();
+            tx.send(()).unwrap();
+
+            assert_eq!(missing, StatusCode::UNAUTHORIZED);
+            assert_eq!(invalid, StatusCode::FORBIDDEN);
+            assert_eq!(valid, StatusCode::NO_CONTENT);
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    #[tokio::test]
+    async fn use_case_3_negotiates_csv() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let (system, test_minutes) = build_system_and_register_tags().await;
+        let router = build_router(system, Config::default());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 3], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let time_range = TimeRange {
+                from: test_minutes.minute_earlier.inner(),
+                to: test_minutes.minute_after.inner(),
+            }
+            .to_string();
+            let aggregates = |format: Option<&str>| {
+                let mut query = vec![
+                    ("time_range", time_range.as_str()),
+                    ("action", "BUY"),
+                    ("aggregates", "COUNT"),
+                    ("aggregates", "SUM_PRICE"),
+                ];
+                query.extend(format.map(|format| ("format", format)));
+                client
+                    .post("http://127.0.0.3:9042/aggregates")
+                    .header(header::ACCEPT, "text/csv")
+                    .query(&query)
+                    .send()
+            };
+            let csv = aggregates(None).await.unwrap();
+            let ndjson = aggregates(Some("ndjson")).await.unwrap();
+            tx.send(()).unwrap();
+
+            assert_eq!(csv.headers()[header::CONTENT_TYPE], "text/csv");
+            let csv = csv.text().await.unwrap();
+            assert!(csv.starts_with("1m_bucket,action,count,sum_price\n"));
+
+            assert_eq!(
+                ndjson.headers()[header::CONTENT_TYPE],
+                "application/x-ndjson"
+            );
+            for line in ndjson.text().await.unwrap().lines() {
+                let row = serde_json::from_str::<serde_json::Value>(line).unwrap();
+                assert!(row["count"].is_i64());
+                assert!(row["sum_price"].is_i64());
+            }
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    #[tokio::test]
+    async fn aggregates_follow_time_zone() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let (system, test_minutes) = build_system_and_register_tags().await;
+        let router = build_router(system, Config::default());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 18], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let aggregates = |query: Vec<(&str, String)>| {
+                let request = client
+                    .post("http://127.0.0.18:9042/aggregates")
+                    .query(&[("action", "BUY"), ("aggregates", "COUNT")])
+                    .query(&query);
+                async {
+                    let response = request.send().await.unwrap();
+                    response.json::<UseCase3Response>().await.unwrap()
+                }
+            };
+            let (from, to) = (
+                test_minutes.minute_earlier.inner(),
+                test_minutes.minute_after.inner(),
+            );
+            let local = |time: DateTime<chrono::Utc>| {
+                time.with_timezone(&Tz::Asia__Kolkata)
+                    .naive_local()
+                    .format("%Y-%m-%dT%H:%M:%S")
+                    .to_string()
+            };
+            let utc = aggregates(vec![("time_range", TimeRange { from, to }.to_string())]).await;
+            let zoned = aggregates(vec![
+                ("time_range", format!("{}_{}", local(from), local(to))),
+                ("tz", "Asia/Kolkata".to_owned()),
+            ])
+            .await;
+            let unknown_zone = client
+                .post("http://127.0.0.18:9042/v2/aggregates")
+                .query(&[
+                    ("time_range", TimeRange { from, to }.to_string()),
+                    ("action", "BUY".to_owned()),
+                    ("tz", "Mars/Olympus_Mons".to_owned()),
+                ])
+                .send()
+                .await
+                .unwrap();
+            let empty = |time_range: String| {
+                client
+                    .post("http://127.0.0.18:9042/aggregates")
+                    .query(&[("time_range", time_range.as_str()), ("action", "BUY")])
+                    .send()
+            };
+            let inverted = empty(format!("{}_{}", local(to), local(from)))
+                .await
+                .unwrap();
+            let now = empty("now_now".to_owned()).await.unwrap();
+            tx.send(()).unwrap();
+
+            assert_eq!(utc.rows.len(), zoned.rows.len());
+            for (utc, zoned) in utc.rows.iter().zip(&zoned.rows) {
+                let bucket = NaiveDateTime::parse_from_str(&utc[0], "%Y-%m-%dT%H:%M:%S").unwrap();
+                assert_eq!(zoned[0], local(bucket.and_utc()));
+                assert_eq!(zoned[1..], utc[1..]);
+            }
+            assert_eq!(unknown_zone.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(inverted.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(now.status(), StatusCode::BAD_REQUEST);
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    #[tokio::test]
+    async fn aggregates_filter_on_configured_dimensions() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let dimensions = Dimensions::new(vec![
+            Dimension::Country,
+            Dimension::Attribute("campaign".to_owned()),
+        ])
+        .unwrap();
+        let system = mock::System::new().with_dimensions(dimensions);
+        for (country, campaign) in [("PL", "spring"), ("PL", "summer"), ("DE", "spring")] {
+            let mut tag = UserTag {
+                time: moment_middle().into(),
+                country: country.to_owned(),
+                ..default_tag()
+            };
+            tag.attributes
+                .insert("campaign".to_owned(), campaign.to_owned());
+            system.register_user_tag(tag).await;
+        }
+        let router = build_router(system, Config::default());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 19], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let minute = UtcMinute::from(moment_middle());
+            let time_range = TimeRange {
+                from: minute.inner(),
+                to: minute.next().inner(),
+            }
+            .to_string();
+            let aggregates = |path: &str, filters: &[(&str, &str)]| {
+                client
+                    .post(format!("http://127.0.0.19:9042{}", path))
+                    .query(&[
+                        ("time_range", time_range.as_str()),
+                        ("action", "BUY"),
+                        ("aggregates", "COUNT"),
+                    ])
+                    .query(filters)
+                    .send()
+            };
+            let filtered = aggregates(
+                "/aggregates",
+                &[("attributes.campaign", "spring"), ("country", "PL")],
+            )
+            .await
+            .unwrap()
+            .json::<UseCase3Response>()
+            .await
+            .unwrap();
+            let not_kept = aggregates("/aggregates", &[("origin", "CHRL")])
+                .await
+                .unwrap();
+            let unknown = aggregates("/v2/aggregates", &[("colour", "red")])
+                .await
+                .unwrap();
+            let invalid = aggregates("/aggregates", &[("attributes.Bad", "x")])
+                .await
+                .unwrap();
+            tx.send(()).unwrap();
+
+            assert_eq!(
+                filtered.columns,
+                [
+                    "1m_bucket",
+                    "action",
+                    "country",
+                    "attributes.campaign",
+                    "count"
+                ]
+            );
+            assert_eq!(filtered.rows[0][2..], ["PL", "spring", "1"]);
+            assert_eq!(not_kept.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(unknown.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    /// Every documented operation is routed, with the documented parameters.
+    /// Paths routed by [`build_router`], in the OpenAPI notation. The router
+    /// cannot list them, so they are read from its source.
+    fn router_paths() -> Vec<String> {
+        let source = include_str!("endpoints.rs");
+        let source = &source[source.find("pub fn build_router").unwrap()..];
+        let source = &source[..source.find(".with_state(").unwrap()];
+        let literal = |call: &str| {
+            let rest = call.trim_start();
+            let rest = rest.strip_prefix('"').unwrap();
+            rest[..rest.find('"').unwrap()].to_owned()
+        };
+
+        let (root, nested) = source.split_once(".nest(").unwrap();
+        let prefix = literal(nested);
+        let routes = |source: &str, prefix: &str| {
+            source
+                .split(".route(")
+                .skip(1)
+                .map(|call| {
+                    let path = literal(call)
+                        .split('/')
+                        .map(|segment| match segment.strip_prefix(':') {
+                            Some(parameter) => format!("{{{}}}", parameter),
+                            None => segment.to_owned(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    format!("{}{}", prefix, path)
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut paths = routes(root, "");
+        paths.extend(routes(nested, &prefix));
+        paths
+    }
+
+    #[tokio::test]
+    async fn openapi_document_matches_router() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let router = build_router(mock::System::new(), Config::default());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 2], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let document = client
+                .get("http://127.0.0.2:9042/openapi.json")
+                .send()
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap();
+
+            let mut operations = Vec::new();
+            for (path, item) in document["paths"].as_object().unwrap() {
+                for (method, operation) in item.as_object().unwrap() {
+                    let mut query = Vec::new();
+                    let mut url = format!("http://127.0.0.2:9042{}", path);
+                    for parameter in operation["parameters"].as_array().into_iter().flatten() {
+                        let name = parameter["name"].as_str().unwrap();
+                        let example = match &parameter["example"] {
+                            serde_json::Value::String(example) => example.clone(),
+                            _ => name.to_owned(),
+                        };
+                        match parameter["in"].as_str().unwrap() {
+                            "path" => url = url.replace(&format!("{{{}}}", name), &example),
+                            "query" if parameter["required"] == true => {
+                                assert!(parameter["example"].is_string(), "{} {}", path, name);
+                                query.push((name.to_owned(), example));
+                            }
+                            _ => {}
+                        }
+                    }
+                    let method = method.to_uppercase().parse().unwrap();
+                    let status = client
+                        .request(method, url)
+                        .query(&query)
+                        .send()
+                        .await
+                        .unwrap()
+                        .status();
+                    operations.push((path.clone(), status));
+                }
+            }
+            let undocumented = [
+                (reqwest::Method::GET, "/echo"),
+                (reqwest::Method::POST, "/clear"),
+                (reqwest::Method::POST, "/admin/snapshot"),
+                (reqwest::Method::GET, "/admin/memory"),
+                (reqwest::Method::GET, "/admin/watermark"),
+                (reqwest::Method::GET, "/admin/admission"),
+            ];
+            for (method, path) in &undocumented {
+                let status = client
+                    .request(method.clone(), format!("http://127.0.0.2:9042{}", path))
+                    .send()
+                    .await
+                    .unwrap()
+                    .status();
+                assert_ne!(status, StatusCode::NOT_FOUND, "{}", path);
+                assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            }
+            tx.send(()).unwrap();
+
+            for (path, status) in &operations {
+                assert_ne!(*status, StatusCode::NOT_FOUND, "{}", path);
+                assert_ne!(*status, StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            }
+            let mut paths = operations
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>();
+            paths.sort();
+
+            // Every route is either documented or known to be left out.
+            let mut routed = router_paths();
+            routed.sort();
+            let mut expected = paths
+                .iter()
+                .cloned()
+                .chain(undocumented.iter().map(|(_, path)| path.to_string()))
+                .collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(routed, expected);
+
+            assert_eq!(
+                paths,
+                [
+                    "/aggregates",
+                    "/aggregates/stream",
+                    "/health",
+                    "/openapi.json",
+                    "/user_profiles/{cookie}",
+                    "/user_tags",
+                    "/user_tags/stream",
+                    "/v2/aggregates",
+                    "/v2/user_profiles/{cookie}",
+                    "/v2/user_tags"
+                ]
+            );
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    #[tokio::test]
+    async fn v2_pages_profiles_and_reports_problems() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let (system, test_minutes) = build_system_and_register_tags().await;
+        let router = build_router(system, Config::default());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 14], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let time_range = TimeRange {
+                from: test_minutes.minute_earlier.inner(),
+                to: test_minutes.minute_after.inner(),
+            }
+            .to_string();
+            let profile = |limit: &str, cursor: Option<&str>| {
+                let mut query = vec![("time_range", time_range.as_str()), ("limit", limit)];
+                query.extend(cursor.map(|cursor| ("cursor", cursor)));
+                client
+                    .post("http://127.0.0.14:9042/v2/user_profiles/cookie")
+                    .query(&query)
+                    .send()
+            };
+            let json = |response: reqwest::Response| async move {
+                let content_type = response.headers()[header::CONTENT_TYPE].clone();
+                (
+                    content_type,
+                    response.json::<serde_json::Value>().await.unwrap(),
+                )
+            };
+
+            let (_, first) = json(profile("1", None).await.unwrap()).await;
+            let cursor = first["next_cursor"].as_str().unwrap();
+            let (_, second) = json(profile("1", Some(cursor)).await.unwrap()).await;
+            let (problem_type, problem) = json(profile("1000", None).await.unwrap()).await;
+            let (_, aggregates) = json(
+                client
+                    .post("http://127.0.0.14:9042/v2/aggregates")
+                    .query(&[
+                        ("time_range", time_range.as_str()),
+                        ("action", "BUY"),
+                        ("aggregates", "SUM_PRICE"),
+                    ])
+                    .send()
+                    .await
+                    .unwrap(),
+            )
+            .await;
+            let (rejection_type, rejection) = json(
+                client
+                    .post("http://127.0.0.14:9042/v2/aggregates")
+                    .send()
+                    .await
+                    .unwrap(),
+            )
+            .await;
+            let (empty_type, empty) = json(
+                client
+                    .post("http://127.0.0.14:9042/v2/aggregates")
+                    .query(&[("time_range", "now_now"), ("action", "BUY")])
+                    .send()
+                    .await
+                    .unwrap(),
+            )
+            .await;
+            tx.send(()).unwrap();
+
+            assert_eq!(first["buys"].as_array().unwrap().len(), 1);
+            assert_eq!(first["meta"]["buys"]["total"], 2);
+            assert_eq!(first["meta"]["buys"]["truncated"], true);
+            assert_eq!(first["meta"]["views"]["truncated"], false);
+            assert_eq!(second["buys"].as_array().unwrap().len(), 1);
+            assert_ne!(second["buys"][0], first["buys"][0]);
+            assert!(second["buys"][0]["time"].as_str() < first["buys"][0]["time"].as_str());
+            assert_eq!(second["meta"]["buys"]["truncated"], false);
+            assert!(second["meta"]["buys"]["total"].is_null());
+            assert!(second["next_cursor"].is_null());
+
+            assert_eq!(problem_type, PROBLEM_CONTENT_TYPE);
+            assert_eq!(problem["status"], 400);
+            assert!(problem["detail"].as_str().unwrap().contains("limit"));
+
+            assert_eq!(aggregates["columns"][2], "sum_price");
+            let prices = aggregates["rows"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|row| row[2].as_i64().unwrap())
+                .sum::<i64>();
+            assert_eq!(prices, 50);
+
+            assert_eq!(rejection_type, PROBLEM_CONTENT_TYPE);
+            assert_eq!(rejection["status"], 400);
+            assert_eq!(rejection["title"], "Bad Request");
+
+            assert_eq!(empty_type, PROBLEM_CONTENT_TYPE);
+            assert_eq!(empty["status"], 400);
+            assert!(empty["detail"]
+                .as_str()
+                .unwrap()
+                .contains("spans no minute"));
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    #[tokio::test]
+    async fn user_profiles_page_with_cursor() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let (system, test_minutes) = build_system_and_register_tags().await;
+        let router = build_router(system, Config::default());
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 15], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let time_range = TimeRange {
+                from: test_minutes.minute_earlier.inner(),
+                to: test_minutes.minute_after.inner(),
+            }
+            .to_string();
+            let profile = |cursor: Option<&str>| {
+                let mut query = vec![("time_range", time_range.as_str()), ("limit", "1")];
+                query.extend(cursor.map(|cursor| ("cursor", cursor)));
+                client
+                    .post("http://127.0.0.15:9042/user_profiles/cookie")
+                    .query(&query)
+                    .send()
+            };
+
+            let unpaged = profile(None).await.unwrap();
+            let first = profile(Some("")).await.unwrap();
+            let cursor = first.headers()[NEXT_CURSOR].to_str().unwrap().to_owned();
+            let second = profile(Some(&cursor)).await.unwrap();
+            let invalid = profile(Some("zz")).await.unwrap();
+            tx.send(()).unwrap();
+
+            assert!(!unpaged.headers().contains_key(NEXT_CURSOR));
+            let unpaged = unpaged.json::<UserProfile>().await.unwrap();
+            let first = first.json::<UserProfile>().await.unwrap();
+            assert_eq!(first.buys, unpaged.buys);
+            assert!(!second.headers().contains_key(NEXT_CURSOR));
+            let second = second.json::<UserProfile>().await.unwrap();
+            assert_eq!(second.buys.len(), 1);
+            assert!(second.buys[0].time < first.buys[0].time);
+            assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    /// Reads the next server-sent event of the response: its name and data.
+    async fn next_event(
+        response: &mut reqwest::Response,
+        buffer: &mut String,
+    ) -> (String, serde_json::Value) {
+        while !buffer.contains("\n\n") {
+            let chunk = response.chunk().await.unwrap().unwrap();
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let end = buffer.find("\n\n").unwrap();
+        let event = buffer[..end].to_owned();
+        buffer.drain(..end + 2);
+        let field = |name: &str| {
+            event
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .unwrap()
+                .to_owned()
+        };
+        (
+            field("event:"),
+            serde_json::from_str(&field("data:")).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn aggregates_stream_pushes_closed_and_provisional_rows() {
+        init_logger();
+        let (tx, rx) = oneshot::channel::<()>();
+        let config = Config {
+            watermark: Some(watermark::Config::default()),
+            ..Config::default()
+        };
+        let router = build_router(mock::System::new(), config);
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 16], 9042)))
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = rx.await;
+            })
+            .with_current_subscriber();
+
+        let request_fut = async {
+            let client = reqwest::Client::new();
+            let minute = UtcMinute::from(moment_middle());
+            let register = |time: DateTime<chrono::Utc>| {
+                client
+                    .post("http://127.0.0.16:9042/user_tags")
+                    .json(&UserTag {
+                        time: time.into(),
+                        ..default_tag()
+                    })
+                    .send()
+            };
+            let mut stream = client
+                .get("http://127.0.0.16:9042/aggregates/stream")
+                .query(&[
+                    ("action", "BUY"),
+                    ("aggregates", "COUNT"),
+                    ("provisional", "true"),
+                ])
+                .send()
+                .await
+                .unwrap();
+            let mut buffer = String::new();
+
+            register(minute.inner() + chrono::Duration::seconds(10))
+                .await
+                .unwrap();
+            let first = next_event(&mut stream, &mut buffer).await;
+            register(minute.inner() + chrono::Duration::seconds(20))
+                .await
+                .unwrap();
+            let second = next_event(&mut stream, &mut buffer).await;
+            // Closes the first minute, given the 5s out-of-orderness.
+            register(minute.next().inner() + chrono::Duration::seconds(5))
+                .await
+                .unwrap();
+            let closed = next_event(&mut stream, &mut buffer).await;
+            let third = next_event(&mut stream, &mut buffer).await;
+            drop(stream);
+            tx.send(()).unwrap();
+
+            let bucket = |minute: UtcMinute| {
+                minute
+                    .inner()
+                    .naive_utc()
+                    .format("%Y-%m-%dT%H:%M:%S")
+                    .to_string()
+            };
+            assert_eq!(first.0, "provisional");
+            assert_eq!(
+                first.1["columns"],
+                serde_json::json!(["1m_bucket", "action", "count"])
+            );
+            assert_eq!(
+                first.1["rows"],
+                serde_json::json!([[bucket(minute), "BUY", "1"]])
+            );
+            assert_eq!(second.0, "provisional");
+            assert_eq!(
+                second.1["rows"],
+                serde_json::json!([[bucket(minute), "BUY", "2"]])
+            );
+            assert_eq!(closed.0, "closed");
+            assert_eq!(
+                closed.1["rows"],
+                serde_json::json!([[bucket(minute), "BUY", "2"]])
+            );
+            assert_eq!(third.0, "provisional");
+            assert_eq!(
+                third.1["rows"],
+                serde_json::json!([[bucket(minute.next()), "BUY", "1"]])
+            );
+        };
+
+        let _ = futures::future::join(server, request_fut).await;
+    }
+
+    #[tokio::test]
+    async fn user_tags_stream_sends_tags_of_subscribed_cookies() {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::{self, Message};
+
+        init_logger();
+        let router = build_router(mock::System::new(), Config::default());
+        tokio::spawn(
+            axum::Server::bind(&SocketAddr::from(([127, 0, 0, 17], 9042)))
+                .serve(router.into_make_service()),
+        );
+
+        let client = reqwest::Client::new();
+        let register = |cookie: &str, seconds| {
+            client
+                .post("http://127.0.0.17:9042/user_tags")
+                .json(&UserTag {
+                    time: (moment_middle() + chrono::Duration::seconds(seconds)).into(),
+                    cookie: cookie.to_owned(),
+                    ..default_tag()
+                })
+                .send()
+        };
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async("ws://127.0.0.17:9042/user_tags/stream?buffer=8")
+                .await
+                .unwrap();
+        type Socket = tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >;
+        async fn request(socket: &mut Socket, message: serde_json::Value) {
+            socket
+                .send(Message::Text(message.to_string()))
+                .await
+                .unwrap();
+        }
+        async fn next_message(socket: &mut Socket) -> serde_json::Value {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        request(
+            &mut socket,
+            serde_json::json!({"type": "subscribe", "cookies": ["alice"]}),
+        )
+        .await;
+        let subscribed = next_message(&mut socket).await;
+
+        register("alice", 1).await.unwrap();
+        register("bob", 2).await.unwrap();
+        register("alice", 3).await.unwrap();
+        let first = next_message(&mut socket).await;
+        let second = next_message(&mut socket).await;
+
+        assert_eq!(
+            subscribed,
+            serde_json::json!({"type": "subscribed", "cookies": ["alice"]})
+        );
+        assert_eq!(first["type"], "tag");
+        assert_eq!(first["tag"]["cookie"], "alice");
+        assert_eq!(second["tag"]["cookie"], "alice");
+        assert!(first["tag"]["time"].as_str() < second["tag"]["time"].as_str());
+
+        request(
+            &mut socket,
+            serde_json::json!({"type": "unsubscribe", "cookies": ["alice"]}),
+        )
+        .await;
+        assert_eq!(
+            next_message(&mut socket).await["cookies"],
+            serde_json::json!([])
+        );
+        request(&mut socket, serde_json::json!({"type": "subscribe"})).await;
+        assert_eq!(next_message(&mut socket).await["type"], "error");
+
+        let rejected =
+            tokio_tungstenite::connect_async("ws://127.0.0.17:9042/user_tags/stream?buffer=0")
+                .await
+                .unwrap_err();
+        let tungstenite::Error::Http(response) = rejected else {
+            panic!("unexpected error {:?}", rejected);
+        };
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
     // #[tokio::test]
     // async fn test_use_case_3() {
     //     init_logger();
@@ -0,0 +1,545 @@
+//! Ingestion through a partitioned event log.
+//!
+//! Tags are published to a log modelled on a Kafka topic: records are keyed by
+//! cookie and spread over partitions with Kafka's default partitioner, so all
+//! tags of a cookie land in one partition, in order. A consumer worker per
+//! partition applies them to the system and commits the offset of the consumer
+//! group afterwards, so delivery is at-least-once. Publishing to a partition
+//! lagging too far behind is rejected. [`EmbeddedLog`] implements
+//! the log in process on top of [`SegmentLog`]s; a broker-backed [`EventLog`]
+//! can be used in its place.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+use crate::codec::{self, Decoder, Encoder};
+use crate::dedup::IdempotencyKey;
+use crate::ingest_queue;
+use crate::segment_log::SegmentLog;
+use crate::types::{System, UserTag};
+use crate::utils;
+
+/// Consumer group of the workers applying tags to the system.
+pub const CONSUMER_GROUP: &str = "allezon";
+
+const OFFSETS_MAGIC: &[u8; 4] = b"ALZO";
+const OFFSETS_VERSION: u8 = 1;
+/// Records applied by a worker between commits.
+const MAX_BATCH: usize = 256;
+/// How long a fetch waits for records to appear.
+const FETCH_WAIT: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Partition of a record key, as chosen by Kafka's default partitioner.
+pub fn partition_for(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fffffff) as usize % partitions
+}
+
+/// The variant of MurmurHash2 used by Kafka clients.
+fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let rest = chunks.remainder();
+    if rest.len() >= 3 {
+        h ^= (rest[2] as u32) << 16;
+    }
+    if rest.len() >= 2 {
+        h ^= (rest[1] as u32) << 8;
+    }
+    if !rest.is_empty() {
+        h ^= rest[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+/// Partitioned log of opaque records with committed offsets of consumer groups.
+#[async_trait]
+pub trait EventLog: Send + Sync {
+    fn partitions(&self) -> usize;
+
+    /// Durably appends the record. Returns its offset within the partition.
+    async fn produce(&self, partition: usize, record: Vec<u8>) -> io::Result<u64>;
+
+    /// Returns at most `max_records` records with offsets not lower than
+    /// `from`. If there are none, waits a while for them to appear.
+    async fn fetch(
+        &self,
+        partition: usize,
+        from: u64,
+        max_records: usize,
+    ) -> io::Result<Vec<(u64, Vec<u8>)>>;
+
+    /// Offset of the next record to be produced to the partition.
+    async fn end_offset(&self, partition: usize) -> io::Result<u64>;
+
+    /// Offset of the first record the group has not consumed yet.
+    async fn committed(&self, group: &str, partition: usize) -> io::Result<u64>;
+
+    async fn commit(&self, group: &str, partition: usize, offset: u64) -> io::Result<()>;
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub dir: PathBuf,
+    pub partitions: usize,
+    pub max_segment_len: u64,
+    /// Number of records of a partition waiting to be applied beyond which
+    /// publishing to it fails with [`io::ErrorKind::WouldBlock`].
+    pub max_lag: u64,
+}
+
+impl Config {
+    pub fn new(dir: PathBuf, partitions: usize) -> Self {
+        Self {
+            dir,
+            partitions,
+            max_segment_len: 64 << 20,
+            max_lag: 1 << 16,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Partition {
+    log: Mutex<SegmentLog>,
+    appended: Notify,
+}
+
+/// [`EventLog`] stored in a local directory, with a subdirectory of segments
+/// per partition and a file of committed offsets per consumer group.
+/// Segments are removed once the last group to commit has consumed them,
+/// so it is meant for a single consumer group.
+#[derive(Debug)]
+pub struct EmbeddedLog {
+    dir: PathBuf,
+    partitions: Vec<Arc<Partition>>,
+    committed: Mutex<HashMap<String, Vec<u64>>>,
+    /// Serializes commits, so that offsets files are written in order.
+    commits: tokio::sync::Mutex<()>,
+}
+
+impl EmbeddedLog {
+    pub fn open(config: &Config) -> io::Result<Self> {
+        assert!(config.partitions > 0);
+        let partitions = (0..config.partitions)
+            .map(|partition| {
+                let log = SegmentLog::open(
+                    config.dir.join(format!("partition-{}", partition)),
+                    config.max_segment_len,
+                )?;
+                Ok(Arc::new(Partition {
+                    log: Mutex::new(log),
+                    appended: Notify::new(),
+                }))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            dir: config.dir.clone(),
+            partitions,
+            committed: Default::default(),
+            commits: Default::default(),
+        })
+    }
+
+    fn offsets_path(&self, group: &str) -> PathBuf {
+        self.dir.join(format!("offsets-{}", group))
+    }
+
+    fn read_offsets(&self, group: &str) -> io::Result<Vec<u64>> {
+        let mut offsets = vec![0; self.partitions.len()];
+        match std::fs::read(self.offsets_path(group)) {
+            Ok(contents) => {
+                let (_version, body) = codec::unseal(OFFSETS_MAGIC, &contents)?;
+                let mut decoder = Decoder::new(body);
+                for _ in 0..decoder.u64()? {
+                    let partition = decoder.u64()? as usize;
+                    let offset = decoder.u64()?;
+                    // Partitions beyond the configured ones are not consumed.
+                    if let Some(committed) = offsets.get_mut(partition) {
+                        *committed = offset;
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(offsets)
+    }
+
+    fn committed_offsets(&self, group: &str) -> io::Result<Vec<u64>> {
+        let mut committed = self.committed.lock().unwrap();
+        if let Some(offsets) = committed.get(group) {
+            return Ok(offsets.clone());
+        }
+        let offsets = self.read_offsets(group)?;
+        committed.insert(group.to_owned(), offsets.clone());
+        Ok(offsets)
+    }
+}
+
+#[async_trait]
+impl EventLog for EmbeddedLog {
+    fn partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    async fn produce(&self, partition: usize, record: Vec<u8>) -> io::Result<u64> {
+        let partition = Arc::clone(&self.partitions[partition]);
+        tokio::task::spawn_blocking(move || {
+            let mut log = partition.log.lock().unwrap();
+            let offset = log.append(&record)?;
+            log.sync()?;
+            drop(log);
+            partition.appended.notify_waiters();
+            Ok(offset)
+        })
+        .await
+        .expect("Event log append panicked")
+    }
+
+    async fn fetch(
+        &self,
+        partition: usize,
+        from: u64,
+        max_records: usize,
+    ) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let partition = &self.partitions[partition];
+        let read = || {
+            let partition = Arc::clone(partition);
+            tokio::task::spawn_blocking(move || {
+                // Decoded outside of the lock, so that appends are not blocked.
+                let segments = {
+                    let log = partition.log.lock().unwrap();
+                    if log.next_offset() <= from {
+                        return Ok(Vec::new());
+                    }
+                    log.segments_from(from)
+                };
+                segments.read_range(from, max_records)
+            })
+        };
+
+        // Registered before reading, so that no append in between is missed.
+        let appended = partition.appended.notified();
+        tokio::pin!(appended);
+        appended.as_mut().enable();
+        let records = read().await.expect("Event log read panicked")?;
+        if !records.is_empty() {
+            return Ok(records);
+        }
+        if tokio::time::timeout(FETCH_WAIT, appended).await.is_err() {
+            return Ok(Vec::new());
+        }
+        read().await.expect("Event log read panicked")
+    }
+
+    async fn end_offset(&self, partition: usize) -> io::Result<u64> {
+        Ok(self.partitions[partition].log.lock().unwrap().next_offset())
+    }
+
+    async fn committed(&self, group: &str, partition: usize) -> io::Result<u64> {
+        Ok(self.committed_offsets(group)?[partition])
+    }
+
+    async fn commit(&self, group: &str, partition: usize, offset: u64) -> io::Result<()> {
+        let _commit = self.commits.lock().await;
+        let mut offsets = self.committed_offsets(group)?;
+        offsets[partition] = offset;
+        let mut encoder = Encoder::new();
+        encoder.put_u64(offsets.len() as u64);
+        for (partition, &offset) in offsets.iter().enumerate() {
+            encoder.put_u64(partition as u64);
+            encoder.put_u64(offset);
+        }
+        let contents = codec::seal(OFFSETS_MAGIC, OFFSETS_VERSION, &encoder.finish());
+        let path = self.offsets_path(group);
+        let log = Arc::clone(&self.partitions[partition]);
+        tokio::task::spawn_blocking(move || {
+            utils::write_atomically(&path, &contents)?;
+            log.log.lock().unwrap().remove_before(offset)
+        })
+        .await
+        .expect("Event log commit panicked")?;
+        self.committed
+            .lock()
+            .unwrap()
+            .insert(group.to_owned(), offsets);
+        Ok(())
+    }
+}
+
+/// Publishes tags to the log and applies them to the system in the background.
+pub struct Ingestion {
+    log: Arc<dyn EventLog>,
+    max_lag: u64,
+}
+
+impl Ingestion {
+    /// Starts a consumer worker for each partition of the log, beginning with
+    /// the records left unapplied by a previous run.
+    pub fn start(log: Arc<dyn EventLog>, system: Arc<dyn System>, max_lag: u64) -> Self {
+        for partition in 0..log.partitions() {
+            tokio::spawn(consume(
+                Arc::downgrade(&log),
+                partition,
+                Arc::clone(&system),
+            ));
+        }
+        Self { log, max_lag }
+    }
+
+    /// Fails with [`io::ErrorKind::WouldBlock`] if the partition of the tag
+    /// lags too far behind.
+    pub async fn publish(&self, tag: &UserTag, key: Option<IdempotencyKey>) -> io::Result<()> {
+        let partition = partition_for(tag.cookie.as_bytes(), self.log.partitions());
+        let lag = self.log.end_offset(partition).await?
+            - self.log.committed(CONSUMER_GROUP, partition).await?;
+        if lag >= self.max_lag {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("partition {} of the event log is full", partition),
+            ));
+        }
+        self.log
+            .produce(partition, ingest_queue::encode_record(tag, key))
+            .await?;
+        Ok(())
+    }
+
+    /// Waits until all tags published so far are applied.
+    pub async fn drain(&self) -> io::Result<()> {
+        let mut end_offsets = Vec::new();
+        for partition in 0..self.log.partitions() {
+            end_offsets.push(self.log.end_offset(partition).await?);
+        }
+        for (partition, end_offset) in end_offsets.into_iter().enumerate() {
+            while self.log.committed(CONSUMER_GROUP, partition).await? < end_offset {
+                tokio::time::sleep(DRAIN_INTERVAL).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of published tags which are not yet applied.
+    pub async fn lag(&self) -> io::Result<u64> {
+        let mut lag = 0;
+        for partition in 0..self.log.partitions() {
+            lag += self.log.end_offset(partition).await?
+                - self.log.committed(CONSUMER_GROUP, partition).await?;
+        }
+        Ok(lag)
+    }
+}
+
+async fn consume(log: Weak<dyn EventLog>, partition: usize, system: Arc<dyn System>) {
+    let mut next = None;
+    loop {
+        let Some(log) = log.upgrade() else {
+            return;
+        };
+        let from = match next {
+            Some(from) => from,
+            None => match log.committed(CONSUMER_GROUP, partition).await {
+                Ok(from) => from,
+                Err(err) => {
+                    error!("Failed to read offset of partition {}: {}", partition, err);
+                    drop(log);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            },
+        };
+        let records = match log.fetch(partition, from, MAX_BATCH).await {
+            Ok(records) => records,
+            Err(err) => {
+                error!("Failed to fetch from partition {}: {}", partition, err);
+                drop(log);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        let Some(&(last, _)) = records.last() else {
+            next = Some(from);
+            continue;
+        };
+        if next.is_none() && from > 0 {
+            info!("Resuming partition {} from offset {}", partition, from);
+        }
+
+        // Applied in order, as tags of a cookie must be.
+        for (offset, record) in records {
+            match ingest_queue::decode_record(&record) {
+                Ok((tag, key)) => ingest_queue::apply(Arc::clone(&system), tag, key).await,
+                Err(err) => error!(
+                    "Skipping corrupted record {} of partition {}: {}",
+                    offset, partition, err
+                ),
+            }
+        }
+        next = Some(last + 1);
+        // Records are applied again after a restart, but otherwise nothing is lost.
+        if let Err(err) = log.commit(CONSUMER_GROUP, partition, last + 1).await {
+            error!(
+                "Failed to commit offset of partition {}: {}",
+                partition, err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{
+        self,
+        tests::{default_tag, moment_middle},
+    };
+
+    use super::*;
+
+    #[test]
+    fn partitions_match_kafka_default_partitioner() {
+        // Test vectors of Kafka's `Utils.murmur2`.
+        assert_eq!(murmur2(b"21"), -973932308);
+        assert_eq!(murmur2(b"foobar"), -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string"), -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string"), -1486304829);
+        assert_eq!(
+            murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8"),
+            -58897971
+        );
+        assert_eq!(murmur2(b"abc"), 479470107);
+        assert_eq!(
+            partition_for(b"21", 8),
+            (-973932308i32 & 0x7fffffff) as usize % 8
+        );
+    }
+
+    async fn wait_until_applied(ingestion: &Ingestion) {
+        while ingestion.lag().await.unwrap() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn published_tags_are_applied_once_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::new(dir.path().to_owned(), 4);
+        let tag = |cookie: &str, i| UserTag {
+            time: (moment_middle() + chrono::Duration::milliseconds(i)).into(),
+            cookie: cookie.to_owned(),
+            ..default_tag()
+        };
+        let cookies = ["a", "b", "c", "d", "e"];
+
+        let system = Arc::new(mock::System::new());
+        let log: Arc<dyn EventLog> = Arc::new(EmbeddedLog::open(&config).unwrap());
+        let ingestion = Ingestion::start(
+            Arc::clone(&log),
+            Arc::clone(&system) as Arc<dyn System>,
+            config.max_lag,
+        );
+        for i in 0..10 {
+            for cookie in cookies {
+                ingestion.publish(&tag(cookie, i), None).await.unwrap();
+            }
+        }
+        wait_until_applied(&ingestion).await;
+        for cookie in cookies {
+            let profile = system
+                .last_tags_by_cookie(
+                    cookie,
+                    moment_middle(),
+                    moment_middle() + chrono::Duration::seconds(1),
+                    200,
+                )
+                .await;
+            assert_eq!(profile.buys.len(), 10);
+        }
+        drop(ingestion);
+        drop(log);
+        // Lets the consumers notice that the log is gone.
+        tokio::time::sleep(FETCH_WAIT * 2).await;
+
+        // Simulates a crash after publishing, but before applying.
+        {
+            let log = EmbeddedLog::open(&config).unwrap();
+            let partition = partition_for(b"a", 4);
+            log.produce(partition, ingest_queue::encode_record(&tag("a", 10), None))
+                .await
+                .unwrap();
+        }
+
+        // Only the uncommitted tag is applied after a restart.
+        let system = Arc::new(mock::System::new());
+        let log: Arc<dyn EventLog> = Arc::new(EmbeddedLog::open(&config).unwrap());
+        let ingestion =
+            Ingestion::start(log, Arc::clone(&system) as Arc<dyn System>, config.max_lag);
+        wait_until_applied(&ingestion).await;
+        let profile = system
+            .last_tags_by_cookie(
+                "a",
+                moment_middle(),
+                moment_middle() + chrono::Duration::seconds(1),
+                200,
+            )
+            .await;
+        assert_eq!(profile.buys.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fetch_returns_at_most_max_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_segment_len: 16,
+            ..Config::new(dir.path().to_owned(), 1)
+        };
+        let log = EmbeddedLog::open(&config).unwrap();
+        for i in 0..10u8 {
+            log.produce(0, vec![i; 8]).await.unwrap();
+        }
+        let records = log.fetch(0, 3, 4).await.unwrap();
+        let expected = (3..7).map(|i| (i, vec![i as u8; 8])).collect::<Vec<_>>();
+        assert_eq!(records, expected);
+        assert!(log.fetch(0, 10, 4).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn publishing_to_lagging_partition_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_lag: 0,
+            ..Config::new(dir.path().to_owned(), 1)
+        };
+        let system = Arc::new(mock::System::new());
+        let log: Arc<dyn EventLog> = Arc::new(EmbeddedLog::open(&config).unwrap());
+        let ingestion = Ingestion::start(log, system as Arc<dyn System>, config.max_lag);
+
+        let err = ingestion.publish(&default_tag(), None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(ingestion.lag().await.unwrap(), 0);
+    }
+}
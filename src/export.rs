@@ -0,0 +1,277 @@
+//! Encodings of use case 3 results for tools other than the spec's clients.
+//!
+//! The spec's JSON response stringifies every value; these formats keep
+//! aggregate columns numeric instead.
+
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema};
+use axum::http::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// The spec's response.
+    Json,
+    Csv,
+    /// A JSON object per row.
+    Ndjson,
+    /// Apache Arrow IPC stream.
+    Arrow,
+}
+
+impl Format {
+    /// The known format of the accepted media types with the highest `q`
+    /// weight; of equally weighted ones, the first listed.
+    pub fn from_accept(headers: &HeaderMap) -> Option<Self> {
+        let accept = headers.get(header::ACCEPT)?.to_str().ok()?;
+        let mut best: Option<(Self, f32)> = None;
+        for media_type in accept.split(',') {
+            let mut params = media_type.split(';');
+            let format = match params.next().unwrap_or_default().trim() {
+                "application/json" => Format::Json,
+                "text/csv" => Format::Csv,
+                "application/x-ndjson" | "application/jsonl" => Format::Ndjson,
+                "application/vnd.apache.arrow.stream" => Format::Arrow,
+                _ => continue,
+            };
+            let weight = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|weight| weight.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // A weight of 0 means "not acceptable".
+            if weight > 0.0 && best.is_none_or(|(_, best)| weight > best) {
+                best = Some((format, weight));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Arrow => "application/vnd.apache.arrow.stream",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Text,
+    Integer,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub kind: Kind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum Cell {
+    Text(String),
+    Integer(i64),
+}
+
+impl std::fmt::Display for Cell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cell::Text(text) => f.write_str(text),
+            Cell::Integer(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Rows of cells, each of the kind of its column.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Table {
+    pub columns: Vec<Column>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn to_csv(&self) -> Vec<u8> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer
+            .write_record(self.columns.iter().map(|column| &column.name))
+            .expect("Writing CSV to memory failed");
+        for row in &self.rows {
+            writer
+                .write_record(row.iter().map(Cell::to_string))
+                .expect("Writing CSV to memory failed");
+        }
+        writer.into_inner().expect("Writing CSV to memory failed")
+    }
+
+    pub fn to_ndjson(&self) -> Vec<u8> {
+        let mut lines = Vec::new();
+        for row in &self.rows {
+            let object = self
+                .columns
+                .iter()
+                .zip(row)
+                .map(|(column, cell)| {
+                    let value = match cell {
+                        Cell::Text(text) => serde_json::Value::from(text.as_str()),
+                        Cell::Integer(value) => serde_json::Value::from(*value),
+                    };
+                    (column.name.clone(), value)
+                })
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::to_writer(&mut lines, &object).expect("Writing JSON to memory failed");
+            lines.push(b'\n');
+        }
+        lines
+    }
+
+    pub fn to_arrow(&self) -> Vec<u8> {
+        let schema = Arc::new(Schema::new(
+            self.columns
+                .iter()
+                .map(|column| {
+                    let data_type = match column.kind {
+                        Kind::Text => DataType::Utf8,
+                        Kind::Integer => DataType::Int64,
+                    };
+                    Field::new(&column.name, data_type, false)
+                })
+                .collect::<Vec<_>>(),
+        ));
+        let arrays = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| -> ArrayRef {
+                let cells = self.rows.iter().map(|row| &row[i]);
+                match column.kind {
+                    Kind::Text => Arc::new(
+                        cells
+                            .map(|cell| match cell {
+                                Cell::Text(text) => text.as_str(),
+                                Cell::Integer(_) => unreachable!("integer in a text column"),
+                            })
+                            .map(Some)
+                            .collect::<StringArray>(),
+                    ),
+                    Kind::Integer => Arc::new(
+                        cells
+                            .map(|cell| match cell {
+                                Cell::Integer(value) => *value,
+                                Cell::Text(_) => unreachable!("text in an integer column"),
+                            })
+                            .map(Some)
+                            .collect::<Int64Array>(),
+                    ),
+                }
+            })
+            .collect::<Vec<_>>();
+        let batch = RecordBatch::try_new(Arc::clone(&schema), arrays)
+            .expect("Columns do not match the schema");
+
+        let mut writer =
+            StreamWriter::try_new(Vec::new(), &schema).expect("Writing Arrow to memory failed");
+        writer
+            .write(&batch)
+            .expect("Writing Arrow to memory failed");
+        writer.into_inner().expect("Writing Arrow to memory failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::Array;
+    use arrow_ipc::reader::StreamReader;
+
+    use super::*;
+
+    fn table() -> Table {
+        Table {
+            columns: vec![
+                Column {
+                    name: "1m_bucket".to_owned(),
+                    kind: Kind::Text,
+                },
+                Column {
+                    name: "count".to_owned(),
+                    kind: Kind::Integer,
+                },
+            ],
+            rows: vec![
+                vec![
+                    Cell::Text("2022-03-01T00:05:00".to_owned()),
+                    Cell::Integer(3),
+                ],
+                vec![
+                    Cell::Text("2022-03-01T00:06:00".to_owned()),
+                    Cell::Integer(4),
+                ],
+            ],
+        }
+    }
+
+    #[test]
+    fn accept_header_is_weighted() {
+        let format = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            Format::from_accept(&headers)
+        };
+        assert_eq!(format("text/csv, application/json"), Some(Format::Csv));
+        assert_eq!(
+            format("text/csv;q=0.5, application/x-ndjson;q=0.8, text/html"),
+            Some(Format::Ndjson)
+        );
+        assert_eq!(
+            format("text/csv;q=0, application/json;q=0.1"),
+            Some(Format::Json)
+        );
+        assert_eq!(format("text/csv;q=0"), None);
+        assert_eq!(format("text/html"), None);
+    }
+
+    #[test]
+    fn text_formats_keep_aggregates_numeric() {
+        assert_eq!(
+            String::from_utf8(table().to_csv()).unwrap(),
+            "1m_bucket,count\n2022-03-01T00:05:00,3\n2022-03-01T00:06:00,4\n"
+        );
+        assert_eq!(
+            String::from_utf8(table().to_ndjson()).unwrap(),
+            "{\"1m_bucket\":\"2022-03-01T00:05:00\",\"count\":3}\n\
+             {\"1m_bucket\":\"2022-03-01T00:06:00\",\"count\":4}\n"
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT,
+            "text/html, text/csv;q=0.9, */*".parse().unwrap(),
+        );
+        assert_eq!(Format::from_accept(&headers), Some(Format::Csv));
+    }
+
+    #[test]
+    fn arrow_stream_is_readable() {
+        let bytes = table().to_arrow();
+        let batches = StreamReader::try_new(bytes.as_slice(), None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.schema().field(1).data_type(), &DataType::Int64);
+        let counts = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(counts.values(), &[3, 4]);
+        assert_eq!(counts.len(), 2);
+    }
+}
@@ -0,0 +1,479 @@
+//! Durable queue of ingested tags in front of a [`System`].
+//!
+//! Tags are appended to a [`SegmentLog`] and synced to disk before the request
+//! is acknowledged, so a slow backend does not delay the response. A background
+//! consumer applies them to the backend, retrying failures, and persists the
+//! offset up to which all tags are applied. On restart, tags past that offset
+//! are applied again: delivery is at-least-once, so tags sent with an
+//! idempotency key are not applied twice only within the dedup window.
+//! Pushes beyond a bound on unapplied tags are rejected, so that a backend
+//! which falls behind does not make the queue grow without limit.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info};
+
+use crate::codec::{self, Decoder, Encoder};
+use crate::dedup::IdempotencyKey;
+use crate::segment_log::SegmentLog;
+use crate::types::{System, UserTag};
+use crate::utils;
+
+const ACK_MAGIC: &[u8; 4] = b"ALZQ";
+const ACK_VERSION: u8 = 1;
+const ACK_FILE: &str = "acked";
+const LOG_DIR: &str = "log";
+
+/// Tags applied concurrently by the consumer.
+const MAX_BATCH: usize = 256;
+/// How often the applied offset is persisted while the queue is busy.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub dir: PathBuf,
+    pub max_segment_len: u64,
+    /// Number of pushed tags waiting to be applied beyond which pushes fail
+    /// with [`io::ErrorKind::WouldBlock`].
+    pub max_pending: usize,
+}
+
+impl Config {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            max_segment_len: 64 << 20,
+            max_pending: 1 << 16,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    offset: u64,
+    /// `None` for an entry which failed to be synced, and so was rejected;
+    /// it is skipped, not to leave a gap in the applied offsets.
+    tag: Option<UserTag>,
+    key: Option<IdempotencyKey>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    dir: PathBuf,
+    log: Mutex<SegmentLog>,
+    /// Offset before which all appended entries are synced to disk.
+    synced: Mutex<u64>,
+    /// Offset before which all entries are applied.
+    acked: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct IngestQueue {
+    shared: Arc<Shared>,
+    entries: mpsc::Sender<Entry>,
+}
+
+/// Encodes a tag with its key as a record of a log.
+pub fn encode_record(tag: &UserTag, key: Option<IdempotencyKey>) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    match key {
+        Some(key) => {
+            encoder.put_u8(1);
+            encoder.put_u64(key.to_bits());
+        }
+        None => encoder.put_u8(0),
+    }
+    encoder.put_user_tag(tag);
+    encoder.finish()
+}
+
+pub fn decode_record(record: &[u8]) -> io::Result<(UserTag, Option<IdempotencyKey>)> {
+    let mut decoder = Decoder::new(record);
+    let key = match decoder.u8()? {
+        0 => None,
+        _ => Some(IdempotencyKey::from_bits(decoder.u64()?)),
+    };
+    Ok((decoder.user_tag()?, key))
+}
+
+fn read_acked(dir: &Path) -> io::Result<u64> {
+    match std::fs::read(dir.join(ACK_FILE)) {
+        Ok(contents) => {
+            let (_version, body) = codec::unseal(ACK_MAGIC, &contents)?;
+            Decoder::new(body).u64()
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+impl Shared {
+    fn append(&self, record: &[u8]) -> io::Result<u64> {
+        self.log.lock().unwrap().append(record)
+    }
+
+    /// Syncs the log up to (at least) the entry at `offset`.
+    fn sync(&self, offset: u64) -> io::Result<()> {
+        // Concurrent appenders wait here, so a single sync usually covers
+        // several of them.
+        let mut synced = self.synced.lock().unwrap();
+        if *synced <= offset {
+            let log = self.log.lock().unwrap();
+            let next_offset = log.next_offset();
+            log.sync()?;
+            *synced = next_offset;
+        }
+        Ok(())
+    }
+
+    fn persist_acked(&self, acked: u64) -> io::Result<()> {
+        let mut encoder = Encoder::new();
+        encoder.put_u64(acked);
+        utils::write_atomically(
+            &self.dir.join(ACK_FILE),
+            &codec::seal(ACK_MAGIC, ACK_VERSION, &encoder.finish()),
+        )?;
+        self.log.lock().unwrap().remove_before(acked)
+    }
+}
+
+impl IngestQueue {
+    /// Opens the queue and starts applying its entries to `system`,
+    /// beginning with those left unapplied by a previous run.
+    pub fn open(config: Config, system: Arc<dyn System>) -> io::Result<Self> {
+        let log = SegmentLog::open(config.dir.join(LOG_DIR), config.max_segment_len)?;
+        let acked = read_acked(&config.dir)?;
+        let (entries, receiver) = mpsc::channel(config.max_pending);
+
+        let unapplied = log.next_offset();
+        if unapplied > acked {
+            info!(
+                "Replaying {} unapplied tags from the ingest queue",
+                unapplied - acked
+            );
+        }
+        let shared = Arc::new(Shared {
+            dir: config.dir,
+            synced: Mutex::new(log.next_offset()),
+            acked: AtomicU64::new(acked),
+            log: Mutex::new(log),
+        });
+        tokio::spawn(consume(Arc::clone(&shared), system, receiver, unapplied));
+        Ok(Self { shared, entries })
+    }
+
+    /// Durably enqueues the tag; it is applied to the backend later.
+    /// Fails with [`io::ErrorKind::WouldBlock`] if too many tags are pending.
+    pub async fn push(&self, tag: UserTag, key: Option<IdempotencyKey>) -> io::Result<()> {
+        let permit = match self.entries.clone().try_reserve_owned() {
+            Ok(permit) => permit,
+            Err(TrySendError::Full(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "ingest queue is full",
+                ))
+            }
+            Err(TrySendError::Closed(_)) => panic!("Ingest queue consumer stopped"),
+        };
+        let record = encode_record(&tag, key);
+        let shared = Arc::clone(&self.shared);
+        // Spawned, so that an appended entry reaches the consumer even if
+        // the request is dropped in the meantime.
+        tokio::spawn(async move {
+            let (offset, synced) = tokio::task::spawn_blocking(move || {
+                let offset = shared.append(&record)?;
+                io::Result::Ok((offset, shared.sync(offset)))
+            })
+            .await
+            .expect("Ingest queue append panicked")?;
+            let tag = synced.is_ok().then_some(tag);
+            permit.send(Entry { offset, tag, key });
+            synced
+        })
+        .await
+        .expect("Ingest queue push panicked")
+    }
+
+    /// Waits until all tags pushed so far are applied.
+    pub async fn drain(&self) {
+        let next_offset = self.shared.log.lock().unwrap().next_offset();
+        while self.shared.acked.load(Ordering::Relaxed) < next_offset {
+            tokio::time::sleep(DRAIN_INTERVAL).await;
+        }
+    }
+
+    /// Number of enqueued tags which are not yet applied.
+    pub fn pending(&self) -> u64 {
+        let next_offset = self.shared.log.lock().unwrap().next_offset();
+        next_offset - self.shared.acked.load(Ordering::Relaxed)
+    }
+}
+
+/// Applies the tag, retrying (with an exponential backoff) while the backend
+/// fails, which the `System` implementations signal by panicking.
+pub async fn apply(system: Arc<dyn System>, tag: UserTag, key: Option<IdempotencyKey>) {
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        let system = Arc::clone(&system);
+        let tag = tag.clone();
+        let attempt = tokio::spawn(async move {
+            match key {
+                Some(key) => {
+                    if !system.register_user_tag_once(tag, key).await {
+                        debug!("Skipping duplicate user tag");
+                    }
+                }
+                None => system.register_user_tag(tag).await,
+            }
+        });
+        match attempt.await {
+            Ok(()) => return,
+            Err(err) => {
+                error!(
+                    "Failed to apply user tag, retrying in {:?}: {}",
+                    backoff, err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Reads back a batch of entries left unapplied by a previous run, i.e. with
+/// offsets from `from` to `until`. Returns them along with the offsets of
+/// corrupted ones.
+async fn read_unapplied(shared: &Arc<Shared>, from: u64, until: u64) -> (Vec<Entry>, Vec<u64>) {
+    loop {
+        let segments = shared.log.lock().unwrap().segments_from(from);
+        let records = tokio::task::spawn_blocking(move || segments.read_range(from, MAX_BATCH))
+            .await
+            .expect("Ingest queue read panicked");
+        match records {
+            Ok(records) => {
+                let mut entries = Vec::new();
+                let mut skipped = Vec::new();
+                for (offset, record) in records.into_iter().filter(|(offset, _)| *offset < until) {
+                    match decode_record(&record) {
+                        Ok((tag, key)) => entries.push(Entry {
+                            offset,
+                            tag: Some(tag),
+                            key,
+                        }),
+                        Err(err) => {
+                            error!("Skipping corrupted ingest queue entry {}: {}", offset, err);
+                            skipped.push(offset);
+                        }
+                    }
+                }
+                return (entries, skipped);
+            }
+            Err(err) => {
+                error!("Failed to read ingest queue entries, retrying: {}", err);
+                tokio::time::sleep(MAX_RETRY_BACKOFF).await;
+            }
+        }
+    }
+}
+
+async fn consume(
+    shared: Arc<Shared>,
+    system: Arc<dyn System>,
+    mut entries: mpsc::Receiver<Entry>,
+    unapplied: u64,
+) {
+    let mut acked = shared.acked.load(Ordering::Relaxed);
+    let mut persisted_acked = acked;
+    let mut persisted_at = Instant::now();
+    // Entries are sent in the order their appends finish, so some may be
+    // applied before entries with lower offsets.
+    let mut applied_ahead = BTreeSet::new();
+
+    let mut next = None;
+    loop {
+        let batch = if acked < unapplied {
+            // Entries of a previous run are not sent, so that they do not
+            // take up the channel.
+            let (batch, skipped) = read_unapplied(&shared, acked, unapplied).await;
+            applied_ahead.extend(skipped);
+            batch
+        } else {
+            let first = match next.take() {
+                Some(entry) => entry,
+                None => match entries.recv().await {
+                    Some(entry) => entry,
+                    None => return,
+                },
+            };
+            let mut batch = vec![first];
+            while batch.len() < MAX_BATCH {
+                match entries.try_recv() {
+                    Ok(entry) => batch.push(entry),
+                    Err(_) => break,
+                }
+            }
+            batch
+        };
+
+        let applied = futures::future::join_all(batch.into_iter().map(|entry| {
+            let system = Arc::clone(&system);
+            async move {
+                if let Some(tag) = entry.tag {
+                    apply(system, tag, entry.key).await;
+                }
+                entry.offset
+            }
+        }))
+        .await;
+        applied_ahead.extend(applied);
+        while applied_ahead.first() == Some(&acked) {
+            applied_ahead.pop_first();
+            acked += 1;
+        }
+
+        if acked >= unapplied {
+            next = entries.try_recv().ok();
+        }
+        let idle = acked >= unapplied && next.is_none();
+        if acked != persisted_acked && (idle || persisted_at.elapsed() >= ACK_INTERVAL) {
+            let shared = Arc::clone(&shared);
+            let result = tokio::task::spawn_blocking(move || shared.persist_acked(acked))
+                .await
+                .expect("Ingest queue ack panicked");
+            match result {
+                Ok(()) => {
+                    persisted_acked = acked;
+                    persisted_at = Instant::now();
+                }
+                // Entries are applied again on restart, but otherwise nothing is lost.
+                Err(err) => error!("Failed to persist ingest queue offset: {}", err),
+            }
+        }
+        shared.acked.store(acked, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{
+        self,
+        tests::{default_tag, moment_middle},
+    };
+
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn tag(i: i64) -> UserTag {
+        UserTag {
+            time: (moment_middle() + chrono::Duration::milliseconds(i)).into(),
+            ..default_tag()
+        }
+    }
+
+    async fn buys(system: &mock::System) -> usize {
+        system
+            .last_tags_by_cookie(
+                "cookie",
+                moment_middle(),
+                moment_middle() + chrono::Duration::seconds(1),
+                200,
+            )
+            .await
+            .buys
+            .len()
+    }
+
+    async fn wait_until_applied(queue: &IngestQueue) {
+        while queue.pending() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn pushed_tags_are_applied_and_acknowledged() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = Arc::new(mock::System::new());
+        let queue = IngestQueue::open(
+            Config::new(dir.path().to_owned()),
+            Arc::clone(&system) as Arc<dyn System>,
+        )
+        .unwrap();
+
+        for i in 0..10 {
+            queue.push(tag(i), None).await.unwrap();
+        }
+        let key = IdempotencyKey::explicit("retried");
+        queue.push(tag(10), Some(key)).await.unwrap();
+        queue.push(tag(10), Some(key)).await.unwrap();
+        wait_until_applied(&queue).await;
+        assert_eq!(buys(&system).await, 11);
+        drop(queue);
+
+        // Nothing is replayed after a restart.
+        let system = Arc::new(mock::System::new());
+        let queue = IngestQueue::open(
+            Config::new(dir.path().to_owned()),
+            Arc::clone(&system) as Arc<dyn System>,
+        )
+        .unwrap();
+        wait_until_applied(&queue).await;
+        assert_eq!(buys(&system).await, 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_pushes_are_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = Arc::new(mock::System::new());
+        let queue = IngestQueue::open(
+            Config::new(dir.path().to_owned()),
+            Arc::clone(&system) as Arc<dyn System>,
+        )
+        .unwrap();
+
+        // As when the client gives up before its tag is synced.
+        let push = queue.push(tag(0), None);
+        assert!(push.now_or_never().is_none());
+        queue.push(tag(1), None).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while buys(&system).await < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            queue.drain().await;
+        })
+        .await
+        .unwrap();
+        assert_eq!(queue.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_tags_are_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            // Simulates a crash after enqueuing, but before applying.
+            let mut log = SegmentLog::open(dir.path().join(LOG_DIR), 1 << 20).unwrap();
+            for i in 0..5 {
+                log.append(&encode_record(&tag(i), None)).unwrap();
+            }
+            log.sync().unwrap();
+        }
+
+        let system = Arc::new(mock::System::new());
+        let queue = IngestQueue::open(
+            Config::new(dir.path().to_owned()),
+            Arc::clone(&system) as Arc<dyn System>,
+        )
+        .unwrap();
+        wait_until_applied(&queue).await;
+        assert_eq!(buys(&system).await, 5);
+        assert_eq!(read_acked(dir.path()).unwrap(), 5);
+    }
+}
@@ -0,0 +1,421 @@
+//! Encodings of user tags accepted by `/user_tags`, besides the spec's JSON.
+//!
+//! The format is chosen by `Content-Type`: JSON, MessagePack (with the same
+//! field names as JSON) or protobuf (with the schema in `proto/user_tag.proto`).
+//! Bodies may be compressed with gzip or zstd, as told by `Content-Encoding`.
+//! Times finer than milliseconds are truncated or rejected, as told by the
+//! [`Precision`] of the state; protobuf times are in milliseconds anyway.
+
+use std::io::Read;
+
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{FromRef, FromRequest},
+    http::{header, HeaderMap, Request, StatusCode},
+};
+use chrono::{DateTime, Utc};
+use prost::Message;
+use serde::Deserialize;
+
+use crate::types::{self, Precision, UserTag, UtcMillis};
+
+/// Limit on the size of a decompressed body, so that a small compressed
+/// body cannot exhaust memory.
+const MAX_DECOMPRESSED_LEN: u64 = 2 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    MessagePack,
+    Protobuf,
+}
+
+impl Format {
+    fn of(headers: &HeaderMap) -> Result<Self, (StatusCode, String)> {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "application/json" => Ok(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Ok(Format::MessagePack)
+            }
+            "application/protobuf" | "application/x-protobuf" => Ok(Format::Protobuf),
+            other => Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content type {:?}", other),
+            )),
+        }
+    }
+
+    fn decode(self, body: &[u8], precision: Precision) -> Result<UserTag, String> {
+        if precision == Precision::Reject && self != Format::Protobuf {
+            let sent: SentTime = self.decode_serde(body)?;
+            precision.millis(sent.time)?;
+        }
+        match self {
+            Format::Json | Format::MessagePack => self.decode_serde(body),
+            Format::Protobuf => proto::UserTag::decode(body)
+                .map_err(|err| err.to_string())
+                .and_then(UserTag::try_from),
+        }
+    }
+
+    fn decode_serde<T: for<'de> Deserialize<'de>>(self, body: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(body).map_err(|err| err.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(body).map_err(|err| err.to_string()),
+            Format::Protobuf => unreachable!("protobuf bodies are not decoded with serde"),
+        }
+    }
+}
+
+/// Time of a tag as sent, before it is truncated to milliseconds.
+#[derive(Deserialize)]
+struct SentTime {
+    time: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    fn of(headers: &HeaderMap) -> Result<Self, (StatusCode, String)> {
+        let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+            return Ok(Encoding::Identity);
+        };
+        match value.to_str().unwrap_or_default().trim() {
+            "identity" => Ok(Encoding::Identity),
+            "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+            "zstd" => Ok(Encoding::Zstd),
+            other => Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content encoding {:?}", other),
+            )),
+        }
+    }
+
+    fn decompress(self, body: Bytes) -> Result<Bytes, (StatusCode, String)> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Encoding::Identity => return Ok(body),
+            Encoding::Gzip => Box::new(flate2::read::GzDecoder::new(body.as_ref())),
+            Encoding::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(body.as_ref())
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?,
+            ),
+        };
+        let mut decompressed = Vec::new();
+        decoder
+            .take(MAX_DECOMPRESSED_LEN + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        if decompressed.len() as u64 > MAX_DECOMPRESSED_LEN {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "decompressed body is too large".to_owned(),
+            ));
+        }
+        Ok(decompressed.into())
+    }
+}
+
+/// User tag in any of the supported encodings.
+pub struct TagBody(pub UserTag);
+
+#[async_trait]
+impl<S, B> FromRequest<S, B> for TagBody
+where
+    Bytes: FromRequest<S, B>,
+    Precision: FromRef<S>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let precision = Precision::from_ref(state);
+        let format = Format::of(req.headers())?;
+        let encoding = Encoding::of(req.headers())?;
+        let body = Bytes::from_request(req, state).await.map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "failed to read request body".to_owned(),
+            )
+        })?;
+        let body = encoding.decompress(body)?;
+        format
+            .decode(&body, precision)
+            .map(TagBody)
+            .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))
+    }
+}
+
+/// Messages of `proto/user_tag.proto`.
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UserTag {
+        #[prost(int64, tag = "1")]
+        pub time: i64,
+        #[prost(string, tag = "2")]
+        pub cookie: String,
+        #[prost(string, tag = "3")]
+        pub country: String,
+        #[prost(enumeration = "Device", tag = "4")]
+        pub device: i32,
+        #[prost(enumeration = "Action", tag = "5")]
+        pub action: i32,
+        #[prost(string, tag = "6")]
+        pub origin: String,
+        #[prost(message, optional, tag = "7")]
+        pub product_info: Option<ProductInfo>,
+        #[prost(btree_map = "string, string", tag = "8")]
+        pub attributes: std::collections::BTreeMap<String, String>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Device {
+        Unspecified = 0,
+        Pc = 1,
+        Mobile = 2,
+        Tv = 3,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Action {
+        Unspecified = 0,
+        View = 1,
+        Buy = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ProductInfo {
+        #[prost(int32, tag = "1")]
+        pub product_id: i32,
+        #[prost(string, tag = "2")]
+        pub brand_id: String,
+        #[prost(string, tag = "3")]
+        pub category_id: String,
+        #[prost(int32, tag = "4")]
+        pub price: i32,
+    }
+}
+
+impl TryFrom<proto::UserTag> for UserTag {
+    type Error = String;
+
+    fn try_from(tag: proto::UserTag) -> Result<Self, Self::Error> {
+        // Unset fields of proto3 are zeros, which are never valid here.
+        if tag.time == 0 {
+            return Err("missing time".to_owned());
+        }
+        let device = match proto::Device::try_from(tag.device) {
+            Ok(proto::Device::Pc) => types::Device::Pc,
+            Ok(proto::Device::Mobile) => types::Device::Mobile,
+            Ok(proto::Device::Tv) => types::Device::Tv,
+            Ok(proto::Device::Unspecified) => return Err("missing device".to_owned()),
+            Err(_) => return Err(format!("unknown device {}", tag.device)),
+        };
+        let action = match proto::Action::try_from(tag.action) {
+            Ok(proto::Action::View) => types::Action::View,
+            Ok(proto::Action::Buy) => types::Action::Buy,
+            Ok(proto::Action::Unspecified) => return Err("missing action".to_owned()),
+            Err(_) => return Err(format!("unknown action {}", tag.action)),
+        };
+        let product_info = tag
+            .product_info
+            .ok_or_else(|| "missing product_info".to_owned())?;
+        Ok(UserTag {
+            time: UtcMillis::from_timestamp_millis(tag.time)
+                .ok_or_else(|| format!("time {} out of range", tag.time))?,
+            cookie: tag.cookie,
+            country: tag.country,
+            device,
+            action,
+            origin: tag.origin,
+            product_info: types::ProductInfo {
+                product_id: product_info.product_id,
+                brand_id: product_info.brand_id,
+                category_id: product_info.category_id,
+                price: product_info.price,
+            },
+            attributes: tag.attributes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::body::Body;
+
+    use crate::types::{Action, Device, ProductInfo};
+
+    use super::*;
+
+    fn tag() -> UserTag {
+        UserTag {
+            time: "2022-03-22T12:15:00.123Z".parse().unwrap(),
+            cookie: "cookie".to_owned(),
+            country: "PL".to_owned(),
+            device: Device::Mobile,
+            action: Action::Buy,
+            origin: "CHRL".to_owned(),
+            product_info: ProductInfo {
+                product_id: 7,
+                brand_id: "apple".to_owned(),
+                category_id: "fruit".to_owned(),
+                price: 50,
+            },
+            attributes: [("campaign".to_owned(), "spring".to_owned())].into(),
+        }
+    }
+
+    fn proto_tag() -> proto::UserTag {
+        proto::UserTag {
+            time: tag().time.timestamp_millis(),
+            cookie: "cookie".to_owned(),
+            country: "PL".to_owned(),
+            device: proto::Device::Mobile.into(),
+            action: proto::Action::Buy.into(),
+            origin: "CHRL".to_owned(),
+            product_info: Some(proto::ProductInfo {
+                product_id: 7,
+                brand_id: "apple".to_owned(),
+                category_id: "fruit".to_owned(),
+                price: 50,
+            }),
+            attributes: tag().attributes,
+        }
+    }
+
+    async fn extract(
+        content_type: &str,
+        content_encoding: Option<&str>,
+        body: Vec<u8>,
+    ) -> Result<UserTag, (StatusCode, String)> {
+        let mut request = Request::builder().header(header::CONTENT_TYPE, content_type);
+        if let Some(content_encoding) = content_encoding {
+            request = request.header(header::CONTENT_ENCODING, content_encoding);
+        }
+        let request = request.body(Body::from(body)).unwrap();
+        TagBody::from_request(request, &Precision::Normalize)
+            .await
+            .map(|TagBody(tag)| tag)
+    }
+
+    #[tokio::test]
+    async fn all_formats_decode_the_same_tag() {
+        let json = serde_json::to_vec(&tag()).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&tag()).unwrap();
+        let protobuf = proto_tag().encode_to_vec();
+
+        assert_eq!(
+            extract("application/json", None, json).await.unwrap(),
+            tag()
+        );
+        assert_eq!(
+            extract("application/msgpack", None, msgpack).await.unwrap(),
+            tag()
+        );
+        assert_eq!(
+            extract("application/x-protobuf", None, protobuf)
+                .await
+                .unwrap(),
+            tag()
+        );
+        assert_eq!(
+            extract("text/plain", None, Vec::new()).await.unwrap_err().0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[tokio::test]
+    async fn unset_protobuf_fields_are_rejected() {
+        let unset = [
+            proto::UserTag {
+                time: 0,
+                ..proto_tag()
+            },
+            proto::UserTag {
+                device: proto::Device::Unspecified.into(),
+                ..proto_tag()
+            },
+            proto::UserTag {
+                action: proto::Action::Unspecified.into(),
+                ..proto_tag()
+            },
+        ];
+        for (tag, field) in unset.into_iter().zip(["time", "device", "action"]) {
+            let (status, message) = extract("application/x-protobuf", None, tag.encode_to_vec())
+                .await
+                .unwrap_err();
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(message, format!("missing {}", field));
+        }
+    }
+
+    #[tokio::test]
+    async fn finer_times_are_truncated_or_rejected() {
+        let mut json = serde_json::to_value(tag()).unwrap();
+        json["time"] = "2022-03-22T12:15:00.123456Z".into();
+        let json = serde_json::to_vec(&json).unwrap();
+        let request = || {
+            Request::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json.clone()))
+                .unwrap()
+        };
+
+        let TagBody(normalized) = TagBody::from_request(request(), &Precision::Normalize)
+            .await
+            .unwrap();
+        assert_eq!(normalized, tag());
+        let Err((status, message)) = TagBody::from_request(request(), &Precision::Reject).await
+        else {
+            panic!("a time finer than milliseconds was accepted");
+        };
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(message.contains("finer than milliseconds"));
+    }
+
+    #[tokio::test]
+    async fn compressed_bodies_are_decompressed() {
+        let protobuf = proto_tag().encode_to_vec();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&protobuf).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(
+            extract("application/x-protobuf", Some("gzip"), gzip)
+                .await
+                .unwrap(),
+            tag()
+        );
+
+        let zstd = zstd::encode_all(protobuf.as_slice(), 0).unwrap();
+        assert_eq!(
+            extract("application/x-protobuf", Some("zstd"), zstd)
+                .await
+                .unwrap(),
+            tag()
+        );
+
+        let bomb = zstd::encode_all(vec![0; 4 * 1024 * 1024].as_slice(), 0).unwrap();
+        assert_eq!(
+            extract("application/json", Some("zstd"), bomb)
+                .await
+                .unwrap_err()
+                .0,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Live feed of registered tags, followed by `/aggregates/stream`.
+//!
+//! [`System`] wraps the system serving the endpoints and publishes every tag
+//! it registers to a broadcast [`Feed`]. Each subscriber keeps its own
+//! [`MinuteClock`], which follows the shared watermark: minutes before it are
+//! closed, and rows of those minutes are then pushed.
+
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+use crate::aggregates::{Dimensions, Filter};
+use crate::dedup::{DedupWindow, IdempotencyKey};
+use crate::types::{
+    self, Action, Bucket, DataScope, MemoryUsage, SnapshotInfo, TagPosition, TagsPage, TimeRange,
+    UserProfile, UserTag, UtcMinute,
+};
+
+/// Number of tags buffered for each subscriber; a subscriber falling further
+/// behind skips the oldest of them.
+const CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub struct Feed {
+    sender: broadcast::Sender<UserTag>,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Feed {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    /// Receives the tags registered from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<UserTag> {
+        self.sender.subscribe()
+    }
+
+    fn publish(&self, tag: &UserTag) {
+        if self.sender.receiver_count() > 0 {
+            // Subscribers may have gone in the meantime, which is fine.
+            let _ = self.sender.send(tag.clone());
+        }
+    }
+}
+
+/// Minutes closed for a subscriber. A minute is closed once the watermark
+/// reaches its end; minutes closed before the subscriber first saw the
+/// watermark are never reported.
+#[derive(Debug)]
+pub struct MinuteClock {
+    open_from: Option<UtcMinute>,
+}
+
+impl MinuteClock {
+    pub fn new(watermark: Option<DateTime<Utc>>) -> Self {
+        Self {
+            open_from: watermark.map(UtcMinute::from),
+        }
+    }
+
+    /// Advances the clock to the watermark. Returns the range of minutes
+    /// closed since the last advance, if any.
+    pub fn advance(&mut self, watermark: Option<DateTime<Utc>>) -> Option<(UtcMinute, UtcMinute)> {
+        let boundary = UtcMinute::from(watermark?);
+        match self.open_from {
+            Some(from) if from < boundary => {
+                self.open_from = Some(boundary);
+                Some((from, boundary))
+            }
+            Some(_) => None,
+            None => {
+                self.open_from = Some(boundary);
+                None
+            }
+        }
+    }
+
+    pub fn is_open(&self, minute: UtcMinute) -> bool {
+        self.open_from.is_none_or(|from| from <= minute)
+    }
+}
+
+/// System publishing the tags it registers to a [`Feed`].
+pub struct System {
+    inner: Arc<dyn types::System>,
+    feed: Arc<Feed>,
+}
+
+impl System {
+    pub fn new(inner: Arc<dyn types::System>, feed: Arc<Feed>) -> Self {
+        Self { inner, feed }
+    }
+}
+
+#[async_trait]
+impl types::System for System {
+    async fn register_user_tag(&self, user_tag: UserTag) {
+        let tag = user_tag.clone();
+        self.inner.register_user_tag(user_tag).await;
+        self.feed.publish(&tag);
+    }
+
+    fn dimensions(&self) -> &Dimensions {
+        self.inner.dimensions()
+    }
+
+    fn dedup_window(&self) -> Option<&DedupWindow> {
+        self.inner.dedup_window()
+    }
+
+    async fn register_user_tag_once(&self, user_tag: UserTag, key: IdempotencyKey) -> bool {
+        let tag = user_tag.clone();
+        let registered = self.inner.register_user_tag_once(user_tag, key).await;
+        if registered {
+            self.feed.publish(&tag);
+        }
+        registered
+    }
+
+    fn supports_scopes(&self) -> bool {
+        self.inner.supports_scopes()
+    }
+
+    async fn register_user_tag_in(&self, user_tag: UserTag, scope: DataScope) {
+        let tag = scope.includes_aggregates().then(|| user_tag.clone());
+        self.inner.register_user_tag_in(user_tag, scope).await;
+        if let Some(tag) = tag {
+            self.feed.publish(&tag);
+        }
+    }
+
+    async fn last_tags_by_cookie<'a>(
+        &'a self,
+        cookie: &'a str,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        limit: usize,
+    ) -> UserProfile {
+        self.inner
+            .last_tags_by_cookie(cookie, time_from, time_to, limit)
+            .await
+    }
+
+    async fn tags_page(
+        &self,
+        cookie: &str,
+        action: Action,
+        time_range: TimeRange,
+        after: Option<TagPosition>,
+        limit: usize,
+    ) -> TagsPage {
+        self.inner
+            .tags_page(cookie, action, time_range, after, limit)
+            .await
+    }
+
+    async fn check_filter(
+        &self,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        filter: &Filter,
+    ) -> Result<(), String> {
+        self.inner.check_filter(time_from, time_to, filter).await
+    }
+
+    async fn select_bucket_stats(
+        &self,
+        time_from: DateTime<Utc>,
+        time_to: DateTime<Utc>,
+        filter: &Filter,
+    ) -> Vec<Bucket> {
+        self.inner
+            .select_bucket_stats(time_from, time_to, filter)
+            .await
+    }
+
+    async fn clear(&self, scope: DataScope, time_range: Option<TimeRange>) {
+        self.inner.clear(scope, time_range).await
+    }
+
+    async fn snapshot(&self) -> Option<io::Result<SnapshotInfo>> {
+        self.inner.snapshot().await
+    }
+
+    async fn memory_usage(&self) -> Option<MemoryUsage> {
+        self.inner.memory_usage().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::tests::{default_tag, moment_middle};
+    use crate::types::System as _;
+
+    use super::*;
+
+    #[test]
+    fn clock_closes_each_minute_once() {
+        let mut clock = MinuteClock::new(None);
+        let minute = UtcMinute::from(moment_middle());
+        let at = |seconds| Some(minute.inner() + chrono::Duration::seconds(seconds));
+
+        assert_eq!(clock.advance(None), None);
+        assert_eq!(clock.advance(at(5)), None);
+        assert!(clock.is_open(minute));
+        assert_eq!(clock.advance(at(59)), None);
+        assert_eq!(clock.advance(at(60)), Some((minute, minute.next())));
+        assert!(!clock.is_open(minute));
+        assert_eq!(clock.advance(at(25)), None);
+        assert_eq!(
+            clock.advance(at(180)),
+            Some((minute.next(), minute.next().next().next()))
+        );
+
+        let clock = MinuteClock::new(at(60));
+        assert!(!clock.is_open(minute) && clock.is_open(minute.next()));
+    }
+
+    #[tokio::test]
+    async fn registered_tags_are_published() {
+        let feed = Arc::new(Feed::new());
+        let system = System::new(Arc::new(crate::mock::System::new()), Arc::clone(&feed));
+        let tags = [1, 2, 3].map(|seconds| UserTag {
+            time: (moment_middle() + chrono::Duration::seconds(seconds)).into(),
+            ..default_tag()
+        });
+
+        // Without subscribers, tags are just registered.
+        system.register_user_tag(tags[0].clone()).await;
+        let mut receiver = feed.subscribe();
+        system.register_user_tag(tags[1].clone()).await;
+        system
+            .register_user_tag_in(tags[2].clone(), DataScope::Profiles)
+            .await;
+        assert_eq!(receiver.recv().await.unwrap(), tags[1]);
+        assert!(receiver.try_recv().is_err());
+
+        let profile = system
+            .last_tags_by_cookie(
+                "cookie",
+                moment_middle(),
+                moment_middle() + chrono::Duration::minutes(1),
+                10,
+            )
+            .await;
+        assert_eq!(profile.buys.len(), 3);
+    }
+}
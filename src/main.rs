@@ -1,14 +1,32 @@
 use clap::Parser;
 use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::log;
 
+mod admission;
+mod aggregates;
+mod cluster;
+mod codec;
+mod dedup;
+mod disk;
 mod endpoints;
+mod event_log;
+mod export;
+mod ingest_queue;
+mod input;
+mod live;
 mod mock;
 mod scylla;
+mod segment_log;
+mod streaming;
+mod subscriptions;
 #[cfg(test)]
 mod tests;
 mod types;
 mod utils;
+mod watermark;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -23,6 +41,136 @@ struct Args {
 
     #[arg(short, long, action)]
     mock: bool,
+
+    /// Directory of the embedded on-disk storage to use instead of Scylla.
+    #[arg(short, long, conflicts_with = "mock")]
+    disk: Option<PathBuf>,
+
+    /// Snapshot file to initialize the in-memory (`--mock`) state from.
+    #[arg(long, requires = "mock")]
+    restore: Option<PathBuf>,
+
+    /// File that snapshots of the in-memory (`--mock`) state are written to,
+    /// both periodically and on `POST /admin/snapshot`.
+    #[arg(long, requires = "mock")]
+    snapshot_path: Option<PathBuf>,
+
+    /// Maximum number of cookies the in-memory (`--mock`) backend keeps
+    /// profiles of; least recently used ones are evicted beyond it.
+    #[arg(long, requires = "mock")]
+    max_cookies: Option<usize>,
+
+    /// Interval between automatic snapshots; 0 disables them.
+    #[arg(long, default_value_t = 300)]
+    snapshot_interval_secs: u64,
+
+    /// How long (in event time) after the end of a minute its use case 3
+    /// counters are kept in memory before being flushed to Scylla at once.
+    #[arg(long, default_value_t = 30)]
+    watermark_delay_secs: i64,
+
+    /// Classifies tags against the watermark (the newest event time minus
+    /// `--out-of-orderness-secs`), not applying the ones too late or too far
+    /// in the future.
+    #[arg(long, action)]
+    watermark: bool,
+
+    /// How far (in seconds) behind the newest event time the watermark is.
+    #[arg(long, default_value_t = 5, requires = "watermark")]
+    out_of_orderness_secs: i64,
+
+    /// How far (in seconds) a tag may be behind the watermark
+    /// to still be applied.
+    #[arg(long, default_value_t = 3600, requires = "watermark")]
+    allowed_lateness_secs: i64,
+
+    /// How far (in seconds) ahead of the clock a tag may be to be applied.
+    #[arg(long, default_value_t = 300, requires = "watermark")]
+    max_ahead_secs: i64,
+
+    /// File that tags which are too late or too far in the future are
+    /// appended to; without it, such tags are rejected.
+    #[arg(long, requires = "watermark")]
+    dead_letter_path: Option<PathBuf>,
+
+    /// How long (in seconds) idempotency keys of registered tags are
+    /// remembered, so that retries of them are ignored.
+    #[arg(long, default_value_t = 600)]
+    dedup_window_secs: u64,
+
+    /// Deduplicates tags sent without an `Idempotency-Key` header
+    /// by their contents.
+    #[arg(long, action)]
+    derive_idempotency_keys: bool,
+
+    /// Directory of a durable queue that tags are written to before being
+    /// acknowledged; they are applied to the storage in the background.
+    #[arg(long)]
+    ingest_queue: Option<PathBuf>,
+
+    /// Directory of an embedded partitioned event log that tags are published
+    /// to before being acknowledged; a consumer per partition applies them
+    /// to the storage.
+    #[arg(long, conflicts_with = "ingest_queue")]
+    event_log: Option<PathBuf>,
+
+    /// Number of partitions of the event log; tags of a cookie always land
+    /// in the same one.
+    #[arg(long, default_value_t = 8)]
+    event_log_partitions: usize,
+
+    /// What happens to times finer than the spec allows: milliseconds in tags
+    /// and profile time ranges, full minutes in aggregate time ranges.
+    #[arg(long, value_enum, default_value_t = types::Precision::Normalize)]
+    time_precision: types::Precision,
+
+    /// Dimensions which use case 3 aggregates are kept for, and so can be
+    /// filtered on: `origin`, `brand_id`, `category_id`, `country`, `device`
+    /// and `attributes.[name]`. `origin`, `brand_id` and `category_id` by default.
+    #[arg(long, num_args = 1..)]
+    dimensions: Option<Vec<aggregates::Dimension>>,
+
+    /// Maximum numbers of requests handled concurrently on `/user_tags`,
+    /// `/user_profiles` and `/aggregates` respectively; requests beyond that
+    /// wait in a queue or, if it is full, are rejected with 503.
+    #[arg(long, num_args = 3, value_names = ["TAGS", "PROFILES", "AGGREGATES"])]
+    max_in_flight: Option<Vec<usize>>,
+
+    /// Depths of the queues of requests waiting on `/user_tags`,
+    /// `/user_profiles` and `/aggregates` respectively.
+    #[arg(long, num_args = 3, value_names = ["TAGS", "PROFILES", "AGGREGATES"])]
+    max_queued: Option<Vec<usize>>,
+
+    /// Base URLs of all nodes of the cluster (including this one), e.g.
+    /// `http://10.0.0.1:8080`; profiles are partitioned among them by cookie.
+    /// Only the in-memory (`--mock`) storage is partitioned, as a shared one
+    /// would be counted in aggregates once per node.
+    #[arg(
+        long,
+        num_args = 1..,
+        requires = "node_index",
+        requires = "mock",
+        requires = "cluster_secret"
+    )]
+    cluster: Option<Vec<String>>,
+
+    /// Index of this node in `--cluster`.
+    #[arg(long, requires = "cluster")]
+    node_index: Option<usize>,
+
+    /// Secret shared by the nodes of the cluster, required by the `/internal`
+    /// routes through which they talk to each other.
+    #[arg(long, requires = "cluster")]
+    cluster_secret: Option<String>,
+
+    /// Number of nodes storing each profile in the cluster.
+    #[arg(long, default_value_t = 2)]
+    replication_factor: usize,
+
+    /// Token required by administrative endpoints (e.g. `/clear`);
+    /// these are disabled when no token is given.
+    #[arg(long)]
+    admin_token: Option<String>,
 }
 
 async fn shutdown_signal() {
@@ -43,21 +191,116 @@ async fn main() {
         .next()
         .expect("Failed to parse socket address");
 
-    let router: axum::Router;
+    let system: Arc<dyn types::System>;
+    // Kept to flush the counters held in memory on shutdown.
+    let mut scylla_session = None;
+    let config = endpoints::Config {
+        admin_token: args.admin_token,
+        watermark: args.watermark.then(|| watermark::Config {
+            out_of_orderness: chrono::Duration::seconds(args.out_of_orderness_secs),
+            allowed_lateness: chrono::Duration::seconds(args.allowed_lateness_secs),
+            max_ahead: chrono::Duration::seconds(args.max_ahead_secs),
+            dead_letter_path: args.dead_letter_path,
+        }),
+        derive_idempotency_keys: args.derive_idempotency_keys,
+        time_precision: args.time_precision,
+        ingest_queue: args.ingest_queue.map(ingest_queue::Config::new),
+        event_log: args
+            .event_log
+            .map(|dir| event_log::Config::new(dir, args.event_log_partitions)),
+        admission: {
+            let mut admission = admission::Config::default();
+            let routes = [
+                &mut admission.user_tags,
+                &mut admission.user_profiles,
+                &mut admission.aggregates,
+            ];
+            for (i, limits) in routes.into_iter().enumerate() {
+                if let Some(max_in_flight) = &args.max_in_flight {
+                    limits.max_in_flight = max_in_flight[i];
+                }
+                if let Some(max_queued) = &args.max_queued {
+                    limits.max_queued = max_queued[i];
+                }
+            }
+            admission
+        },
+    };
+    let dedup_config = dedup::Config {
+        window: Duration::from_secs(args.dedup_window_secs),
+        ..Default::default()
+    };
 
-    if !args.mock {
-        router = endpoints::build_router(scylla::Session::new(&args.scylla_uri).await);
-        log::info!("Connected to Scylla on {}", args.scylla_uri);
-    } else {
-        router = endpoints::build_router(mock::System::new());
+    let dimensions = match args.dimensions {
+        Some(dimensions) => {
+            aggregates::Dimensions::new(dimensions).expect("Failed to parse dimensions")
+        }
+        None => Default::default(),
+    };
+
+    if args.mock {
+        let mut mock_system = match args.restore {
+            Some(path) => mock::System::restore(&path).expect("Failed to restore snapshot"),
+            None => mock::System::new(),
+        }
+        .with_limits(mock::Limits {
+            max_cookies: args.max_cookies,
+            ..Default::default()
+        })
+        .with_dedup(dedup_config)
+        .with_dimensions(dimensions);
+        if let Some(path) = args.snapshot_path {
+            let interval = (args.snapshot_interval_secs > 0)
+                .then(|| Duration::from_secs(args.snapshot_interval_secs));
+            mock_system = mock_system.with_snapshots(path, interval);
+        }
+        system = Arc::new(mock_system);
         log::info!("Starting in mock mode");
+    } else if let Some(dir) = args.disk {
+        let disk_config = disk::Config {
+            dimensions,
+            ..Default::default()
+        };
+        let disk_system = disk::System::open(&dir, disk_config)
+            .await
+            .expect("Failed to open on-disk storage");
+        system = Arc::new(disk_system);
+        log::info!("Using on-disk storage in {}", dir.display());
+    } else {
+        let streaming_config = streaming::Config {
+            watermark_delay: chrono::Duration::seconds(args.watermark_delay_secs),
+            ..Default::default()
+        };
+        let session = Arc::new(
+            scylla::Session::new(&args.scylla_uri, streaming_config, dedup_config, dimensions)
+                .await,
+        );
+        scylla_session = Some(Arc::clone(&session));
+        system = session;
+        log::info!("Connected to Scylla on {}", args.scylla_uri);
     }
 
+    let router = match (args.cluster, args.node_index, args.cluster_secret) {
+        (Some(nodes), Some(node_index), Some(secret)) => {
+            log::info!("Running as node {} of {:?}", node_index, nodes);
+            let ring = cluster::Ring::new(nodes, args.replication_factor);
+            endpoints::build_router(
+                cluster::System::new(Arc::clone(&system), ring, node_index, &secret),
+                config,
+            )
+            .merge(cluster::internal_router(system, &secret))
+        }
+        _ => endpoints::build_shared_router(system, config),
+    };
+
     log::info!("Starting server on {}", socket_address);
     let server = axum::Server::bind(&socket_address)
         .serve(router.into_make_service())
         .with_graceful_shutdown(shutdown_signal());
     server.await.unwrap();
+    if let Some(session) = scylla_session {
+        session.flush_all().await;
+    }
 }
 
 #[ignore = "Not yet written"]
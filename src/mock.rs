@@ -1,60 +1,160 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::BuildHasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use chrono::{DateTime, Timelike, Utc};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
-use tracing::trace;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, error, info};
 
 use crate::{
-    types::{self, Action, Bucket, UserProfile, UserTag, UtcMinute},
+    aggregates::{self, Dimensions, Filter, MinuteAggregates},
+    codec::{self, Decoder, Encoder},
+    dedup::{self, DedupWindow},
+    types::{
+        self, Action, Bucket, DataScope, MemoryUsage, SnapshotInfo, TagPosition, TagsPage,
+        TimeRange, UserProfile, UserTag, UtcMinute,
+    },
     utils,
 };
 
 const MAX_TAGS_BY_COOKIE: usize = 200;
+const DEFAULT_SHARDS: usize = 64;
 
+const SNAPSHOT_MAGIC: &[u8; 4] = b"ALZS";
+/// Version 1 stored full tags by timestamp instead of per-minute counters,
+/// version 2 stored counters of the legacy dimensions without listing them.
+const SNAPSHOT_VERSION: u8 = 3;
+
+type ProfileShard = HashMap<String, UserProfileInner>;
+type AggregateShard = BTreeMap<UtcMinute, MinuteAggregates>;
+
+/// Both parts of the state are split into independently locked shards,
+/// so that ingestion does not serialize on a single lock and does not block
+/// profile reads of other cookies.
 #[derive(Debug)]
 struct SystemData {
-    // // For 3rd use case - aggregates.
-    tags_by_timestamp: BTreeMap<DateTime<Utc>, Vec<UserTag>>,
+    hasher: RandomState,
+
+    // For 2nd use case - user profiles, sharded by cookie hash.
+    tags_by_cookie: Vec<RwLock<ProfileShard>>,
 
-    // For 2nd use case - user profiles.
-    tags_by_cookie: BTreeMap<String, UserProfileInner>,
+    // For 3rd use case - per-minute counters, sharded by minute.
+    buckets_by_minute: Vec<RwLock<AggregateShard>>,
+    /// Dimensions which the counters are grouped by.
+    dimensions: Dimensions,
+
+    /// Time of the newest event seen, in milliseconds since the epoch.
+    /// This is the "logical now" that aggregates retention is relative to.
+    newest_event: AtomicI64,
+    /// Start (in seconds since the epoch) of the oldest minute that
+    /// aggregates are kept for.
+    aggregates_kept_from: AtomicI64,
+    /// Logical clock ticking on every profile access, for LRU eviction.
+    access_clock: AtomicU64,
+    /// Number of cookies with a profile, across all shards.
+    cookies: AtomicUsize,
+    /// Held while evicting idle profiles, so that only one task does it.
+    evicting: Mutex<()>,
+}
+
+/// Bounds on the memory used by [`System`].
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum number of cookies to keep profiles of; the least recently
+    /// used (registered to or queried) ones are evicted beyond it.
+    pub max_cookies: Option<usize>,
+    /// Aggregates of minutes older than that (relative to the newest event)
+    /// are dropped, and events older than that are not aggregated at all.
+    pub aggregates_retention: chrono::Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_cookies: None,
+            aggregates_retention: chrono::Duration::hours(24),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct System {
-    data: RwLock<SystemData>,
+    data: Arc<SystemData>,
+    limits: Limits,
+    dedup: DedupWindow,
+    snapshot_path: Option<PathBuf>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Debug)]
 struct UserProfileInner {
     cookie: String,
     views: BTreeSet<UserTagByTime>,
     buys: BTreeSet<UserTagByTime>,
+    /// Value of [`SystemData::access_clock`] at the last access.
+    last_access: AtomicU64,
+}
+
+impl UserProfileInner {
+    fn new(cookie: String) -> Self {
+        Self {
+            cookie,
+            views: BTreeSet::new(),
+            buys: BTreeSet::new(),
+            last_access: AtomicU64::new(0),
+        }
+    }
+
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of::<(String, Self)>()
+            + 2 * self.cookie.len()
+            + self
+                .views
+                .iter()
+                .chain(self.buys.iter())
+                .map(|tag| tag.tag.estimated_size())
+                .sum::<usize>()
+    }
 }
 
 #[derive(Clone, Debug)]
-struct UserTagByTime(UserTag);
+struct UserTagByTime {
+    tag: UserTag,
+    tie_breaker: i64,
+}
+impl UserTagByTime {
+    fn key(&self) -> TagPosition {
+        (self.tag.time, self.tie_breaker)
+    }
+}
 impl PartialEq for UserTagByTime {
     fn eq(&self, other: &Self) -> bool {
-        self.0.time == other.0.time
+        self.key() == other.key()
     }
 }
 impl Eq for UserTagByTime {}
 impl PartialOrd for UserTagByTime {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.time.partial_cmp(&other.0.time)
+        Some(self.cmp(other))
     }
 }
 impl Ord for UserTagByTime {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.time.cmp(&other.0.time)
+        self.key().cmp(&other.key())
     }
 }
 impl From<UserTag> for UserTagByTime {
     fn from(tag: UserTag) -> Self {
-        UserTagByTime(tag)
+        UserTagByTime {
+            tie_breaker: tag.tie_breaker(),
+            tag,
+        }
     }
 }
 impl Serialize for UserTagByTime {
@@ -62,7 +162,7 @@ impl Serialize for UserTagByTime {
     where
         S: serde::Serializer,
     {
-        self.0.serialize(serializer)
+        self.tag.serialize(serializer)
     }
 }
 impl<'de> Deserialize<'de> for UserTagByTime {
@@ -70,7 +170,7 @@ impl<'de> Deserialize<'de> for UserTagByTime {
     where
         D: serde::Deserializer<'de>,
     {
-        UserTag::deserialize(deserializer).map(Self)
+        UserTag::deserialize(deserializer).map(Self::from)
     }
 }
 
@@ -82,50 +182,391 @@ impl Default for System {
 
 impl System {
     pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Self::from_data(SystemData::new(shards))
+    }
+
+    fn from_data(data: SystemData) -> Self {
         Self {
-            data: RwLock::new(SystemData {
-                tags_by_timestamp: Default::default(),
-                tags_by_cookie: Default::default(),
-            }),
+            data: Arc::new(data),
+            limits: Limits::default(),
+            dedup: DedupWindow::default(),
+            snapshot_path: None,
+        }
+    }
+
+    pub fn with_dedup(mut self, config: dedup::Config) -> Self {
+        self.dedup = DedupWindow::new(config);
+        self
+    }
+
+    /// Makes aggregates be kept for `dimensions`; the ones already kept
+    /// (e.g. restored from a snapshot) are regrouped.
+    pub fn with_dimensions(mut self, dimensions: Dimensions) -> Self {
+        let data = Arc::get_mut(&mut self.data).expect("System is already shared");
+        for shard in &mut data.buckets_by_minute {
+            for aggregates in shard.get_mut().values_mut() {
+                *aggregates = std::mem::take(aggregates).project(&data.dimensions, &dimensions);
+            }
+        }
+        data.dimensions = dimensions;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Creates the system with the state read from a snapshot file.
+    pub fn restore(path: &Path) -> io::Result<Self> {
+        let data = SystemData::decode(&std::fs::read(path)?, DEFAULT_SHARDS)?;
+        info!("Restored snapshot from {}", path.display());
+        Ok(Self::from_data(data))
+    }
+
+    /// Makes snapshots be written to `path`: on demand and, if `interval`
+    /// is given, periodically.
+    pub fn with_snapshots(mut self, path: PathBuf, interval: Option<Duration>) -> Self {
+        if let Some(interval) = interval {
+            tokio::spawn(snapshot_loop(
+                Arc::downgrade(&self.data),
+                path.clone(),
+                interval,
+            ));
         }
+        self.snapshot_path = Some(path);
+        self
     }
 }
 
-#[async_trait]
-impl types::System for System {
-    async fn register_user_tag(&self, tag: types::UserTag) {
-        let mut data = self.data.write().await;
-
-        data.tags_by_timestamp
-            .entry(tag.time)
-            .or_default()
-            .push(tag.clone());
-
-        data.tags_by_cookie
-            .entry(tag.cookie.clone())
-            .and_modify(|user_profile| {
-                let set = match tag.action {
-                    Action::View => &mut user_profile.views,
-                    Action::Buy => &mut user_profile.buys,
-                };
-                set.insert(tag.clone().into());
-                if set.len() > MAX_TAGS_BY_COOKIE {
-                    set.pop_first();
+async fn write_snapshot(data: &SystemData, path: &Path) -> io::Result<SnapshotInfo> {
+    let snapshot = data.encode().await;
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        utils::write_atomically(&path, &snapshot)?;
+        Ok(SnapshotInfo {
+            path,
+            size: snapshot.len() as u64,
+        })
+    })
+    .await
+    .expect("Snapshot task panicked")
+}
+
+async fn snapshot_loop(data: Weak<SystemData>, path: PathBuf, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(data) = data.upgrade() else {
+            return;
+        };
+        match write_snapshot(&data, &path).await {
+            Ok(info) => info!("Written {} B snapshot to {}", info.size, path.display()),
+            Err(err) => error!("Failed to write snapshot to {}: {}", path.display(), err),
+        }
+    }
+}
+
+fn register_in_profile(shard: &mut ProfileShard, tag: UserTag) -> &UserProfileInner {
+    let user_profile = shard
+        .entry(tag.cookie.clone())
+        .or_insert_with(|| UserProfileInner::new(tag.cookie.clone()));
+    let set = match tag.action {
+        Action::View => &mut user_profile.views,
+        Action::Buy => &mut user_profile.buys,
+    };
+    set.insert(tag.into());
+    if set.len() > MAX_TAGS_BY_COOKIE {
+        set.pop_first();
+    }
+    user_profile
+}
+
+impl SystemData {
+    fn new(shards: usize) -> Self {
+        assert!(shards > 0);
+        Self {
+            hasher: RandomState::new(),
+            tags_by_cookie: (0..shards).map(|_| Default::default()).collect(),
+            buckets_by_minute: (0..shards).map(|_| Default::default()).collect(),
+            dimensions: Dimensions::default(),
+            newest_event: AtomicI64::new(i64::MIN),
+            aggregates_kept_from: AtomicI64::new(i64::MIN),
+            access_clock: AtomicU64::new(0),
+            cookies: AtomicUsize::new(0),
+            evicting: Mutex::new(()),
+        }
+    }
+
+    /// Records the event time and returns the newest one seen so far.
+    fn observe_event(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let time = time.timestamp_millis();
+        let newest = self
+            .newest_event
+            .fetch_max(time, Ordering::Relaxed)
+            .max(time);
+        DateTime::from_timestamp_millis(newest).unwrap()
+    }
+
+    fn touch(&self, profile: &UserProfileInner) {
+        let tick = self.access_clock.fetch_add(1, Ordering::Relaxed);
+        profile.last_access.store(tick, Ordering::Relaxed);
+    }
+
+    /// Evicts the least recently used profiles, so that at most `max_cookies`
+    /// of them are kept. Evicts a tenth more than necessary, so that the cost
+    /// of finding the victims is amortized over the following insertions.
+    async fn evict_idle_profiles(&self, max_cookies: usize) {
+        let Ok(_evicting) = self.evicting.try_lock() else {
+            return;
+        };
+        let cookies = self.cookies.load(Ordering::Relaxed);
+        if cookies <= max_cookies {
+            return;
+        }
+        let to_evict = cookies - max_cookies + max_cookies / 10;
+        let mut by_access = Vec::with_capacity(cookies);
+        for (index, shard) in self.tags_by_cookie.iter().enumerate() {
+            let shard = shard.read().await;
+            by_access.extend(shard.iter().map(|(cookie, profile)| {
+                let access = profile.last_access.load(Ordering::Relaxed);
+                (access, index, cookie.clone())
+            }));
+        }
+        if to_evict < by_access.len() {
+            by_access.select_nth_unstable(to_evict);
+            by_access.truncate(to_evict);
+        }
+        // Groups the victims by shard, to take each lock once.
+        by_access.sort_unstable_by_key(|(_, index, _)| *index);
+        let mut evicted = 0;
+        for victims in by_access.chunk_by(|(_, a, _), (_, b, _)| a == b) {
+            let mut shard = self.tags_by_cookie[victims[0].1].write().await;
+            for (access, _, cookie) in victims {
+                // Spares profiles which were used since they were picked.
+                if shard
+                    .get(cookie)
+                    .is_some_and(|profile| profile.last_access.load(Ordering::Relaxed) == *access)
+                {
+                    shard.remove(cookie);
+                    evicted += 1;
                 }
-            })
-            .or_insert_with(|| {
-                let set =
-                    std::iter::once(UserTagByTime::from(tag.clone())).collect::<BTreeSet<_>>();
-                let (views, buys) = match tag.action {
-                    Action::View => (set, BTreeSet::new()),
-                    Action::Buy => (BTreeSet::new(), set),
+            }
+        }
+        self.cookies.fetch_sub(evicted, Ordering::Relaxed);
+        debug!("Evicted {} idle profiles", evicted);
+    }
+
+    /// Drops aggregates of minutes before `cutoff`. Only the first caller
+    /// for a given cutoff actually scans the shards.
+    async fn evict_aggregates_before(&self, cutoff: UtcMinute) {
+        let cutoff_secs = cutoff.inner().timestamp();
+        if self
+            .aggregates_kept_from
+            .fetch_max(cutoff_secs, Ordering::Relaxed)
+            >= cutoff_secs
+        {
+            return;
+        }
+        for shard in &self.buckets_by_minute {
+            let mut shard = shard.write().await;
+            *shard = shard.split_off(&cutoff);
+        }
+    }
+
+    fn profile_shard(&self, cookie: &str) -> &RwLock<ProfileShard> {
+        let hash = self.hasher.hash_one(cookie);
+        &self.tags_by_cookie[hash as usize % self.tags_by_cookie.len()]
+    }
+
+    fn minute_shard(&self, minute: UtcMinute) -> &RwLock<AggregateShard> {
+        let index = minute.inner().timestamp().div_euclid(60) as usize;
+        &self.buckets_by_minute[index % self.buckets_by_minute.len()]
+    }
+
+    /// Each shard is encoded consistently, but the snapshot as a whole
+    /// may include tags registered while it was being taken.
+    async fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+
+        let mut profiles_count = 0;
+        let mut profiles = Encoder::new();
+        for shard in &self.tags_by_cookie {
+            let shard = shard.read().await;
+            profiles_count += shard.len();
+            for (cookie, profile) in shard.iter() {
+                profiles.put_str(cookie);
+                for tags in [&profile.views, &profile.buys] {
+                    profiles.put_u64(tags.len() as u64);
+                    for tag in tags {
+                        profiles.put_user_tag(&tag.tag);
+                    }
+                }
+            }
+        }
+        encoder.put_u64(profiles_count as u64);
+        encoder.put_bytes(&profiles.finish());
+
+        let mut minutes_count = 0;
+        let mut minutes = Encoder::new();
+        for shard in &self.buckets_by_minute {
+            let shard = shard.read().await;
+            minutes_count += shard.len();
+            for (minute, aggregates) in shard.iter() {
+                minutes.put_time(minute.inner());
+                minutes.put_u64(aggregates.len() as u64);
+                for (key, counters) in aggregates.iter() {
+                    minutes.put_aggregate(key, counters);
+                }
+            }
+        }
+        encoder.put_dimensions(&self.dimensions);
+        encoder.put_u64(minutes_count as u64);
+        encoder.put_bytes(&minutes.finish());
+
+        codec::seal(SNAPSHOT_MAGIC, SNAPSHOT_VERSION, &encoder.finish())
+    }
+
+    fn decode(snapshot: &[u8], shards: usize) -> io::Result<Self> {
+        let (version, body) = codec::unseal(SNAPSHOT_MAGIC, snapshot)?;
+        let mut decoder = Decoder::new(body);
+        let mut data = Self::new(shards);
+
+        let read_profiles = |decoder: &mut Decoder| -> io::Result<()> {
+            for _ in 0..decoder.u64()? {
+                let cookie = decoder.str()?;
+                let mut read_tags = || {
+                    (0..decoder.u64()?)
+                        .map(|_| {
+                            let tag = decoder.user_tag()?;
+                            data.observe_event(tag.time.inner());
+                            Ok(UserTagByTime::from(tag))
+                        })
+                        .collect::<io::Result<BTreeSet<_>>>()
                 };
-                UserProfileInner {
-                    cookie: tag.cookie.clone(),
-                    views,
-                    buys,
+                let mut profile = UserProfileInner::new(cookie.clone());
+                profile.views = read_tags()?;
+                profile.buys = read_tags()?;
+                data.profile_shard(&cookie)
+                    .try_write()
+                    .unwrap()
+                    .insert(cookie, profile);
+                data.cookies.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        };
+
+        let read_aggregates = |decoder: &mut Decoder, dimensions: &Dimensions| {
+            for _ in 0..decoder.u64()? {
+                let minute = UtcMinute::from(decoder.time()?);
+                let mut shard = data.minute_shard(minute).try_write().unwrap();
+                let aggregates = shard.entry(minute).or_default();
+                for _ in 0..decoder.u64()? {
+                    let (key, counters) = decoder.aggregate(dimensions)?;
+                    aggregates.add(key, counters);
                 }
-            });
+            }
+            io::Result::Ok(())
+        };
+
+        let dimensions;
+        match version {
+            1 => {
+                for _ in 0..decoder.u64()? {
+                    let time = decoder.time()?;
+                    let minute = UtcMinute::from(time);
+                    let mut shard = data.minute_shard(minute).try_write().unwrap();
+                    let aggregates = shard.entry(minute).or_default();
+                    for _ in 0..decoder.u64()? {
+                        aggregates.register(&decoder.user_tag()?, &Dimensions::legacy());
+                    }
+                }
+                read_profiles(&mut decoder)?;
+                dimensions = Dimensions::legacy();
+            }
+            2 => {
+                read_profiles(&mut decoder)?;
+                read_aggregates(&mut decoder, &Dimensions::legacy())?;
+                dimensions = Dimensions::legacy();
+            }
+            SNAPSHOT_VERSION => {
+                read_profiles(&mut decoder)?;
+                dimensions = decoder.dimensions()?;
+                read_aggregates(&mut decoder, &dimensions)?;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported snapshot version: {}", version),
+                ))
+            }
+        }
+
+        if !decoder.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data in snapshot",
+            ));
+        }
+
+        data.dimensions = dimensions;
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl types::System for System {
+    async fn register_user_tag(&self, tag: types::UserTag) {
+        self.register_user_tag_in(tag, DataScope::All).await;
+    }
+
+    fn supports_scopes(&self) -> bool {
+        true
+    }
+
+    async fn register_user_tag_in(&self, tag: types::UserTag, scope: DataScope) {
+        if scope.includes_aggregates() {
+            let newest = self.data.observe_event(tag.time.inner());
+            let cutoff = UtcMinute::from(newest - self.limits.aggregates_retention);
+            let minute = UtcMinute::from(tag.time);
+            if minute >= cutoff {
+                self.data
+                    .minute_shard(minute)
+                    .write()
+                    .await
+                    .entry(minute)
+                    .or_default()
+                    .register(&tag, &self.data.dimensions);
+            }
+            self.data.evict_aggregates_before(cutoff).await;
+        }
+        if !scope.includes_profiles() {
+            return;
+        }
+
+        let mut shard = self.data.profile_shard(&tag.cookie).write().await;
+        let len = shard.len();
+        self.data.touch(register_in_profile(&mut shard, tag));
+        if shard.len() > len {
+            self.data.cookies.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(shard);
+        if let Some(max_cookies) = self.limits.max_cookies {
+            self.data.evict_idle_profiles(max_cookies).await;
+        }
+    }
+
+    fn dimensions(&self) -> &Dimensions {
+        &self.data.dimensions
+    }
+
+    fn dedup_window(&self) -> Option<&DedupWindow> {
+        Some(&self.dedup)
     }
 
     async fn last_tags_by_cookie<'a>(
@@ -137,22 +578,23 @@ impl types::System for System {
     ) -> UserProfile {
         assert!(limit <= MAX_TAGS_BY_COOKIE);
 
-        let data = self.data.read().await;
+        let shard = self.data.profile_shard(cookie).read().await;
 
-        let profile = data
-            .tags_by_cookie
+        let profile = shard
             .get(cookie)
             .map(|profile| {
+                self.data.touch(profile);
+
                 fn filtered_iter<'a>(
-                    iter: impl Iterator<Item = &'a UserTagByTime> + DoubleEndedIterator,
+                    iter: impl DoubleEndedIterator<Item = &'a UserTagByTime>,
                     time_from: DateTime<Utc>,
                     time_to: DateTime<Utc>,
                     limit: usize,
                 ) -> impl Iterator<Item = &'a UserTag> {
-                    iter.map(|tag| &tag.0)
+                    iter.map(|tag| &tag.tag)
                         .rev()
-                        .skip_while(move |tag| tag.time > time_to)
-                        .take_while(move |tag| tag.time >= time_from)
+                        .skip_while(move |tag| tag.time.inner() > time_to)
+                        .take_while(move |tag| tag.time.inner() >= time_from)
                         .take(limit)
                 }
 
@@ -178,134 +620,132 @@ impl types::System for System {
         profile
     }
 
+    async fn tags_page(
+        &self,
+        cookie: &str,
+        action: Action,
+        time_range: TimeRange,
+        after: Option<TagPosition>,
+        limit: usize,
+    ) -> TagsPage {
+        let shard = self.data.profile_shard(cookie).read().await;
+        let Some(profile) = shard.get(cookie) else {
+            return TagsPage::default();
+        };
+        self.data.touch(profile);
+
+        let tags = match action {
+            Action::View => &profile.views,
+            Action::Buy => &profile.buys,
+        };
+        let in_range = tags
+            .iter()
+            .rev()
+            .skip_while(|tag| tag.tag.time.inner() > time_range.to)
+            .take_while(|tag| tag.tag.time.inner() >= time_range.from);
+        TagsPage::of(in_range.map(|tag| (tag.key(), &tag.tag)), after, limit)
+    }
+
     async fn select_bucket_stats(
         &self,
         time_from: DateTime<Utc>,
         time_to: DateTime<Utc>,
-        action: Action,
-        origin: Option<&str>,
-        brand_id: Option<&str>,
-        category_id: Option<&str>,
+        filter: &Filter,
     ) -> Vec<Bucket> {
         let time_from = UtcMinute::from(time_from);
         let time_to = UtcMinute::from(time_to);
         assert!(time_from < time_to);
-        let read_guard = self.data.read().await;
-        let range = read_guard
-            .tags_by_timestamp
-            .range(time_from.inner()..time_to.inner());
-
-        struct BucketIter<'a, It: Iterator<Item = (&'a DateTime<Utc>, &'a Vec<UserTag>)>> {
-            min_curr: UtcMinute,
-            min_to: UtcMinute,
-            it: std::iter::Peekable<It>,
-            action: Action,
-            origin: Option<&'a str>,
-            brand_id: Option<&'a str>,
-            category_id: Option<&'a str>,
-        }
-
-        impl<'a, It: Iterator<Item = (&'a DateTime<Utc>, &'a Vec<UserTag>)>> BucketIter<'a, It> {
-            fn new(
-                time_from: UtcMinute,
-                time_to: UtcMinute,
-                it: It,
-                action: Action,
-                origin: Option<&'a str>,
-                brand_id: Option<&'a str>,
-                category_id: Option<&'a str>,
-            ) -> Self {
-                Self {
-                    min_curr: time_from,
-                    min_to: time_to,
-                    it: it.peekable(),
-                    action,
-                    origin,
-                    brand_id,
-                    category_id,
-                }
-            }
-        }
-        impl<'a, It: Iterator<Item = (&'a DateTime<Utc>, &'a Vec<UserTag>)>> Iterator
-            for BucketIter<'a, It>
-        {
-            type Item = Bucket;
 
-            fn next(&mut self) -> Option<Self::Item> {
-                // Find out what bucket we are in
-                let bucket_minute: UtcMinute = self.min_curr;
+        let mut buckets = Vec::new();
+        for minute in aggregates::minutes(time_from, time_to) {
+            let counters = self
+                .data
+                .minute_shard(minute)
+                .read()
+                .await
+                .get(&minute)
+                .map(|aggregates| aggregates.select(filter, &self.data.dimensions))
+                .unwrap_or_default();
+            buckets.push(counters.into_bucket(minute));
+        }
+        buckets
+    }
 
-                // Stop condition
-                if bucket_minute >= self.min_to {
-                    return None;
+    async fn clear(&self, scope: DataScope, time_range: Option<TimeRange>) {
+        if scope.includes_profiles() {
+            for shard in &self.data.tags_by_cookie {
+                let mut shard = shard.write().await;
+                let len = shard.len();
+                match time_range {
+                    None => shard.clear(),
+                    Some(TimeRange { from, to }) => {
+                        let in_range = |tag: &UserTagByTime| {
+                            from <= tag.tag.time.inner() && tag.tag.time.inner() < to
+                        };
+                        shard.retain(|_, profile| {
+                            profile.views.retain(|tag| !in_range(tag));
+                            profile.buys.retain(|tag| !in_range(tag));
+                            !profile.views.is_empty() || !profile.buys.is_empty()
+                        });
+                    }
                 }
+                self.data
+                    .cookies
+                    .fetch_sub(len - shard.len(), Ordering::Relaxed);
+            }
+        }
 
-                let mut count = 0;
-                let mut sum_price = 0;
-
-                while let Some((&datetime, tags)) = self.it.peek() {
-                    trace!(
-                        "datetime: {} min, bucket: {} min.",
-                        datetime.minute(),
-                        bucket_minute.inner().minute()
-                    );
-                    trace!("datetime: {}, bucket: {}.", datetime, bucket_minute.inner());
-                    match UtcMinute::from(datetime).cmp(&bucket_minute) {
-                        std::cmp::Ordering::Less => unreachable!("BTreeMap iter invariant!"),
-                        std::cmp::Ordering::Greater => break, // this belongs already to the next bucket
-                        std::cmp::Ordering::Equal => {
-                            for tag in *tags {
-                                if self.action == tag.action
-                                    && self
-                                        .origin
-                                        .map(|origin| origin == tag.origin)
-                                        .unwrap_or(true)
-                                    && self
-                                        .brand_id
-                                        .map(|brand_id| brand_id == tag.product_info.brand_id)
-                                        .unwrap_or(true)
-                                    && self
-                                        .category_id
-                                        .map(|category_id| {
-                                            category_id == tag.product_info.category_id
-                                        })
-                                        .unwrap_or(true)
-                                {
-                                    count += 1;
-                                    sum_price += tag.product_info.price;
-                                }
-                            }
-
-                            // Advance
-                            self.it.next().unwrap();
-                        }
+        if scope.includes_aggregates() {
+            for shard in &self.data.buckets_by_minute {
+                let mut shard = shard.write().await;
+                match time_range {
+                    None => shard.clear(),
+                    Some(TimeRange { from, to }) => {
+                        // Aggregates are bucketed, so whole minutes are removed.
+                        let from = UtcMinute::from(from);
+                        shard.retain(|minute, _| !(from <= *minute && minute.inner() < to));
                     }
                 }
-                self.min_curr = self.min_curr.next();
-
-                Some(Bucket {
-                    minute: bucket_minute,
-                    count,
-                    sum_price,
-                })
+            }
+            if time_range.is_none() {
+                // Lets a fresh data set start from arbitrarily old events,
+                // possibly the same as already seen ones.
+                self.dedup.clear();
+                self.data.newest_event.store(i64::MIN, Ordering::Relaxed);
+                self.data
+                    .aggregates_kept_from
+                    .store(i64::MIN, Ordering::Relaxed);
             }
         }
+    }
 
-        BucketIter::new(
-            time_from,
-            time_to,
-            range,
-            action,
-            origin,
-            brand_id,
-            category_id,
-        )
-        .collect()
+    async fn snapshot(&self) -> Option<io::Result<SnapshotInfo>> {
+        let path = self.snapshot_path.as_ref()?;
+        Some(write_snapshot(&self.data, path).await)
     }
 
-    async fn clear(&self) {
-        let mut data = self.data.write().await;
-        data.tags_by_cookie = Default::default();
+    /// Walks the whole state, so it takes a while with a lot of data.
+    async fn memory_usage(&self) -> Option<MemoryUsage> {
+        let mut usage = MemoryUsage::default();
+        for shard in &self.data.tags_by_cookie {
+            let shard = shard.read().await;
+            usage.cookies += shard.len();
+            for profile in shard.values() {
+                usage.profile_tags += profile.views.len() + profile.buys.len();
+                usage.profiles_bytes += profile.estimated_size();
+            }
+        }
+        for shard in &self.data.buckets_by_minute {
+            let shard = shard.read().await;
+            usage.minutes += shard.len();
+            for aggregates in shard.values() {
+                usage.aggregate_entries += aggregates.len();
+                usage.aggregates_bytes +=
+                    std::mem::size_of::<UtcMinute>() + aggregates.estimated_size();
+            }
+        }
+        usage.total_bytes = usage.profiles_bytes + usage.aggregates_bytes;
+        Some(usage)
     }
 }
 
@@ -315,6 +755,8 @@ pub mod tests {
 
     use chrono::{NaiveDate, NaiveDateTime};
 
+    use crate::aggregates::Dimension;
+    use crate::dedup::IdempotencyKey;
     use crate::types::{Device, ProductInfo, System, UtcMinute};
 
     use super::*;
@@ -334,22 +776,23 @@ pub mod tests {
             price: 0,
         }
     }
-    fn default_tag() -> UserTag {
+    pub fn default_tag() -> UserTag {
         UserTag {
-            time: DateTime::<Utc>::MIN_UTC,
+            time: DateTime::<Utc>::MIN_UTC.into(),
             cookie: "cookie".to_owned(),
             country: "PL".to_owned(),
             device: Device::Pc,
             action: Action::Buy,
             origin: "CHRL".to_owned(),
             product_info: default_product_info(),
+            attributes: BTreeMap::new(),
         }
     }
 
-    fn moment_middle() -> DateTime<Utc> {
+    pub fn moment_middle() -> DateTime<Utc> {
         let naive_date: NaiveDate = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
         let naive_moment: NaiveDateTime = naive_date.and_hms_opt(21, 37, 42).unwrap();
-        let moment_middle: DateTime<Utc> = DateTime::from_utc(naive_moment, Utc);
+        let moment_middle: DateTime<Utc> = naive_moment.and_utc();
         moment_middle
     }
 
@@ -372,7 +815,7 @@ pub mod tests {
 
         let tags_min_zero = [
             UserTag {
-                time: moment_middle(),
+                time: moment_middle().into(),
                 action: Action::Buy,
                 product_info: ProductInfo {
                     price: 20,
@@ -381,7 +824,7 @@ pub mod tests {
                 ..default_tag()
             },
             UserTag {
-                time: moment_middle() + chrono::Duration::seconds(2),
+                time: (moment_middle() + chrono::Duration::seconds(2)).into(),
                 action: Action::Buy,
                 product_info: ProductInfo {
                     price: 30,
@@ -423,7 +866,7 @@ pub mod tests {
             user_profile.buys,
             vec![
                 UserTag {
-                    time: moment_middle() + chrono::Duration::seconds(2),
+                    time: (moment_middle() + chrono::Duration::seconds(2)).into(),
                     action: Action::Buy,
                     product_info: ProductInfo {
                         price: 30,
@@ -432,7 +875,7 @@ pub mod tests {
                     ..default_tag()
                 },
                 UserTag {
-                    time: moment_middle(),
+                    time: moment_middle().into(),
                     action: Action::Buy,
                     product_info: ProductInfo {
                         price: 20,
@@ -455,7 +898,7 @@ pub mod tests {
         assert_eq!(
             user_profile.buys,
             vec![UserTag {
-                time: moment_middle() + chrono::Duration::seconds(2),
+                time: (moment_middle() + chrono::Duration::seconds(2)).into(),
                 action: Action::Buy,
                 product_info: ProductInfo {
                     price: 30,
@@ -465,4 +908,383 @@ pub mod tests {
             },]
         );
     }
+
+    #[tokio::test]
+    async fn clear_is_scoped() {
+        let (system, minutes) = build_system_and_register_tags().await;
+        let profile_range = (minutes.minute_middle.inner(), minutes.minute_after.inner());
+        let filter = Filter::new(Action::Buy);
+        let buckets = || {
+            system.select_bucket_stats(
+                minutes.minute_middle.inner(),
+                minutes.minute_after.inner(),
+                &filter,
+            )
+        };
+
+        system.clear(DataScope::Aggregates, None).await;
+        assert!(buckets().await.iter().all(|bucket| bucket.count == 0));
+        let user_profile = system
+            .last_tags_by_cookie("cookie", profile_range.0, profile_range.1, 100)
+            .await;
+        assert_eq!(user_profile.buys.len(), 2);
+
+        // Only the first of the two tags falls into the cleared range.
+        system
+            .clear(
+                DataScope::Profiles,
+                Some(TimeRange {
+                    from: moment_middle(),
+                    to: moment_middle() + chrono::Duration::seconds(1),
+                }),
+            )
+            .await;
+        let user_profile = system
+            .last_tags_by_cookie("cookie", profile_range.0, profile_range.1, 100)
+            .await;
+        assert_eq!(user_profile.buys.len(), 1);
+        assert_eq!(
+            user_profile.buys[0].time.inner(),
+            moment_middle() + chrono::Duration::seconds(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn clear_all_resets_aggregates_too() {
+        let (system, minutes) = build_system_and_register_tags().await;
+
+        system.clear(DataScope::All, None).await;
+
+        let buckets = system
+            .select_bucket_stats(
+                minutes.minute_middle.inner(),
+                minutes.minute_after.inner(),
+                &Filter::new(Action::Buy),
+            )
+            .await;
+        assert!(buckets.iter().all(|bucket| bucket.count == 0));
+        let user_profile = system
+            .last_tags_by_cookie(
+                "cookie",
+                minutes.minute_middle.inner(),
+                minutes.minute_after.inner(),
+                100,
+            )
+            .await;
+        assert!(user_profile.buys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_restores_whole_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        let (system, minutes) = build_system_and_register_tags().await;
+        assert!(system.snapshot().await.is_none());
+
+        let system = system.with_snapshots(path.clone(), None);
+        let info = system.snapshot().await.unwrap().unwrap();
+        assert_eq!(info.size, std::fs::metadata(&path).unwrap().len());
+
+        let restored = super::System::restore(&path).unwrap();
+        for system in [&system, &restored] {
+            let profile = system
+                .last_tags_by_cookie(
+                    "cookie",
+                    minutes.minute_middle.inner(),
+                    minutes.minute_after.inner(),
+                    100,
+                )
+                .await;
+            assert_eq!(profile.buys.len(), 2);
+        }
+        let mut buckets = Vec::new();
+        for system in [&system, &restored] {
+            buckets.push(
+                system
+                    .select_bucket_stats(
+                        minutes.minute_middle.inner(),
+                        minutes.minute_after.inner(),
+                        &Filter::new(Action::Buy),
+                    )
+                    .await,
+            );
+        }
+        assert_eq!(buckets[0], buckets[1]);
+        assert_eq!(buckets[0][0].sum_price, 50);
+
+        // Restored aggregates are regrouped by the configured dimensions.
+        let regrouped = super::System::restore(&path)
+            .unwrap()
+            .with_dimensions(Dimensions::legacy());
+        assert_eq!(regrouped.dimensions(), &Dimensions::legacy());
+        let mut filter = Filter::new(Action::Buy);
+        filter
+            .values
+            .insert(Dimension::Origin, default_tag().origin);
+        let regrouped_buckets = regrouped
+            .select_bucket_stats(
+                minutes.minute_middle.inner(),
+                minutes.minute_after.inner(),
+                &filter,
+            )
+            .await;
+        assert_eq!(regrouped_buckets, buckets[0]);
+
+        // Corrupted snapshots are rejected.
+        let mut snapshot = std::fs::read(&path).unwrap();
+        snapshot[10] ^= 1;
+        std::fs::write(&path, snapshot).unwrap();
+        assert!(super::System::restore(&path).is_err());
+    }
+
+    #[tokio::test]
+    async fn same_millisecond_tags_are_kept() {
+        let system = super::System::new();
+        let tags = [20, 30, 40].map(|price| UserTag {
+            time: moment_middle().into(),
+            product_info: ProductInfo {
+                price,
+                ..default_product_info()
+            },
+            ..default_tag()
+        });
+        for tag in tags.iter().rev() {
+            system.register_user_tag(tag.clone()).await;
+        }
+
+        let profile = system
+            .last_tags_by_cookie("cookie", moment_middle(), moment_middle(), 100)
+            .await;
+        let mut expected = tags.to_vec();
+        expected.sort_by_key(|tag| std::cmp::Reverse(tag.tie_breaker()));
+        assert_eq!(profile.buys, expected);
+    }
+
+    #[tokio::test]
+    async fn pages_list_all_tags_in_range() {
+        let system = super::System::new();
+        for seconds in 0..5 {
+            let tag = UserTag {
+                time: (moment_middle() + chrono::Duration::seconds(seconds)).into(),
+                ..default_tag()
+            };
+            system.register_user_tag(tag).await;
+        }
+        let time_range = TimeRange {
+            from: moment_middle(),
+            to: moment_middle() + chrono::Duration::seconds(3),
+        };
+
+        let mut listed = Vec::new();
+        let mut after = None;
+        loop {
+            let page = system
+                .tags_page("cookie", Action::Buy, time_range, after, 3)
+                .await;
+            // Counted for the first page only.
+            assert_eq!(page.total, after.is_none().then_some(4));
+            listed.extend(page.tags);
+            after = page.next;
+            if after.is_none() {
+                break;
+            }
+        }
+        let expected = (0..4)
+            .rev()
+            .map(|seconds| moment_middle() + chrono::Duration::seconds(seconds))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed
+                .iter()
+                .map(|tag| tag.time.inner())
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn retried_tags_are_registered_once() {
+        let system = super::System::new();
+        let tag = UserTag {
+            time: moment_middle().into(),
+            ..default_tag()
+        };
+        let key = IdempotencyKey::explicit("request-1");
+
+        assert!(system.register_user_tag_once(tag.clone(), key).await);
+        assert!(!system.register_user_tag_once(tag.clone(), key).await);
+        // A different key makes it a distinct event.
+        assert!(
+            system
+                .register_user_tag_once(tag, IdempotencyKey::explicit("request-2"))
+                .await
+        );
+
+        let minute = UtcMinute::from(moment_middle());
+        let buckets = system
+            .select_bucket_stats(
+                minute.inner(),
+                minute.next().inner(),
+                &Filter::new(Action::Buy),
+            )
+            .await;
+        assert_eq!(buckets[0].count, 2);
+
+        // Clearing everything makes replaying the same events possible.
+        system.clear(DataScope::All, None).await;
+        let tag = UserTag {
+            time: moment_middle().into(),
+            ..default_tag()
+        };
+        assert!(system.register_user_tag_once(tag, key).await);
+    }
+
+    #[tokio::test]
+    async fn old_aggregates_are_evicted() {
+        let (system, minutes) = build_system_and_register_tags().await;
+        let usage = system.memory_usage().await.unwrap();
+        assert_eq!((usage.minutes, usage.aggregate_entries), (1, 1));
+
+        let next_day = moment_middle() + chrono::Duration::hours(24) + chrono::Duration::minutes(1);
+        system
+            .register_user_tag(UserTag {
+                time: next_day.into(),
+                ..default_tag()
+            })
+            .await;
+        // Too old to be aggregated anymore.
+        system
+            .register_user_tag(UserTag {
+                time: (moment_middle() + chrono::Duration::seconds(1)).into(),
+                ..default_tag()
+            })
+            .await;
+
+        let buckets = system
+            .select_bucket_stats(
+                minutes.minute_middle.inner(),
+                minutes.minute_after.inner(),
+                &Filter::new(Action::Buy),
+            )
+            .await;
+        assert!(buckets.iter().all(|bucket| bucket.count == 0));
+        let usage = system.memory_usage().await.unwrap();
+        assert_eq!(usage.minutes, 1);
+        // Profiles are not subject to the retention.
+        assert_eq!(usage.profile_tags, 4);
+    }
+
+    #[tokio::test]
+    async fn idle_cookies_are_evicted() {
+        let system = super::System::with_shards(1).with_limits(Limits {
+            max_cookies: Some(10),
+            ..Default::default()
+        });
+        let register = |cookie: usize| {
+            system.register_user_tag(UserTag {
+                time: moment_middle().into(),
+                cookie: format!("cookie{}", cookie),
+                ..default_tag()
+            })
+        };
+        let system = &system;
+        let buys_of = |cookie: usize| async move {
+            system
+                .last_tags_by_cookie(
+                    &format!("cookie{}", cookie),
+                    moment_middle(),
+                    moment_middle(),
+                    10,
+                )
+                .await
+                .buys
+                .len()
+        };
+
+        for cookie in 0..10 {
+            register(cookie).await;
+        }
+        // Makes the oldest cookie the most recently used one.
+        assert_eq!(buys_of(0).await, 1);
+        register(10).await;
+
+        let usage = system.memory_usage().await.unwrap();
+        assert!(usage.cookies <= 10);
+        assert!(usage.profiles_bytes > 0 && usage.total_bytes >= usage.profiles_bytes);
+        assert_eq!(buys_of(0).await, 1);
+        assert_eq!(buys_of(10).await, 1);
+        assert_eq!(buys_of(1).await, 0);
+    }
+
+    #[tokio::test]
+    async fn cookie_limit_is_global() {
+        let system = super::System::with_shards(64).with_limits(Limits {
+            max_cookies: Some(2),
+            ..Default::default()
+        });
+        for cookie in 0..100 {
+            system
+                .register_user_tag(UserTag {
+                    time: moment_middle().into(),
+                    cookie: format!("cookie{}", cookie),
+                    ..default_tag()
+                })
+                .await;
+        }
+        assert!(system.memory_usage().await.unwrap().cookies <= 2);
+    }
+
+    /// Compares a single-shard system (equivalent to one global lock) with the
+    /// default sharding under concurrent ingestion and profile queries (10:1,
+    /// as in the spec). Run with:
+    /// $ cargo test --release mock::tests::bench_sharded_throughput -- --ignored --nocapture
+    #[ignore = "benchmark"]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn bench_sharded_throughput() {
+        const WORKERS: usize = 16;
+        const TAGS_PER_WORKER: usize = 20_000;
+
+        let mut throughputs = Vec::new();
+        for shards in [1, DEFAULT_SHARDS] {
+            let system = Arc::new(super::System::with_shards(shards));
+            let start = std::time::Instant::now();
+            let workers = (0..WORKERS).map(|worker| {
+                let system = Arc::clone(&system);
+                tokio::spawn(async move {
+                    for i in 0..TAGS_PER_WORKER {
+                        let cookie = format!("cookie{}", (worker * TAGS_PER_WORKER + i) % 10_000);
+                        let time = moment_middle() + chrono::Duration::milliseconds(i as i64);
+                        system
+                            .register_user_tag(UserTag {
+                                time: time.into(),
+                                cookie: cookie.clone(),
+                                ..default_tag()
+                            })
+                            .await;
+                        if i % 10 == 0 {
+                            system
+                                .last_tags_by_cookie(
+                                    &cookie,
+                                    time - chrono::Duration::minutes(1),
+                                    time,
+                                    200,
+                                )
+                                .await;
+                        }
+                    }
+                })
+            });
+            for worker in futures::future::join_all(workers).await {
+                worker.unwrap();
+            }
+            let elapsed = start.elapsed();
+            let throughput = (WORKERS * TAGS_PER_WORKER) as f64 / elapsed.as_secs_f64();
+            println!(
+                "{:>3} shard(s): {:>10.0} tags/s ({:?})",
+                shards, throughput, elapsed
+            );
+            throughputs.push(throughput);
+        }
+        println!("speedup: {:.2}x", throughputs[1] / throughputs[0]);
+    }
 }
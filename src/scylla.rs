@@ -1,35 +1,197 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use scylla::batch::{Batch, BatchStatement, BatchType};
+use scylla::cql_to_rust::{FromCqlVal, FromCqlValError};
 use scylla::frame::response::result::CqlValue;
-use scylla::frame::value::Counter;
+use scylla::frame::value::{Counter, SerializeValuesError, SerializedValues, Value, ValueTooBig};
 use scylla::macros::{FromUserType, IntoUserType};
 use scylla::prepared_statement::PreparedStatement;
+use scylla::transport::errors::QueryError;
 use scylla::IntoTypedRows;
-use tracing::{debug, trace};
-
-use crate::types::{Action, Bucket, UtcMinute};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::aggregates::{AggregateKey, Counters, Dimension, Dimensions, Filter, MinuteAggregates};
+use crate::dedup::{self, DedupWindow};
+use crate::streaming::{self, WindowedAggregator};
+use crate::types::{
+    Action, Bucket, DataScope, TagPosition, TagsPage, TimeRange, UtcMillis, UtcMinute,
+};
 use crate::{types, utils};
 
 pub struct Session {
-    session: scylla::Session,
+    session: Arc<scylla::Session>,
     // use case 1
     insert_user_tag: PreparedStatement,
+    /// Updates of all bucket tables, in their order.
     update_bucket_stats: Batch,
+    aggregator: Arc<WindowedAggregator>,
+    dedup: DedupWindow,
 
     // use case 2
     select_last_tags_by_cookie: PreparedStatement,
+    count_tags_by_cookie: PreparedStatement,
+    select_tags_by_cookie: PreparedStatement,
+    select_tags_by_cookie_before: PreparedStatement,
     delete_old_tags_by_cookie: PreparedStatement,
+    delete_tags_by_cookie_in_range: PreparedStatement,
 
     // use case 3
-    select_bucket_stats_all: PreparedStatement,
-    select_bucket_stats_origin: PreparedStatement,
-    select_bucket_stats_brand: PreparedStatement,
-    select_bucket_stats_category: PreparedStatement,
-    select_bucket_stats_origin_brand: PreparedStatement,
-    select_bucket_stats_origin_category: PreparedStatement,
-    select_bucket_stats_brand_category: PreparedStatement,
-    select_bucket_stats_origin_brand_category: PreparedStatement,
+    bucket_tables: Arc<[BucketTable]>,
+}
+
+/// Table of counters keyed by a chain of dimensions.
+///
+/// A query filtering on a set of dimensions is served by the table whose key
+/// starts with exactly those, summing over the rest of the key. The tables
+/// are the symmetric chains of subsets of the dimensions (de Bruijn et al.),
+/// so that each set is the start of the key of exactly one table, and there
+/// are as few tables as sets of half of the dimensions.
+struct BucketTable {
+    /// Positions of the clustering columns in the session's dimensions.
+    columns: Vec<usize>,
+    /// Queries filtering on fewer columns are served by other tables.
+    min_filtered: usize,
+    update: PreparedStatement,
+    /// Sums filtering on `min_filtered` and more of the columns.
+    selects: Vec<PreparedStatement>,
+    delete: PreparedStatement,
+    truncate: String,
+}
+
+/// Keys of the bucket tables for `dimensions` dimensions, with the number
+/// of key columns their queries filter on at least.
+fn bucket_chains(dimensions: usize) -> Vec<(Vec<usize>, usize)> {
+    let mut chains = vec![(Vec::new(), 0)];
+    for dimension in 0..dimensions {
+        chains = chains
+            .into_iter()
+            .flat_map(|(columns, min_filtered)| {
+                // Sets branching off the chain before its last column.
+                let branch = (columns.len() > min_filtered).then(|| {
+                    let mut branch = columns[..min_filtered].to_vec();
+                    branch.push(dimension);
+                    branch.extend_from_slice(&columns[min_filtered..columns.len() - 1]);
+                    (branch, min_filtered + 1)
+                });
+                let mut extended = columns;
+                extended.push(dimension);
+                std::iter::once((extended, min_filtered)).chain(branch)
+            })
+            .collect();
+    }
+    chains
+}
+
+fn column(dimension: &Dimension) -> String {
+    match dimension {
+        Dimension::Attribute(name) => format!("attr_{}", name),
+        dimension => dimension.to_string(),
+    }
+}
+
+impl BucketTable {
+    /// Creates (and empties) the table and prepares its statements.
+    async fn prepare(
+        session: &scylla::Session,
+        dimensions: &Dimensions,
+        columns: Vec<usize>,
+        min_filtered: usize,
+    ) -> Self {
+        let names = columns
+            .iter()
+            .map(|position| column(dimensions.iter().nth(*position).unwrap()))
+            .collect::<Vec<_>>();
+        // Names of dimensions may be too long for table names.
+        let table = format!(
+            "buckets_{:016x}",
+            utils::stable_hash(names.join(",").as_bytes())
+        );
+        let definitions = names
+            .iter()
+            .map(|name| format!("{} text, ", name))
+            .collect::<String>();
+        let key = names
+            .iter()
+            .map(|name| format!(", {}", name))
+            .collect::<String>();
+        // TODO: as TTL is not applicable to counter columns, add a task that deletes old entries each hour
+        session
+            .query(format!("CREATE TABLE IF NOT EXISTS {} (bucket timestamp, action text, {}count counter, sum counter, PRIMARY KEY((bucket, action){}))", table, definitions, key), ())
+            .await
+            .unwrap();
+        let truncate = format!("TRUNCATE TABLE {}", table);
+        session.query(truncate.as_str(), ()).await.unwrap();
+
+        let conditions = |filtered: usize| {
+            names[..filtered]
+                .iter()
+                .map(|name| format!(" AND {} = ?", name))
+                .collect::<String>()
+        };
+        let mut selects = Vec::new();
+        for filtered in min_filtered..=names.len() {
+            selects.push(
+                session
+                    .prepare(format!(
+                        "SELECT SUM(count), SUM(sum) FROM {} WHERE bucket = ? AND action = ?{}",
+                        table,
+                        conditions(filtered)
+                    ))
+                    .await
+                    .expect("Failed to prepare select_bucket_stats"),
+            );
+        }
+        Self {
+            update: session
+                .prepare(format!(
+                    "UPDATE {} SET count = count + ?, sum = sum + ? WHERE bucket = ? AND action = ?{}",
+                    table,
+                    conditions(names.len())
+                ))
+                .await
+                .expect("Failed to prepare update_bucket_stats"),
+            selects,
+            delete: session
+                .prepare(format!(
+                    "DELETE FROM {} WHERE bucket = ? AND action = ?",
+                    table
+                ))
+                .await
+                .expect("Failed to prepare delete_buckets"),
+            truncate,
+            columns,
+            min_filtered,
+        }
+    }
+
+    /// The select summing over the filtered dimensions, if the table serves them.
+    fn select<V>(&self, filtered: &HashMap<usize, V>) -> Option<&PreparedStatement> {
+        let count = filtered.len();
+        (self.min_filtered <= count
+            && count <= self.columns.len()
+            && self.columns[..count]
+                .iter()
+                .all(|column| filtered.contains_key(column)))
+        .then(|| &self.selects[count - self.min_filtered])
+    }
+}
+
+// Tag times have the precision of `timestamp`, so they are stored as they are.
+impl Value for UtcMillis {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        self.inner().serialize(buf)
+    }
+}
+
+impl FromCqlVal<CqlValue> for UtcMillis {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
+        DateTime::<Utc>::from_cql(cql_val).map(UtcMillis::from)
+    }
 }
 
 #[derive(FromUserType, IntoUserType, Debug)]
@@ -46,6 +208,8 @@ struct UserTag {
     pub device: String,
     pub origin: String,
     pub product_info: ProductInfo,
+    /// Missing in tags stored before the field was added.
+    pub attributes: Option<BTreeMap<String, String>>,
 }
 
 impl UserTag {
@@ -60,13 +224,14 @@ impl UserTag {
                 category_id: user_tag.product_info.category_id,
                 price: user_tag.product_info.price,
             },
+            attributes: (!user_tag.attributes.is_empty()).then_some(user_tag.attributes),
         })
     }
 
     pub fn into_user_tag(
         self,
         cookie: String,
-        time: DateTime<Utc>,
+        time: UtcMillis,
         action: String,
     ) -> Result<types::UserTag, serde_json::Error> {
         Ok(types::UserTag {
@@ -82,6 +247,7 @@ impl UserTag {
                 category_id: self.product_info.category_id,
                 price: self.product_info.price,
             },
+            attributes: self.attributes.unwrap_or_default(),
         })
     }
 }
@@ -97,31 +263,49 @@ impl Session {
             )
             .await
             .unwrap();
-        session.query("CREATE TYPE IF NOT EXISTS user_tag (country text, device text, origin text, product_info frozen<product_info>)", ()).await.unwrap();
-        session.query("CREATE TABLE IF NOT EXISTS user_tags (cookie text, action text, time timestamp, tag frozen<user_tag>, PRIMARY KEY ((cookie, action), time)) WITH CLUSTERING ORDER BY (time DESC)", ()).await.unwrap();
-        session
-            .query("TRUNCATE TABLE user_tags", &[])
-            .await
-            .unwrap();
-        // TODO: as TTL is not applicable to counter columns, add a task that deletes old entries each hour
-        session.query("CREATE TABLE IF NOT EXISTS buckets_obc (bucket timestamp, action text, origin text, brand_id text, category_id text, count counter, sum counter, PRIMARY KEY((bucket, action), origin, brand_id, category_id))", ()).await.unwrap();
-        session
-            .query("TRUNCATE TABLE buckets_obc", &[])
+        session.query("CREATE TYPE IF NOT EXISTS user_tag (country text, device text, origin text, product_info frozen<product_info>, attributes frozen<map<text, text>>)", ()).await.unwrap();
+        let (fields,) = session
+            .query("SELECT field_names FROM system_schema.types WHERE keyspace_name = 'allezon' AND type_name = 'user_tag'", ())
             .await
+            .unwrap()
+            .single_row_typed::<(Vec<String>,)>()
             .unwrap();
-        session.query("CREATE TABLE IF NOT EXISTS buckets_co (bucket timestamp, action text, origin text,  category_id text, count counter, sum counter, PRIMARY KEY((bucket, action), category_id, origin))", ()).await.unwrap();
-        session
-            .query("TRUNCATE TABLE buckets_co", &[])
+        if !fields.iter().any(|field| field == "attributes") {
+            session
+                .query(
+                    "ALTER TYPE user_tag ADD attributes frozen<map<text, text>>",
+                    (),
+                )
+                .await
+                .unwrap();
+        }
+        // Tables created before tags got a tie breaker have a different primary key,
+        // which cannot be altered. Their contents would be truncated below anyway.
+        let has_tie_breaker = session
+            .query("SELECT column_name FROM system_schema.columns WHERE keyspace_name = 'allezon' AND table_name = 'user_tags' AND column_name = 'tie_breaker'", ())
             .await
-            .unwrap();
-        session.query("CREATE TABLE IF NOT EXISTS buckets_bc (bucket timestamp, action text, brand_id text, category_id text, count counter, sum counter, PRIMARY KEY((bucket, action), brand_id, category_id))", ()).await.unwrap();
+            .unwrap()
+            .rows
+            .is_some_and(|rows| !rows.is_empty());
+        if !has_tie_breaker {
+            session
+                .query("DROP TABLE IF EXISTS user_tags", ())
+                .await
+                .unwrap();
+        }
+        session.query("CREATE TABLE IF NOT EXISTS user_tags (cookie text, action text, time timestamp, tie_breaker bigint, tag frozen<user_tag>, PRIMARY KEY ((cookie, action), time, tie_breaker)) WITH CLUSTERING ORDER BY (time DESC, tie_breaker DESC)", ()).await.unwrap();
         session
-            .query("TRUNCATE TABLE buckets_bc", &[])
+            .query("TRUNCATE TABLE user_tags", &[])
             .await
             .unwrap();
     }
 
-    pub async fn new(uri: &str) -> Self {
+    pub async fn new(
+        uri: &str,
+        streaming_config: streaming::Config,
+        dedup_config: dedup::Config,
+        dimensions: Dimensions,
+    ) -> Self {
         let session = scylla::SessionBuilder::new()
             .known_node(uri)
             .build()
@@ -129,198 +313,186 @@ impl Session {
             .expect("Failed to create Scylla session");
 
         Self::prepare(&session).await;
+        let flush_interval = streaming_config.flush_interval;
+        let mut bucket_tables = Vec::new();
+        for (columns, min_filtered) in bucket_chains(dimensions.len()) {
+            bucket_tables
+                .push(BucketTable::prepare(&session, &dimensions, columns, min_filtered).await);
+        }
 
-        Self {
+        let this = Self {
             insert_user_tag: session
-                .prepare("INSERT INTO user_tags (cookie, action, time, tag) VALUES (?, ?, ?, ?)")
+                .prepare("INSERT INTO user_tags (cookie, action, time, tie_breaker, tag) VALUES (?, ?, ?, ?, ?)")
                 .await
                 .expect("Failed to prepare insert_user_tag"),
             select_last_tags_by_cookie: session
-                .prepare("SELECT time, tag FROM user_tags WHERE cookie = ? AND action = ? AND time >= ? AND time <= ? ORDER BY time DESC LIMIT 200")
+                .prepare("SELECT time, tag FROM user_tags WHERE cookie = ? AND action = ? AND time >= ? AND time <= ? ORDER BY time DESC, tie_breaker DESC LIMIT 200")
                 .await
                 .expect("Failed to prepare select_last_tags_by_cookie"),
-            delete_old_tags_by_cookie: session
-                .prepare("DELETE FROM user_tags WHERE cookie = ? AND action = ? AND time < ?")
-                .await
-                .expect("Failed to prepare delete_old_tags_by_cookie"),
-
-            select_bucket_stats_all: session
-                .prepare("SELECT SUM(count), SUM(sum) FROM buckets_bc WHERE bucket = ? AND action = ?")
-                .await
-                .expect("Failed to prepare select_bucket_stats_all"),
-            select_bucket_stats_origin: session
-                .prepare("SELECT SUM(count), SUM(sum) FROM buckets_obc WHERE bucket = ? AND action = ? AND origin = ?")
+            count_tags_by_cookie: session
+                .prepare("SELECT COUNT(*) FROM user_tags WHERE cookie = ? AND action = ? AND time >= ? AND time <= ?")
                 .await
-                .expect("Failed to prepare select_bucket_stats_origin"),
-            select_bucket_stats_brand: session
-                .prepare("SELECT SUM(count), SUM(sum) FROM buckets_bc WHERE bucket = ? AND action = ? AND brand_id = ?")
+                .expect("Failed to prepare count_tags_by_cookie"),
+            select_tags_by_cookie: session
+                .prepare("SELECT time, tie_breaker, tag FROM user_tags WHERE cookie = ? AND action = ? AND time >= ? AND time <= ?")
                 .await
-                .expect("Failed to prepare select_bucket_stats_brand"),
-            select_bucket_stats_category: session
-                .prepare("SELECT SUM(count), SUM(sum) FROM buckets_co WHERE bucket = ? AND action = ? AND category_id = ?")
+                .expect("Failed to prepare select_tags_by_cookie"),
+            // Multi-column relations, as they cannot be mixed with single-column ones.
+            select_tags_by_cookie_before: session
+                .prepare("SELECT time, tie_breaker, tag FROM user_tags WHERE cookie = ? AND action = ? AND (time, tie_breaker) < (?, ?) AND (time) >= (?)")
                 .await
-                .expect("Failed to prepare select_bucket_stats_category"),
-            select_bucket_stats_origin_brand: session
-                .prepare("SELECT SUM(count), SUM(sum) FROM buckets_obc WHERE bucket = ? AND action = ? AND origin = ? AND brand_id = ?")
-                .await
-                .expect("Failed to prepare select_bucket_stats_origin_brand"),
-            select_bucket_stats_origin_category: session
-                .prepare("SELECT count, sum FROM buckets_co WHERE bucket = ? AND action = ? AND origin = ? AND category_id = ?")
-                .await
-                .expect("Failed to prepare select_bucket_stats_origin_category"),
-            select_bucket_stats_brand_category: session
-                .prepare("SELECT count, sum FROM buckets_bc WHERE bucket = ? AND action = ? AND brand_id = ? AND category_id = ?")
+                .expect("Failed to prepare select_tags_by_cookie_before"),
+            delete_old_tags_by_cookie: session
+                .prepare("DELETE FROM user_tags WHERE cookie = ? AND action = ? AND time < ?")
                 .await
-                .expect("Failed to prepare select_bucket_stats_brand_category"),
-            select_bucket_stats_origin_brand_category: session
-                .prepare("SELECT count, sum FROM buckets_obc WHERE bucket = ? AND action = ? AND origin = ? AND brand_id = ? AND category_id = ?")
+                .expect("Failed to prepare delete_old_tags_by_cookie"),
+            delete_tags_by_cookie_in_range: session
+                .prepare("DELETE FROM user_tags WHERE cookie = ? AND action = ? AND time >= ? AND time < ?")
                 .await
-                .expect("Failed to prepare select_bucket_stats_origin_brand_category"),
+                .expect("Failed to prepare delete_tags_by_cookie_in_range"),
+
+            update_bucket_stats: Batch::new_with_statements(
+                BatchType::Counter,
+                bucket_tables
+                    .iter()
+                    .map(|table| BatchStatement::PreparedStatement(table.update.clone()))
+                    .collect(),
+            ),
+            bucket_tables: bucket_tables.into(),
+
+            session: Arc::new(session),
+            aggregator: Arc::new(WindowedAggregator::new(streaming_config, dimensions)),
+            dedup: DedupWindow::new(dedup_config),
+        };
+        this.spawn_periodic_flush(flush_interval);
+        this
+    }
 
-            update_bucket_stats: {
-                Batch::new_with_statements(BatchType::Counter, [
-                    session
-                        .prepare("UPDATE buckets_obc SET count = count + 1, sum = sum + ? WHERE bucket = ? AND action = ? AND origin = ? AND brand_id = ? AND category_id = ?")
-                        .await
-                        .expect("Failed to prepare update_bucket_stats_obc"),
-                    session
-                        .prepare("UPDATE buckets_co SET count = count + 1, sum = sum + ? WHERE bucket = ? AND action = ? AND origin = ? AND category_id = ?")
-                        .await
-                        .expect("Failed to prepare update_bucket_stats_co"),
-                    session
-                        .prepare("UPDATE buckets_bc SET count = count + 1, sum = sum + ? WHERE bucket = ? AND action = ? AND brand_id = ? AND category_id = ?")
-                        .await
-                        .expect("Failed to prepare update_bucket_stats_bc"),
-                ].into_iter().map(BatchStatement::PreparedStatement).collect())
-            },
+    fn flusher(&self) -> Flusher {
+        Flusher {
+            session: Arc::clone(&self.session),
+            batch: self.update_bucket_stats.clone(),
+            tables: Arc::clone(&self.bucket_tables),
+            aggregator: Arc::clone(&self.aggregator),
+        }
+    }
+
+    /// Persists closed minutes in the background.
+    fn spawn_flush(&self, closed: Vec<(UtcMinute, MinuteAggregates)>) {
+        let flusher = self.flusher();
+        tokio::spawn(async move { flusher.flush(closed).await });
+    }
 
+    /// Looks for closed minutes every `interval`, so that they get persisted
+    /// even when no tags arrive. Stops once the session is dropped.
+    fn spawn_periodic_flush(&self, interval: Duration) {
+        let Flusher {
             session,
+            batch,
+            tables,
+            aggregator,
+        } = self.flusher();
+        let aggregator = Arc::downgrade(&aggregator);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let Some(aggregator) = aggregator.upgrade() else {
+                    break;
+                };
+                let closed = aggregator.take_closed();
+                let flusher = Flusher {
+                    session: Arc::clone(&session),
+                    batch: batch.clone(),
+                    tables: Arc::clone(&tables),
+                    aggregator,
+                };
+                flusher.flush(closed).await;
+            }
+        });
+    }
 
+    /// Persists all minutes held in memory, waiting for the flushes already
+    /// in progress. To be called on shutdown.
+    pub async fn flush_all(&self) {
+        self.flusher().flush(self.aggregator.take_all()).await;
+        while !self.aggregator.is_flushed() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        info!("Flushed all bucket stats");
     }
 
-    async fn update_bucket_stats(
-        &self,
-        bucket: UtcMinute,
-        action: Action,
-        origin: &str,
-        brand_id: &str,
-        category_id: &str,
-        price: i64,
-    ) {
-        debug!("Updating bucket stats for bucket {}", bucket);
-        self.session
-            .batch(
-                &self.update_bucket_stats,
-                (
-                    // obc
-                    (
-                        price,
-                        bucket.inner(),
-                        action.to_string(),
-                        origin,
-                        brand_id,
-                        category_id,
-                    ),
-                    // co
-                    (
-                        price,
-                        bucket.inner(),
-                        action.to_string(),
-                        category_id,
-                        origin,
-                    ),
-                    // bc
-                    (
-                        price,
-                        bucket.inner(),
-                        action.to_string(),
-                        brand_id,
-                        category_id,
-                    ),
-                ),
-            )
+    async fn clear_tags_in_range(&self, TimeRange { from, to }: TimeRange) {
+        // Partitions are keyed by cookie, so the only way to reach them all is a full scan.
+        let mut partitions = self
+            .session
+            .query_iter("SELECT DISTINCT cookie, action FROM user_tags", ())
             .await
-            .unwrap();
+            .expect("Failed to list user tags partitions")
+            .into_typed::<(String, String)>();
+
+        while let Some(partition) = partitions.next().await {
+            let (cookie, action) = partition.expect("Failed to get user tags partition");
+            self.session
+                .execute(
+                    &self.delete_tags_by_cookie_in_range,
+                    (cookie, action, from, to),
+                )
+                .await
+                .expect("Failed to delete user tags in range");
+        }
+    }
+
+    /// Deleted counters cannot be incremented again, so the minutes stay
+    /// empty until the buckets are truncated by a clear without a range.
+    async fn clear_buckets_in_range(&self, TimeRange { from, to }: TimeRange) {
+        let minutes = std::iter::successors(Some(UtcMinute::from(from)), |last| Some(last.next()))
+            .take_while(|minute| minute.inner() < to);
+        let deletes = minutes
+            .flat_map(|minute| {
+                [Action::View, Action::Buy]
+                    .into_iter()
+                    .map(move |action| (minute, action))
+            })
+            .flat_map(|(minute, action)| {
+                self.bucket_tables.iter().map(move |table| {
+                    self.session
+                        .execute(&table.delete, (minute.inner(), action.to_string()))
+                })
+            });
+        for result in futures::future::join_all(deletes).await {
+            result.expect("Failed to delete buckets in range");
+        }
     }
 
+    /// Persisted counters of the minute from the table serving the filter,
+    /// or `None` if the filter is on dimensions which are not kept.
     async fn select_bucket_stats_impl(
         &self,
         bucket: DateTime<Utc>,
-        action: Action,
-        origin: Option<&str>,
-        brand_id: Option<&str>,
-        category_id: Option<&str>,
-    ) -> Bucket {
-        let query_result = match (origin, brand_id, category_id) {
-            (None, None, None) => {
-                self.session
-                    .execute(&self.select_bucket_stats_all, (bucket, action.to_string()))
-                    .await
-            }
-            (Some(origin), None, None) => {
-                self.session
-                    .execute(
-                        &self.select_bucket_stats_origin,
-                        (bucket, action.to_string(), origin),
-                    )
-                    .await
-            }
-            (None, Some(brand_id), None) => {
-                self.session
-                    .execute(
-                        &self.select_bucket_stats_brand,
-                        (bucket, action.to_string(), brand_id),
-                    )
-                    .await
-            }
-            (None, None, Some(category_id)) => {
-                self.session
-                    .execute(
-                        &self.select_bucket_stats_category,
-                        (bucket, action.to_string(), category_id),
-                    )
-                    .await
-            }
-            (Some(origin), Some(brand_id), None) => {
-                self.session
-                    .execute(
-                        &self.select_bucket_stats_origin_brand,
-                        (bucket, action.to_string(), origin, brand_id),
-                    )
-                    .await
-            }
-            (Some(origin), None, Some(category_id)) => {
-                self.session
-                    .execute(
-                        &self.select_bucket_stats_origin_category,
-                        (bucket, action.to_string(), origin, category_id),
-                    )
-                    .await
-            }
-            (None, Some(brand_id), Some(category_id)) => {
-                self.session
-                    .execute(
-                        &self.select_bucket_stats_brand_category,
-                        (bucket, action.to_string(), brand_id, category_id),
-                    )
-                    .await
-            }
-            (Some(origin), Some(brand_id), Some(category_id)) => {
-                self.session
-                    .execute(
-                        &self.select_bucket_stats_origin_brand_category,
-                        (bucket, action.to_string(), origin, brand_id, category_id),
-                    )
-                    .await
-            }
+        filter: &Filter,
+    ) -> Option<Counters> {
+        let dimensions = self.aggregator.dimensions();
+        let filtered = filter
+            .values
+            .iter()
+            .map(|(dimension, value)| Some((dimensions.position(dimension)?, value)))
+            .collect::<Option<HashMap<_, _>>>()?;
+        let (table, select) = self
+            .bucket_tables
+            .iter()
+            .find_map(|table| Some((table, table.select(&filtered)?)))
+            .expect("Every set of dimensions has a bucket table");
+
+        let mut values = SerializedValues::new();
+        values.add_value(&bucket).unwrap();
+        values.add_value(&filter.action.to_string()).unwrap();
+        for column in &table.columns[..filtered.len()] {
+            values.add_value(filtered[column]).unwrap();
         }
-        .unwrap();
-
+        let query_result = self.session.execute(select, values).await.unwrap();
         trace!("Got bucket rows: {:#?}, ", query_result.rows);
 
-        // Ugly as hell, but lets us preserve unified match above
-        // (no differentiating between Counter and BigInt returned).
         let row = query_result.first_row().unwrap();
         let mut cols_iter = row.columns.into_iter();
         let count_cql = cols_iter.next().unwrap().unwrap();
@@ -336,10 +508,83 @@ impl Session {
             (count_cql, sum_cql) => panic!("Unexpected CqlVal: ({:?}, {:?})", count_cql, sum_cql),
         };
 
-        Bucket {
-            minute: bucket.into(),
-            count: count as i32,
-            sum_price: sum as i32,
+        Some(Counters {
+            count,
+            sum_price: sum,
+        })
+    }
+}
+
+async fn update_bucket_stats(
+    session: &scylla::Session,
+    batch: &Batch,
+    tables: &[BucketTable],
+    bucket: UtcMinute,
+    key: &AggregateKey,
+    counters: Counters,
+) -> Result<(), QueryError> {
+    trace!("Updating bucket stats for bucket {}", bucket);
+    let Counters { count, sum_price } = counters;
+    let values = tables
+        .iter()
+        .map(|table| {
+            let mut values = SerializedValues::new();
+            values.add_value(&count)?;
+            values.add_value(&sum_price)?;
+            values.add_value(&bucket.inner())?;
+            values.add_value(&key.action.to_string())?;
+            for column in &table.columns {
+                values.add_value(&key.values[*column])?;
+            }
+            Ok(values)
+        })
+        .collect::<Result<Vec<_>, SerializeValuesError>>()?;
+    session.batch(batch, values).await?;
+    Ok(())
+}
+
+/// What it takes to persist closed minutes, apart from the session.
+struct Flusher {
+    session: Arc<scylla::Session>,
+    batch: Batch,
+    tables: Arc<[BucketTable]>,
+    aggregator: Arc<WindowedAggregator>,
+}
+
+impl Flusher {
+    /// Adds the counters to the bucket tables, retrying failed updates
+    /// until all of them are added.
+    async fn flush(&self, closed: Vec<(UtcMinute, MinuteAggregates)>) {
+        for (minute, aggregates) in closed {
+            let mut pending = aggregates.iter().collect::<Vec<_>>();
+            while !pending.is_empty() {
+                let results = futures::future::join_all(pending.iter().map(|(key, counters)| {
+                    update_bucket_stats(
+                        &self.session,
+                        &self.batch,
+                        &self.tables,
+                        minute,
+                        key,
+                        **counters,
+                    )
+                }))
+                .await;
+                let mut failed = Vec::new();
+                for ((key, counters), result) in pending.into_iter().zip(results) {
+                    match result {
+                        Ok(()) => self.aggregator.persisted(minute, key),
+                        Err(err) => {
+                            error!("Failed to flush bucket stats for {}: {}", minute, err);
+                            failed.push((key, counters));
+                        }
+                    }
+                }
+                pending = failed;
+                if !pending.is_empty() {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+            debug!("Flushed {} bucket stats for {}", aggregates.len(), minute);
         }
     }
 }
@@ -349,30 +594,56 @@ impl types::System for Session {
     async fn register_user_tag(&self, user_tag: types::UserTag) {
         let user_tag_time = user_tag.time;
         let user_tag_cookie = user_tag.cookie.clone();
+        let user_tag_tie_breaker = user_tag.tie_breaker();
         let user_tag_action =
             serde_json::to_string(&user_tag.action).expect("Failed to serialize user tag action");
 
-        self.update_bucket_stats(
-            user_tag_time.into(),
-            user_tag.action,
-            &user_tag.origin,
-            &user_tag.product_info.brand_id,
-            &user_tag.product_info.category_id,
-            user_tag.product_info.price as i64,
-        )
-        .await;
+        if !self.aggregator.register(&user_tag) {
+            // The minute has already been flushed, so the tag is added on its own.
+            update_bucket_stats(
+                &self.session,
+                &self.update_bucket_stats,
+                &self.bucket_tables,
+                user_tag_time.into(),
+                &AggregateKey::of(&user_tag, self.aggregator.dimensions()),
+                Counters {
+                    count: 1,
+                    sum_price: user_tag.product_info.price as i64,
+                },
+            )
+            .await
+            .expect("Failed to update bucket stats");
+        }
+        let closed = self.aggregator.take_closed();
+        if !closed.is_empty() {
+            self.spawn_flush(closed);
+        }
 
         let db_user_tag = UserTag::new(user_tag).expect("Failed to create UserTag");
 
         self.session
             .execute(
                 &self.insert_user_tag,
-                (user_tag_cookie, user_tag_action, user_tag_time, db_user_tag),
+                (
+                    user_tag_cookie,
+                    user_tag_action,
+                    user_tag_time,
+                    user_tag_tie_breaker,
+                    db_user_tag,
+                ),
             )
             .await
             .expect("Failed to insert user tag");
     }
 
+    fn dimensions(&self) -> &Dimensions {
+        self.aggregator.dimensions()
+    }
+
+    fn dedup_window(&self) -> Option<&DedupWindow> {
+        Some(&self.dedup)
+    }
+
     async fn last_tags_by_cookie<'a>(
         &'a self,
         cookie: &'a str,
@@ -393,7 +664,7 @@ impl types::System for Session {
                 .expect("Failed to select last tags by cookie")
                 .rows
                 .map(|rows| {
-                    rows.into_typed::<(DateTime<Utc>, UserTag)>()
+                    rows.into_typed::<(UtcMillis, UserTag)>()
                         .map(|result| {
                             let (time, user_tag) = result.expect("Failed to get user tag");
                             user_tag
@@ -404,7 +675,9 @@ impl types::System for Session {
                 })
                 .unwrap_or_default();
 
-            if let Some(oldest) = user_tags.last() {
+            // With 200 newer tags in the range, older ones are beyond the cap
+            // of a profile; fewer tell nothing about tags outside of the range.
+            if let Some(oldest) = user_tags.last().filter(|_| user_tags.len() == 200) {
                 self.session
                     .execute(
                         &self.delete_old_tags_by_cookie,
@@ -427,30 +700,186 @@ impl types::System for Session {
         profile
     }
 
+    async fn tags_page(
+        &self,
+        cookie: &str,
+        action: Action,
+        time_range: TimeRange,
+        after: Option<TagPosition>,
+        limit: usize,
+    ) -> TagsPage {
+        let action_string = serde_json::to_string(&action).unwrap();
+
+        // Counting scans the whole range, so it is done for the first page only.
+        let total = match after {
+            Some(_) => None,
+            None => {
+                let (total,) = self
+                    .session
+                    .execute(
+                        &self.count_tags_by_cookie,
+                        (
+                            cookie,
+                            action_string.clone(),
+                            time_range.from,
+                            time_range.to,
+                        ),
+                    )
+                    .await
+                    .expect("Failed to count tags by cookie")
+                    .single_row_typed::<(i64,)>()
+                    .expect("Failed to get count of tags by cookie");
+                Some(total as usize)
+            }
+        };
+
+        // One more row than listed tells whether more tags follow.
+        let page_size = limit as i32 + 1;
+        let rows = match after.filter(|(time, _)| time.inner() <= time_range.to) {
+            Some((time, tie_breaker)) => {
+                let mut statement = self.select_tags_by_cookie_before.clone();
+                statement.set_page_size(page_size);
+                self.session
+                    .execute_iter(
+                        statement,
+                        (
+                            cookie,
+                            action_string.clone(),
+                            time,
+                            tie_breaker,
+                            time_range.from,
+                        ),
+                    )
+                    .await
+            }
+            // Tags in the time range are all older than a cursor past its end.
+            None => {
+                let mut statement = self.select_tags_by_cookie.clone();
+                statement.set_page_size(page_size);
+                self.session
+                    .execute_iter(
+                        statement,
+                        (
+                            cookie,
+                            action_string.clone(),
+                            time_range.from,
+                            time_range.to,
+                        ),
+                    )
+                    .await
+            }
+        }
+        .expect("Failed to select tags by cookie");
+        // Further pages of rows are fetched with their paging state as the stream is read.
+        let rows = rows
+            .into_typed::<(UtcMillis, i64, UserTag)>()
+            .take(limit + 1)
+            .map(|row| row.expect("Failed to get user tag"))
+            .collect::<Vec<_>>()
+            .await;
+
+        let more = rows.len() > limit;
+        let mut page = TagsPage {
+            total,
+            ..Default::default()
+        };
+        for (time, tie_breaker, user_tag) in rows.into_iter().take(limit) {
+            page.tags.push(
+                user_tag
+                    .into_user_tag(cookie.to_string(), time, action_string.clone())
+                    .expect("Failed to convert user tag"),
+            );
+            page.next = more.then_some((time, tie_breaker));
+        }
+        page
+    }
+
     async fn select_bucket_stats(
         &self,
         time_from: DateTime<Utc>,
         time_to: DateTime<Utc>,
-        action: Action,
-        origin: Option<&str>,
-        brand_id: Option<&str>,
-        category_id: Option<&str>,
+        filter: &Filter,
     ) -> Vec<Bucket> {
         let time_to = UtcMinute::from(time_to);
         let futures = std::iter::successors(Some(UtcMinute::from(time_from)), |last| {
             let next = last.next();
             (next < time_to).then_some(next)
         })
-        .map(|bucket| {
-            self.select_bucket_stats_impl(bucket.inner(), action, origin, brand_id, category_id)
+        .map(|bucket| async move {
+            // Counters not yet flushed are only known in memory, while late
+            // tags of the minute may have been persisted already.
+            let mut counters = self
+                .select_bucket_stats_impl(bucket.inner(), filter)
+                .await
+                .unwrap_or_default();
+            counters.add(self.aggregator.select(bucket, filter));
+            counters.into_bucket(bucket)
         });
         futures::future::join_all(futures).await
     }
 
-    async fn clear(&self) {
-        self.session
-            .query("TRUNCATE user_tags", ())
-            .await
-            .expect("Failed to clear user tags");
+    async fn clear(&self, scope: DataScope, time_range: Option<TimeRange>) {
+        if scope.includes_profiles() {
+            match time_range {
+                None => {
+                    self.session
+                        .query("TRUNCATE user_tags", ())
+                        .await
+                        .expect("Failed to clear user tags");
+                }
+                Some(time_range) => self.clear_tags_in_range(time_range).await,
+            }
+        }
+
+        if scope.includes_aggregates() {
+            self.aggregator.clear(time_range);
+            match time_range {
+                None => {
+                    // Counters start from scratch, so replayed events count again.
+                    self.dedup.clear();
+                    for table in self.bucket_tables.iter() {
+                        self.session
+                            .query(table.truncate.as_str(), ())
+                            .await
+                            .expect("Failed to clear buckets");
+                    }
+                }
+                Some(time_range) => {
+                    warn!(
+                        "Aggregates of {} are not counted anymore until they are cleared in whole",
+                        time_range
+                    );
+                    self.clear_buckets_in_range(time_range).await
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_set_of_dimensions_has_one_bucket_table() {
+        // The tables of the spec's dimensions: origin, brand_id, category_id.
+        assert_eq!(
+            bucket_chains(3),
+            [(vec![0, 1, 2], 0), (vec![2, 0], 1), (vec![1, 2], 1)]
+        );
+        for dimensions in 0..=6 {
+            let chains = bucket_chains(dimensions);
+            let mut served = vec![0; 1 << dimensions];
+            for (columns, min_filtered) in &chains {
+                for filtered in *min_filtered..=columns.len() {
+                    let set = columns[..filtered]
+                        .iter()
+                        .fold(0, |set, column| set | 1 << column);
+                    served[set] += 1;
+                }
+            }
+            assert!(served.iter().all(|tables| *tables == 1));
+        }
+        assert_eq!(bucket_chains(5).len(), 10);
     }
 }
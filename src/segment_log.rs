@@ -0,0 +1,274 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Append-only log of opaque records, split into segment files.
+///
+/// Every record gets a consecutive offset. Segments are named after the offset
+/// of their first record, so whole segments can be dropped once all of their
+/// records are no longer needed. Each record is framed as
+/// `[len: u32 LE][crc32: u32 LE][payload]`; a torn frame at the end of the last
+/// segment (e.g. after a crash) is truncated on [`SegmentLog::open`].
+#[derive(Debug)]
+pub struct SegmentLog {
+    dir: PathBuf,
+    max_segment_len: u64,
+    /// Base offsets of all segments, ascending. The last one is active.
+    segments: Vec<u64>,
+    active: File,
+    active_len: u64,
+    next_offset: u64,
+}
+
+fn segment_path(dir: &Path, base_offset: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", base_offset, SEGMENT_EXTENSION))
+}
+
+/// Reads all valid records of a segment. Returns them along with the length
+/// of the valid prefix of the file, which is shorter if the last frame is torn
+/// (or still being appended).
+fn read_segment(path: &Path) -> io::Result<(Vec<Vec<u8>>, u64)> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while contents.len() - pos >= FRAME_HEADER_LEN {
+        let len = u32::from_le_bytes(contents[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(contents[pos + 4..pos + 8].try_into().unwrap());
+        let start = pos + FRAME_HEADER_LEN;
+        if contents.len() - start < len || crc32fast::hash(&contents[start..start + len]) != crc {
+            break;
+        }
+        records.push(contents[start..start + len].to_vec());
+        pos = start + len;
+    }
+    Ok((records, pos as u64))
+}
+
+/// Segments of a [`SegmentLog`] as of some moment, so that their records can
+/// be read without holding the log.
+#[derive(Debug)]
+pub struct Segments {
+    dir: PathBuf,
+    /// Base offsets of the segments, ascending.
+    base_offsets: Vec<u64>,
+    /// Offset of the first record appended after the moment.
+    end: u64,
+}
+
+impl Segments {
+    /// Reads at most `max` records with offsets not lower than `from`.
+    pub fn read_range(&self, from: u64, max: usize) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let mut records = Vec::new();
+        for &base_offset in &self.base_offsets {
+            if records.len() >= max {
+                break;
+            }
+            let (segment_records, _) = read_segment(&segment_path(&self.dir, base_offset))?;
+            records.extend(
+                (base_offset..)
+                    .zip(segment_records)
+                    .filter(|(offset, _)| *offset >= from && *offset < self.end)
+                    .take(max - records.len()),
+            );
+        }
+        Ok(records)
+    }
+}
+
+impl SegmentLog {
+    pub fn open(dir: impl Into<PathBuf>, max_segment_len: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = fs::read_dir(&dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != SEGMENT_EXTENSION {
+                    return None;
+                }
+                path.file_stem()?.to_str()?.parse::<u64>().ok()
+            })
+            .collect::<Vec<_>>();
+        segments.sort_unstable();
+
+        let (next_offset, active_len) = match segments.last() {
+            Some(&base_offset) => {
+                let path = segment_path(&dir, base_offset);
+                let (records, valid_len) = read_segment(&path)?;
+                let len = fs::metadata(&path)?.len();
+                if valid_len != len {
+                    warn!(
+                        "Ignoring {} bytes of torn data at the end of {}",
+                        len - valid_len,
+                        path.display()
+                    );
+                }
+                // Drops a torn tail, so that new records are appended right after valid ones.
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid_len)?;
+                (base_offset + records.len() as u64, valid_len)
+            }
+            None => {
+                segments.push(0);
+                (0, 0)
+            }
+        };
+
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, *segments.last().unwrap()))?;
+
+        Ok(Self {
+            dir,
+            max_segment_len,
+            segments,
+            active,
+            active_len,
+            next_offset,
+        })
+    }
+
+    /// Offset that the next appended record will get.
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// Appends a record and returns its offset. The record is handed over to
+    /// the OS, but is not guaranteed to be on disk until [`SegmentLog::sync`].
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<u64> {
+        if self.active_len >= self.max_segment_len {
+            self.roll()?;
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        frame.extend_from_slice(payload);
+        self.active.write_all(&frame)?;
+
+        self.active_len += frame.len() as u64;
+        let offset = self.next_offset;
+        self.next_offset += 1;
+        Ok(offset)
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.active.sync_data()
+    }
+
+    /// Handle to the active segment, for syncing it without holding the log.
+    /// Segments before it are synced when rolled.
+    pub fn active_segment(&self) -> io::Result<File> {
+        self.active.try_clone()
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        self.active.sync_data()?;
+        self.active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, self.next_offset))?;
+        self.segments.push(self.next_offset);
+        self.active_len = 0;
+        Ok(())
+    }
+
+    /// Reads all records with offsets not lower than `from`.
+    pub fn read_from(&self, from: u64) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        self.read_range(from, usize::MAX)
+    }
+
+    /// Reads at most `max` records with offsets not lower than `from`.
+    pub fn read_range(&self, from: u64, max: usize) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        self.segments_from(from).read_range(from, max)
+    }
+
+    /// Segments holding records with offsets not lower than `from`.
+    pub fn segments_from(&self, from: u64) -> Segments {
+        let base_offsets = self
+            .segments
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.segments.get(i + 1).is_none_or(|&next| next > from))
+            .map(|(_, &base_offset)| base_offset)
+            .collect();
+        Segments {
+            dir: self.dir.clone(),
+            base_offsets,
+            end: self.next_offset,
+        }
+    }
+
+    /// Deletes segments which contain only records with offsets lower than `offset`.
+    /// The active segment is never deleted.
+    pub fn remove_before(&mut self, offset: u64) -> io::Result<()> {
+        while self.segments.len() > 1 && self.segments[1] <= offset {
+            fs::remove_file(segment_path(&self.dir, self.segments[0]))?;
+            self.segments.remove(0);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_survive_reopening_and_torn_tail_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut log = SegmentLog::open(dir.path(), 32).unwrap();
+            for i in 0..10u8 {
+                assert_eq!(log.append(&[i; 10]).unwrap(), i as u64);
+            }
+            log.sync().unwrap();
+        }
+
+        // Simulates a crash in the middle of writing a frame.
+        let last_segment = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .max()
+            .unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(last_segment)
+            .unwrap()
+            .write_all(&[42, 0, 0])
+            .unwrap();
+
+        let mut log = SegmentLog::open(dir.path(), 32).unwrap();
+        assert_eq!(log.next_offset(), 10);
+        assert_eq!(log.append(&[10; 10]).unwrap(), 10);
+
+        let records = log.read_from(4).unwrap();
+        assert_eq!(
+            records,
+            (4..=10u8)
+                .map(|i| (i as u64, vec![i; 10]))
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            log.read_range(2, 3).unwrap(),
+            (2..5u8)
+                .map(|i| (i as u64, vec![i; 10]))
+                .collect::<Vec<_>>()
+        );
+
+        log.remove_before(4).unwrap();
+        let first_kept = log.read_from(0).unwrap()[0].0;
+        assert!(first_kept > 0 && first_kept <= 4);
+    }
+}
@@ -0,0 +1,261 @@
+//! In-process aggregation of use case 3 counters in event-time windows.
+//!
+//! Tags are accumulated in per-minute [`MinuteAggregates`] while their minute
+//! is open. A minute gets closed once the watermark (the newest event time
+//! minus a configured delay) passes its end; closed minutes are handed over
+//! to be persisted once, and tags arriving for them later are reported as late.
+//! While no newer events arrive, the watermark follows the wall clock, so that
+//! minutes still get closed when the traffic stops.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+
+use crate::aggregates::{AggregateKey, Counters, Dimensions, Filter, MinuteAggregates};
+use crate::types::{TimeRange, UserTag, UtcMinute};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// How long after the end of a minute (in event time) its tags
+    /// are still accepted into memory.
+    pub watermark_delay: chrono::Duration,
+    /// How often closed minutes are looked for regardless of the traffic.
+    pub flush_interval: std::time::Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            watermark_delay: chrono::Duration::seconds(30),
+            flush_interval: std::time::Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    newest_event: Option<DateTime<Utc>>,
+    /// When the newest event was seen.
+    newest_event_seen: Option<Instant>,
+    /// All minutes before that have been closed.
+    closed_before: Option<UtcMinute>,
+    open: BTreeMap<UtcMinute, MinuteAggregates>,
+    /// Closed minutes which are not yet confirmed to be persisted.
+    flushing: BTreeMap<UtcMinute, MinuteAggregates>,
+}
+
+#[derive(Debug)]
+pub struct WindowedAggregator {
+    config: Config,
+    dimensions: Dimensions,
+    state: Mutex<State>,
+}
+
+impl WindowedAggregator {
+    pub fn new(config: Config, dimensions: Dimensions) -> Self {
+        Self {
+            config,
+            dimensions,
+            state: Default::default(),
+        }
+    }
+
+    pub fn dimensions(&self) -> &Dimensions {
+        &self.dimensions
+    }
+
+    /// Aggregates the tag in memory. Returns `false` if the tag is late,
+    /// i.e. its minute is already closed, so it must be persisted directly.
+    #[must_use]
+    pub fn register(&self, tag: &UserTag) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.newest_event < Some(tag.time.inner()) {
+            state.newest_event = Some(tag.time.inner());
+            state.newest_event_seen = Some(Instant::now());
+        }
+
+        let minute = UtcMinute::from(tag.time);
+        if state.closed_before.is_some_and(|closed| minute < closed) {
+            return false;
+        }
+        state
+            .open
+            .entry(minute)
+            .or_default()
+            .register(tag, &self.dimensions);
+        true
+    }
+
+    /// Closes all minutes which have ended before the watermark and returns
+    /// them for persisting. Each minute is returned only once; it keeps being
+    /// served from memory until [`WindowedAggregator::flushed`] is called.
+    pub fn take_closed(&self) -> Vec<(UtcMinute, MinuteAggregates)> {
+        let mut state = self.state.lock().unwrap();
+        let (Some(newest_event), Some(seen)) = (state.newest_event, state.newest_event_seen) else {
+            return Vec::new();
+        };
+        let idle = chrono::Duration::from_std(seen.elapsed()).unwrap_or(chrono::Duration::MAX);
+        let watermark = newest_event
+            .checked_add_signed(idle - self.config.watermark_delay)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        Self::close_before(&mut state, UtcMinute::from(watermark))
+    }
+
+    /// Closes all minutes, e.g. to persist them before shutting down.
+    pub fn take_all(&self) -> Vec<(UtcMinute, MinuteAggregates)> {
+        let mut state = self.state.lock().unwrap();
+        let Some(last) = state.open.last_key_value().map(|(minute, _)| *minute) else {
+            return Vec::new();
+        };
+        Self::close_before(&mut state, last.next())
+    }
+
+    fn close_before(state: &mut State, boundary: UtcMinute) -> Vec<(UtcMinute, MinuteAggregates)> {
+        if state.closed_before.is_some_and(|closed| closed >= boundary) {
+            return Vec::new();
+        }
+        state.closed_before = Some(boundary);
+
+        let still_open = state.open.split_off(&boundary);
+        let closed = std::mem::replace(&mut state.open, still_open);
+        state.flushing.extend(
+            closed
+                .iter()
+                .map(|(minute, aggregates)| (*minute, aggregates.clone())),
+        );
+        closed.into_iter().collect()
+    }
+
+    /// Marks counters of a minute returned by [`WindowedAggregator::take_closed`]
+    /// as persisted, so that they are no longer served from memory.
+    pub fn persisted(&self, minute: UtcMinute, key: &AggregateKey) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(aggregates) = state.flushing.get_mut(&minute) {
+            aggregates.remove(key);
+            if aggregates.is_empty() {
+                state.flushing.remove(&minute);
+            }
+        }
+    }
+
+    /// Whether all closed minutes have been persisted.
+    pub fn is_flushed(&self) -> bool {
+        self.state.lock().unwrap().flushing.is_empty()
+    }
+
+    /// Returns counters of the minute which are held in memory, i.e. not yet
+    /// persisted; they are to be added to the persisted ones.
+    pub fn select(&self, minute: UtcMinute, filter: &Filter) -> Counters {
+        let state = self.state.lock().unwrap();
+        let mut counters = Counters::default();
+        for aggregates in [state.open.get(&minute), state.flushing.get(&minute)]
+            .into_iter()
+            .flatten()
+        {
+            counters.add(aggregates.select(filter, &self.dimensions));
+        }
+        counters
+    }
+
+    /// Drops the aggregates of minutes overlapping `time_range`, or of all
+    /// minutes if it is not given.
+    pub fn clear(&self, time_range: Option<TimeRange>) {
+        let mut state = self.state.lock().unwrap();
+        match time_range {
+            None => *state = State::default(),
+            Some(TimeRange { from, to }) => {
+                let from = UtcMinute::from(from);
+                let in_range = |minute: &UtcMinute| from <= *minute && minute.inner() < to;
+                state.open.retain(|minute, _| !in_range(minute));
+                state.flushing.retain(|minute, _| !in_range(minute));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::tests::{default_tag, moment_middle};
+    use crate::types::Action;
+
+    use super::*;
+
+    fn filter() -> Filter {
+        Filter::new(Action::Buy)
+    }
+
+    fn tag_at(time: DateTime<Utc>) -> UserTag {
+        UserTag {
+            time: time.into(),
+            ..default_tag()
+        }
+    }
+
+    #[test]
+    fn minutes_are_closed_after_watermark_delay() {
+        let aggregator = WindowedAggregator::new(
+            Config {
+                watermark_delay: chrono::Duration::seconds(10),
+                ..Default::default()
+            },
+            Dimensions::default(),
+        );
+        let minute = UtcMinute::from(moment_middle());
+
+        assert!(aggregator.register(&tag_at(moment_middle())));
+        assert!(aggregator.take_closed().is_empty());
+        assert_eq!(aggregator.select(minute, &filter()).count, 1);
+
+        // Within the delay: the minute is still open.
+        assert!(aggregator.register(&tag_at(
+            minute.next().inner() + chrono::Duration::seconds(5)
+        )));
+        assert!(aggregator.take_closed().is_empty());
+        assert!(aggregator.register(&tag_at(moment_middle())));
+
+        assert!(aggregator.register(&tag_at(
+            minute.next().inner() + chrono::Duration::seconds(10)
+        )));
+        let closed = aggregator.take_closed();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].0, minute);
+        assert_eq!(
+            closed[0].1.select(&filter(), &Dimensions::default()).count,
+            2
+        );
+        assert!(aggregator.take_closed().is_empty());
+
+        // Served from memory until persisted; tags for it are late from now on.
+        assert_eq!(aggregator.select(minute, &filter()).count, 2);
+        assert!(!aggregator.register(&tag_at(moment_middle())));
+        for (key, _) in closed[0].1.iter() {
+            aggregator.persisted(minute, key);
+        }
+        assert!(aggregator.is_flushed());
+        assert_eq!(aggregator.select(minute, &filter()).count, 0);
+        assert_eq!(aggregator.select(minute.next(), &filter()).count, 2);
+    }
+
+    #[test]
+    fn clear_reopens_all_minutes() {
+        let aggregator = WindowedAggregator::new(Config::default(), Dimensions::default());
+        assert!(aggregator.register(&tag_at(moment_middle())));
+        assert!(aggregator.register(&tag_at(moment_middle() + chrono::Duration::hours(1))));
+        assert_eq!(aggregator.take_closed().len(), 1);
+
+        aggregator.clear(None);
+        assert!(aggregator.register(&tag_at(moment_middle())));
+    }
+
+    #[test]
+    fn take_all_closes_open_minutes() {
+        let aggregator = WindowedAggregator::new(Config::default(), Dimensions::default());
+        assert!(aggregator.register(&tag_at(moment_middle())));
+        assert_eq!(aggregator.take_all().len(), 1);
+        assert!(aggregator.take_all().is_empty());
+        assert!(!aggregator.is_flushed());
+        assert!(!aggregator.register(&tag_at(moment_middle())));
+    }
+}
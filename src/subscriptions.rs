@@ -0,0 +1,264 @@
+//! Subscriptions to tags of chosen cookies over a WebSocket.
+//!
+//! A subscriber follows the live feed of registered tags (see [`crate::live`])
+//! and forwards the tags of its cookies through a bounded outbox to a writer,
+//! which sends them to the socket. When the client does not keep up and the
+//! outbox is full, further tags are either dropped (and the client is told
+//! how many) or the client is disconnected, as chosen by its [`Overflow`].
+
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc::{self, error::TrySendError},
+};
+use tracing::log;
+use utoipa::ToSchema;
+
+use crate::types::UserTag;
+
+/// Maximum number of cookies a single client may subscribe to.
+pub const MAX_COOKIES: usize = 1000;
+
+/// Accepted capacities of the outbox of a subscriber.
+pub const BUFFERS: RangeInclusive<usize> = 1..=4096;
+pub const DEFAULT_BUFFER: usize = 256;
+
+/// Close code sent to subscribers disconnected for not keeping up
+/// ("Try Again Later").
+const SLOW_CONSUMER: u16 = 1013;
+
+/// What happens to tags for which there is no room in the outbox.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// They are dropped; a `dropped` message with their count follows
+    /// the next sent message.
+    #[default]
+    Drop,
+    /// The client is disconnected.
+    Disconnect,
+}
+
+/// Message sent by the client.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Subscribe { cookies: Vec<String> },
+    Unsubscribe { cookies: Vec<String> },
+}
+
+/// Message sent to the client.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// A tag of a subscribed cookie has been registered.
+    Tag { tag: UserTag },
+    /// Cookies subscribed to after a subscription change.
+    Subscribed { cookies: Vec<String> },
+    /// Number of tags dropped since the last such message. Tags skipped
+    /// while the server was behind the feed are counted whether they were
+    /// of subscribed cookies or not.
+    Dropped { count: u64 },
+    /// A client message could not be handled.
+    Error { message: String },
+}
+
+/// Tags waiting to be sent to a client.
+#[derive(Debug)]
+struct Outbox {
+    sender: mpsc::Sender<ServerMessage>,
+    dropped: Arc<AtomicU64>,
+    overflow: Overflow,
+}
+
+/// The client is to be disconnected, as it does not keep up.
+#[derive(Debug, PartialEq, Eq)]
+struct Overflowed;
+
+impl Outbox {
+    /// Queues the tag, or drops it if the outbox is full.
+    fn push(&self, tag: UserTag) -> Result<(), Overflowed> {
+        match self.sender.try_send(ServerMessage::Tag { tag }) {
+            Ok(()) | Err(TrySendError::Closed(_)) => Ok(()),
+            Err(TrySendError::Full(_)) => self.skip(1),
+        }
+    }
+
+    /// Accounts for `count` tags which could not be queued.
+    fn skip(&self, count: u64) -> Result<(), Overflowed> {
+        match self.overflow {
+            Overflow::Drop => {
+                self.dropped.fetch_add(count, Ordering::Relaxed);
+                Ok(())
+            }
+            Overflow::Disconnect => Err(Overflowed),
+        }
+    }
+}
+
+/// Serves a subscriber until it disconnects, or until it is disconnected.
+pub async fn serve(
+    socket: WebSocket,
+    tags: broadcast::Receiver<UserTag>,
+    buffer: usize,
+    overflow: Overflow,
+) {
+    let (sink, stream) = socket.split();
+    let (sender, receiver) = mpsc::channel(buffer);
+    let outbox = Outbox {
+        sender,
+        dropped: Default::default(),
+        overflow,
+    };
+    let (disconnect, disconnected) = mpsc::channel(1);
+    let writer = tokio::spawn(write(
+        sink,
+        receiver,
+        Arc::clone(&outbox.dropped),
+        disconnected,
+    ));
+    if let Err(Overflowed) = read(stream, tags, &outbox).await {
+        log::info!("Disconnecting a slow subscriber");
+        let _ = disconnect.try_send(());
+    }
+    drop(outbox);
+    let _ = writer.await;
+}
+
+/// Handles messages of the client and tags of the feed.
+async fn read(
+    mut stream: SplitStream<WebSocket>,
+    mut tags: broadcast::Receiver<UserTag>,
+    outbox: &Outbox,
+) -> Result<(), Overflowed> {
+    let mut cookies = BTreeSet::new();
+    loop {
+        tokio::select! {
+            message = stream.next() => {
+                let message = match message {
+                    Some(Ok(Message::Text(message))) => message,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+                    // Pings are answered by the socket itself.
+                    Some(Ok(_)) => continue,
+                };
+                let reply = match serde_json::from_str(&message) {
+                    Ok(ClientMessage::Subscribe { cookies: added }) => {
+                        let new = added.iter().filter(|cookie| !cookies.contains(*cookie)).count();
+                        if cookies.len() + new > MAX_COOKIES {
+                            ServerMessage::Error {
+                                message: format!("at most {} cookies may be subscribed to", MAX_COOKIES),
+                            }
+                        } else {
+                            cookies.extend(added);
+                            ServerMessage::Subscribed { cookies: cookies.iter().cloned().collect() }
+                        }
+                    }
+                    Ok(ClientMessage::Unsubscribe { cookies: removed }) => {
+                        for cookie in &removed {
+                            cookies.remove(cookie);
+                        }
+                        ServerMessage::Subscribed { cookies: cookies.iter().cloned().collect() }
+                    }
+                    Err(err) => ServerMessage::Error { message: err.to_string() },
+                };
+                if outbox.sender.send(reply).await.is_err() {
+                    return Ok(());
+                }
+            }
+            tag = tags.recv() => match tag {
+                Ok(tag) if cookies.contains(&tag.cookie) => outbox.push(tag)?,
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Subscriber skipped {} tags of the feed", skipped);
+                    outbox.skip(skipped)?;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Sends messages of the outbox to the client, until the reader is done or
+/// tells to disconnect.
+async fn write(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut outbox: mpsc::Receiver<ServerMessage>,
+    dropped: Arc<AtomicU64>,
+    mut disconnect: mpsc::Receiver<()>,
+) {
+    let text = |message: &ServerMessage| {
+        Message::Text(serde_json::to_string(message).expect("Serializing a message failed"))
+    };
+    loop {
+        let message = tokio::select! {
+            biased;
+            Some(()) = disconnect.recv() => {
+                let _ = sink
+                    .send(Message::Close(Some(CloseFrame {
+                        code: SLOW_CONSUMER,
+                        reason: "subscriber too slow".into(),
+                    })))
+                    .await;
+                return;
+            }
+            message = outbox.recv() => match message {
+                Some(message) => message,
+                None => return,
+            },
+        };
+        if sink.send(text(&message)).await.is_err() {
+            return;
+        }
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0
+            && sink
+                .send(text(&ServerMessage::Dropped { count }))
+                .await
+                .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::tests::default_tag;
+
+    use super::*;
+
+    fn outbox(overflow: Overflow) -> (Outbox, mpsc::Receiver<ServerMessage>) {
+        let (sender, receiver) = mpsc::channel(1);
+        let outbox = Outbox {
+            sender,
+            dropped: Default::default(),
+            overflow,
+        };
+        (outbox, receiver)
+    }
+
+    #[test]
+    fn full_outbox_drops_or_disconnects() {
+        let (dropping, _receiver) = outbox(Overflow::Drop);
+        assert_eq!(dropping.push(default_tag()), Ok(()));
+        assert_eq!(dropping.push(default_tag()), Ok(()));
+        assert_eq!(dropping.skip(3), Ok(()));
+        assert_eq!(dropping.dropped.load(Ordering::Relaxed), 4);
+
+        let (disconnecting, mut receiver) = outbox(Overflow::Disconnect);
+        assert_eq!(disconnecting.push(default_tag()), Ok(()));
+        assert_eq!(disconnecting.push(default_tag()), Err(Overflowed));
+        assert!(matches!(receiver.try_recv(), Ok(ServerMessage::Tag { .. })));
+        assert_eq!(disconnecting.push(default_tag()), Ok(()));
+    }
+}
@@ -54,15 +54,12 @@ impl DataSet {
             .action
             .unwrap_or_else(|| *self.actions.choose(rng).unwrap());
         let time = config.time.unwrap_or_else(Utc::now);
-        let correct_time = DateTime::<Utc>::from_utc(
-            time.naive_utc()
-                .with_nanosecond(time.nanosecond() - time.nanosecond() % 1_000_000)
-                .unwrap(),
-            Utc,
-        );
+        let correct_time = time
+            .with_nanosecond(time.nanosecond() - time.nanosecond() % 1_000_000)
+            .unwrap();
 
         types::UserTag {
-            time: correct_time,
+            time: correct_time.into(),
             cookie,
             country: self.countries.choose(rng).unwrap().clone(),
             device: *self.devices.choose(rng).unwrap(),
@@ -74,6 +71,7 @@ impl DataSet {
                 category_id: self.categories.choose(rng).unwrap().clone(),
                 price: rng.gen_range(0..1000),
             },
+            attributes: Default::default(),
         }
     }
 }
@@ -5,11 +5,13 @@ use chrono::Utc;
 use pretty_assertions::assert_eq;
 
 use super::dataset;
+use crate::aggregates::{Dimension, Filter};
 use crate::endpoints::Aggregates;
 use crate::mock;
 use crate::scylla;
 use crate::types;
 use crate::types::Action;
+use crate::types::DataScope;
 use crate::types::System;
 use crate::types::TimeRange;
 use crate::utils;
@@ -23,7 +25,13 @@ pub struct TestData {
 impl TestData {
     pub async fn new(scylla_url: &str) -> Self {
         Self {
-            scylla_client: scylla::Session::new(scylla_url).await,
+            scylla_client: scylla::Session::new(
+                scylla_url,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            )
+            .await,
             mock_client: mock::System::new(),
             dataset: dataset::DataSet::new(),
         }
@@ -120,13 +128,23 @@ impl TestData {
     ) {
         let time_from = timerange.from;
         let time_to = timerange.to;
+        let mut filter = Filter::new(action);
+        for (dimension, value) in [
+            (Dimension::Origin, origin),
+            (Dimension::BrandId, brand_id),
+            (Dimension::CategoryId, category_id),
+        ] {
+            if let Some(value) = value {
+                filter.values.insert(dimension, value.to_owned());
+            }
+        }
         let mock_buckets = self
             .mock_client
-            .select_bucket_stats(time_from, time_to, action, origin, brand_id, category_id)
+            .select_bucket_stats(time_from, time_to, &filter)
             .await;
         let scylla_buckets = self
             .scylla_client
-            .select_bucket_stats(time_from, time_to, action, origin, brand_id, category_id)
+            .select_bucket_stats(time_from, time_to, &filter)
             .await;
         assert_eq!(mock_buckets.len(), scylla_buckets.len());
         mock_buckets
@@ -139,7 +157,7 @@ impl TestData {
     }
 
     pub async fn clear(&self) {
-        self.scylla_client.clear().await;
-        self.mock_client.clear().await;
+        self.scylla_client.clear(DataScope::All, None).await;
+        self.mock_client.clear(DataScope::All, None).await;
     }
 }
@@ -1,15 +1,26 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use async_trait::async_trait;
-use chrono::{DateTime, DurationRound, NaiveDateTime, Utc};
+use chrono::{DateTime, DurationRound, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::aggregates::{Dimensions, Filter};
+use crate::codec::Encoder;
+use crate::dedup::{DedupWindow, IdempotencyKey};
+use crate::utils;
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 #[cfg_attr(test, derive(PartialEq, Eq, Hash))]
 pub struct UserTag {
-    pub time: DateTime<Utc>, // format: "2022-03-22T12:15:00.000Z"
+    #[schema(value_type = String, format = DateTime, example = "2022-03-22T12:15:00.000Z")]
+    pub time: UtcMillis, // format: "2022-03-22T12:15:00.000Z"
     //   millisecond precision
     //   with 'Z' suffix
     pub cookie: String,
@@ -18,9 +29,39 @@ pub struct UserTag {
     pub action: Action,
     pub origin: String,
     pub product_info: ProductInfo,
+    /// Custom properties of the event, beyond the spec.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl UserTag {
+    /// Approximate number of bytes the tag occupies in memory,
+    /// including its heap-allocated strings.
+    pub fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.cookie.len()
+            + self.country.len()
+            + self.origin.len()
+            + self.product_info.brand_id.len()
+            + self.product_info.category_id.len()
+            + self
+                .attributes
+                .iter()
+                .map(|(name, value)| 2 * std::mem::size_of::<String>() + name.len() + value.len())
+                .sum::<usize>()
+    }
+
+    /// Deterministic hash of the whole tag, which orders (and tells apart)
+    /// tags of a cookie registered at the same millisecond. It is a part of
+    /// the stored data, so it must not change between versions.
+    pub fn tie_breaker(&self) -> i64 {
+        let mut encoder = Encoder::new();
+        encoder.put_user_tag(self);
+        utils::stable_hash(&encoder.finish()) as i64
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
 #[cfg_attr(test, derive(Hash))]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Device {
@@ -29,8 +70,7 @@ pub enum Device {
     Tv,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
-#[cfg_attr(test, derive(Hash))]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Action {
     View,
@@ -46,7 +86,7 @@ impl Display for Action {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, ToSchema)]
 #[cfg_attr(test, derive(PartialEq, Eq, Hash))]
 pub struct ProductInfo {
     pub product_id: i32,
@@ -55,7 +95,7 @@ pub struct ProductInfo {
     pub price: i32,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct UtcMinute(DateTime<Utc>);
 impl From<DateTime<Utc>> for UtcMinute {
     fn from(time: DateTime<Utc>) -> Self {
@@ -92,16 +132,244 @@ impl UtcMinute {
     // }
 }
 
+/// Time with the precision of tags and of profile time ranges: milliseconds,
+/// which is also the precision of Scylla's `timestamp`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UtcMillis(DateTime<Utc>);
+impl From<DateTime<Utc>> for UtcMillis {
+    fn from(time: DateTime<Utc>) -> Self {
+        // Unlike `duration_trunc`, works with times beyond nanosecond timestamps.
+        let nanos = time.nanosecond() / 1_000_000 * 1_000_000;
+        Self(time.with_nanosecond(nanos).unwrap())
+    }
+}
+
+impl FromStr for UtcMillis {
+    type Err = chrono::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<DateTime<Utc>>().map(Self::from)
+    }
+}
+
+impl From<UtcMillis> for UtcMinute {
+    fn from(time: UtcMillis) -> Self {
+        Self::from(time.0)
+    }
+}
+
+impl Display for UtcMillis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.format("%Y-%m-%dT%H:%M:%S%.3fZ"))
+    }
+}
+
+impl UtcMillis {
+    pub fn inner(self) -> DateTime<Utc> {
+        self.0
+    }
+
+    pub fn from_timestamp_millis(millis: i64) -> Option<Self> {
+        DateTime::from_timestamp_millis(millis).map(Self)
+    }
+
+    pub fn timestamp_millis(self) -> i64 {
+        self.0.timestamp_millis()
+    }
+}
+
+/// Truncates times with a finer precision; see [`Precision`] for rejecting them.
+impl<'de> Deserialize<'de> for UtcMillis {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        DateTime::<Utc>::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl Serialize for UtcMillis {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// What happens to times finer than the spec's precision: milliseconds for
+/// tags and profile time ranges, and whole minutes for aggregate time ranges.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Precision {
+    /// They are truncated.
+    #[default]
+    Normalize,
+    /// They are rejected.
+    Reject,
+}
+
+impl Precision {
+    pub fn millis(self, time: DateTime<Utc>) -> Result<UtcMillis, String> {
+        let millis = UtcMillis::from(time);
+        if self == Precision::Reject && millis.inner() != time {
+            return Err(format!("time {} is finer than milliseconds", time));
+        }
+        Ok(millis)
+    }
+
+    pub fn minute(self, time: DateTime<Utc>) -> Result<UtcMinute, String> {
+        let minute = UtcMinute::from(time);
+        if self == Precision::Reject && minute.inner() != time {
+            return Err(format!("time {} is not a full minute", time));
+        }
+        Ok(minute)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct TimeRange {
     pub from: DateTime<Utc>,
     pub to: DateTime<Utc>,
 }
 
+impl TimeRange {
+    /// The range of a profile query, in milliseconds.
+    pub fn in_millis(self, precision: Precision) -> Result<Self, String> {
+        Ok(Self {
+            from: precision.millis(self.from)?.inner(),
+            to: precision.millis(self.to)?.inner(),
+        })
+    }
+
+    /// The range of an aggregates query, in whole minutes. It has to span
+    /// at least one of them.
+    pub fn in_minutes(self, precision: Precision) -> Result<Self, String> {
+        let range = Self {
+            from: precision.minute(self.from)?.inner(),
+            to: precision.minute(self.to)?.inner(),
+        };
+        if range.from >= range.to {
+            return Err(format!("time range {} spans no minute", range));
+        }
+        Ok(range)
+    }
+}
+
+/// Bound of a time range given in a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeBound {
+    /// Without an offset, as in the spec: in the time zone of the query.
+    Local(NaiveDateTime),
+    /// With an offset or `Z`, or relative to the current time.
+    Exact(DateTime<Utc>),
+}
+
+impl TimeBound {
+    /// Parses a bound in the spec's format (`2022-03-22T12:15:00.000`),
+    /// RFC 3339 (`2022-03-22T12:15:00Z`, `2022-03-22T14:15:00+02:00`),
+    /// or relative to `now` (`now`, `now-1h`, `now-1d+30m`).
+    fn parse(bound: &str, now: DateTime<Utc>) -> Result<Self, String> {
+        // An unescaped `+` of a query string arrives as a space.
+        let bound = bound.replace(' ', "+");
+        if let Some(mut offsets) = bound.strip_prefix("now") {
+            let mut time = now;
+            while !offsets.is_empty() {
+                let (sign, rest) = match offsets.split_at(1) {
+                    ("+", rest) => (1, rest),
+                    ("-", rest) => (-1, rest),
+                    _ => return Err(format!("expected '+' or '-' in {:?}", bound)),
+                };
+                let digits =
+                    rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                let (amount, rest) = rest.split_at(digits);
+                let amount = amount
+                    .parse::<i64>()
+                    .map_err(|_| format!("expected an amount in {:?}", bound))?;
+                let unit_len = rest.len()
+                    - rest
+                        .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+                        .len();
+                let (unit, rest) = rest.split_at(unit_len);
+                let duration = match unit {
+                    "ms" => chrono::Duration::try_milliseconds(amount),
+                    "s" => chrono::Duration::try_seconds(amount),
+                    "m" => chrono::Duration::try_minutes(amount),
+                    "h" => chrono::Duration::try_hours(amount),
+                    "d" => chrono::Duration::try_days(amount),
+                    "w" => chrono::Duration::try_weeks(amount),
+                    _ => return Err(format!("unknown unit {:?} in {:?}", unit, bound)),
+                }
+                .ok_or_else(|| format!("{:?} is out of range", bound))?;
+                time = time
+                    .checked_add_signed(duration * sign)
+                    .ok_or_else(|| format!("{:?} is out of range", bound))?;
+                offsets = rest;
+            }
+            return Ok(TimeBound::Exact(time));
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(&bound) {
+            return Ok(TimeBound::Exact(time.with_timezone(&Utc)));
+        }
+        NaiveDateTime::from_str(&bound)
+            .map(TimeBound::Local)
+            .map_err(|err| format!("invalid time {:?}: {}", bound, err))
+    }
+
+    fn in_zone(self, tz: Tz) -> Result<DateTime<Utc>, String> {
+        match self {
+            TimeBound::Exact(time) => Ok(time),
+            // Of a local time repeated when clocks go back, its first occurrence.
+            TimeBound::Local(time) => tz
+                .from_local_datetime(&time)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc))
+                .ok_or_else(|| format!("{} does not exist in {}", time, tz)),
+        }
+    }
+}
+
+/// Time range as given in a query, whose bounds without an offset are yet
+/// to be placed in the time zone of the query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRangeParam {
+    from: TimeBound,
+    to: TimeBound,
+}
+
+impl TimeRangeParam {
+    /// Parses `<from>_<to>`, with bounds in any of the formats of
+    /// [`TimeBound::parse`], e.g. `now-1h_now`.
+    pub fn parse(range: &str, now: DateTime<Utc>) -> Result<Self, String> {
+        let (from, to) = range
+            .split_once('_')
+            .ok_or_else(|| "expected underscore after first DateTime".to_owned())?;
+        Ok(Self {
+            from: TimeBound::parse(from, now)?,
+            to: TimeBound::parse(to, now)?,
+        })
+    }
+
+    pub fn in_zone(self, tz: Tz) -> Result<TimeRange, String> {
+        Ok(TimeRange {
+            from: self.from.in_zone(tz)?,
+            to: self.to.in_zone(tz)?,
+        })
+    }
+}
+
+impl From<TimeRange> for TimeRangeParam {
+    fn from(TimeRange { from, to }: TimeRange) -> Self {
+        Self {
+            from: TimeBound::Exact(from),
+            to: TimeBound::Exact(to),
+        }
+    }
+}
+
 struct TimeRangeVisitor;
 
 impl<'de> Visitor<'de> for TimeRangeVisitor {
-    type Value = TimeRange;
+    type Value = TimeRangeParam;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
         formatter.write_str("time_range")
@@ -111,15 +379,33 @@ impl<'de> Visitor<'de> for TimeRangeVisitor {
     where
         E: Error,
     {
-        let (time_from, time_to) = v
-            .split_once('_')
-            .ok_or_else(|| E::custom("expected underscore after first DateTime"))?;
-        let from = DateTime::from_utc(NaiveDateTime::from_str(time_from).map_err(E::custom)?, Utc);
-        let to = DateTime::from_utc(NaiveDateTime::from_str(time_to).map_err(E::custom)?, Utc);
-        Ok(Self::Value { from, to })
+        TimeRangeParam::parse(v, Utc::now()).map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeRangeParam {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(TimeRangeVisitor)
+    }
+}
+
+impl Serialize for TimeRangeParam {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let time = |bound| match bound {
+            TimeBound::Local(time) => time.format("%Y-%m-%dT%H:%M:%S%.3f").to_string(),
+            TimeBound::Exact(time) => time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        };
+        serializer.collect_str(&format_args!("{}_{}", time(self.from), time(self.to)))
     }
 }
 
+/// The spec's format, in UTC.
 impl Display for TimeRange {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         fn allezon_datetime(datetime: DateTime<Utc>) -> String {
@@ -137,12 +423,16 @@ impl Display for TimeRange {
     }
 }
 
+/// Accepts all the formats of [`TimeRangeParam`], with times without
+/// an offset in UTC.
 impl<'de> Deserialize<'de> for TimeRange {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        deserializer.deserialize_str(TimeRangeVisitor)
+        TimeRangeParam::deserialize(deserializer)?
+            .in_zone(Tz::UTC)
+            .map_err(D::Error::custom)
     }
 }
 
@@ -156,7 +446,7 @@ impl Serialize for TimeRange {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 #[cfg_attr(test, derive(PartialEq, Eq, Hash))]
 pub struct UserProfile {
     pub cookie: String,
@@ -164,17 +454,203 @@ pub struct UserProfile {
     pub buys: Vec<UserTag>,
 }
 
+/// Time and tie breaker of a tag, which order tags of a profile.
+pub type TagPosition = (UtcMillis, i64);
+
+/// Where the next page of a profile starts, for each kind of its tags:
+/// right after the tag with the given time and tie breaker (tags are listed
+/// from the newest), or nowhere when all of them have been listed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProfileCursor {
+    pub views: Option<TagPosition>,
+    pub buys: Option<TagPosition>,
+}
+
+impl ProfileCursor {
+    /// Opaque form of the cursor, handed out to clients.
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::new();
+        for position in [self.views, self.buys] {
+            match position {
+                Some((time, tie_breaker)) => {
+                    bytes.push(1);
+                    bytes.extend(time.timestamp_millis().to_be_bytes());
+                    bytes.extend(tie_breaker.to_be_bytes());
+                }
+                None => bytes.push(0),
+            }
+        }
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let mut rest = bytes.as_slice();
+        let mut take = |len: usize| {
+            let taken = rest.get(..len)?;
+            rest = &rest[len..];
+            Some(taken)
+        };
+        let mut position = || match take(1)? {
+            [0] => Some(None),
+            [1] => {
+                let millis = i64::from_be_bytes(take(8)?.try_into().unwrap());
+                let tie_breaker = i64::from_be_bytes(take(8)?.try_into().unwrap());
+                Some(Some((
+                    UtcMillis::from_timestamp_millis(millis)?,
+                    tie_breaker,
+                )))
+            }
+            _ => None,
+        };
+        let cursor = ProfileCursor {
+            views: position()?,
+            buys: position()?,
+        };
+        rest.is_empty().then_some(cursor)
+    }
+}
+
+/// Page of tags of one kind of a profile.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TagsPage {
+    /// From the newest.
+    pub tags: Vec<UserTag>,
+    /// Number of tags of the kind in the time range, on all pages. Only
+    /// counted for the first page, i.e. without `after`.
+    pub total: Option<usize>,
+    /// Position of the last listed tag, when more tags follow it.
+    pub next: Option<TagPosition>,
+}
+
+impl TagsPage {
+    /// Page of `tags`, which are all the tags in the time range, from the newest.
+    pub fn of<'a>(
+        tags: impl Iterator<Item = (TagPosition, &'a UserTag)>,
+        after: Option<TagPosition>,
+        limit: usize,
+    ) -> Self {
+        let mut page = TagsPage::default();
+        let mut total = 0;
+        let mut last_listed = None;
+        for (position, tag) in tags {
+            total += 1;
+            if after.is_some_and(|after| position >= after) {
+                continue;
+            }
+            if page.tags.len() < limit {
+                page.tags.push(tag.clone());
+                last_listed = Some(position);
+            } else {
+                page.next = last_listed;
+            }
+        }
+        page.total = after.is_none().then_some(total);
+        page
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Bucket {
     pub minute: UtcMinute,
-    pub count: i32,
-    pub sum_price: i32,
+    pub count: i64,
+    pub sum_price: i64,
+}
+
+/// Part of the stored data, e.g. the one affected by [`System::clear`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataScope {
+    /// User tags kept for use case 2.
+    Profiles,
+    /// Minute buckets kept for use case 3.
+    Aggregates,
+    #[default]
+    All,
+}
+
+impl DataScope {
+    pub fn includes_profiles(self) -> bool {
+        matches!(self, DataScope::Profiles | DataScope::All)
+    }
+
+    pub fn includes_aggregates(self) -> bool {
+        matches!(self, DataScope::Aggregates | DataScope::All)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotInfo {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Estimate of the memory held by a backend. Sizes are approximations:
+/// they account for the stored data, but not for allocator and
+/// collection overheads.
+#[derive(Debug, Default, Serialize)]
+pub struct MemoryUsage {
+    pub cookies: usize,
+    pub profile_tags: usize,
+    pub profiles_bytes: usize,
+    pub minutes: usize,
+    pub aggregate_entries: usize,
+    pub aggregates_bytes: usize,
+    pub total_bytes: usize,
 }
 
 #[async_trait]
 pub trait System: Sync + Send {
     async fn register_user_tag(&self, user_tag: UserTag);
 
+    /// Dimensions which aggregates are kept for.
+    fn dimensions(&self) -> &Dimensions;
+
+    /// Keys of recently registered events, if the system deduplicates them.
+    fn dedup_window(&self) -> Option<&DedupWindow> {
+        None
+    }
+
+    /// Registers the tag unless an event with the same key has been registered
+    /// recently (in both profiles and aggregates). Returns whether it was registered.
+    async fn register_user_tag_once(&self, user_tag: UserTag, key: IdempotencyKey) -> bool {
+        // The key is only kept once the tag is registered, so that retries
+        // of a failed registration are not taken for duplicates.
+        let reservation = match self.dedup_window() {
+            Some(window) => match window.reserve(key) {
+                Some(reservation) => Some(reservation),
+                None => return false,
+            },
+            None => None,
+        };
+        self.register_user_tag(user_tag).await;
+        if let Some(reservation) = reservation {
+            reservation.commit();
+        }
+        true
+    }
+
+    /// Whether [`System::register_user_tag_in`] supports parts of the data,
+    /// as needed to be a cluster replica.
+    fn supports_scopes(&self) -> bool {
+        false
+    }
+
+    /// Registers the tag in `scope` only, for systems holding the rest of it
+    /// elsewhere (e.g. cluster replicas). Only the whole scope is supported
+    /// unless [`System::supports_scopes`].
+    async fn register_user_tag_in(&self, user_tag: UserTag, scope: DataScope) {
+        assert_eq!(
+            scope,
+            DataScope::All,
+            "partial registration is not supported"
+        );
+        self.register_user_tag(user_tag).await;
+    }
+
     async fn last_tags_by_cookie<'a>(
         &'a self,
         cookie: &'a str,
@@ -183,17 +659,69 @@ pub trait System: Sync + Send {
         limit: usize,
     ) -> UserProfile;
 
+    /// Page of tags of the cookie with the action in the time range, from the
+    /// newest, starting right after the tag at `after`. Unlike
+    /// [`System::last_tags_by_cookie`], pages reach all the retained tags;
+    /// by default, only the newest 200 of them.
+    async fn tags_page(
+        &self,
+        cookie: &str,
+        action: Action,
+        time_range: TimeRange,
+        after: Option<TagPosition>,
+        limit: usize,
+    ) -> TagsPage {
+        let profile = self
+            .last_tags_by_cookie(cookie, time_range.from, time_range.to, 200)
+            .await;
+        let tags = match action {
+            Action::View => profile.views,
+            Action::Buy => profile.buys,
+        };
+        TagsPage::of(
+            tags.iter().map(|tag| ((tag.time, tag.tie_breaker()), tag)),
+            after,
+            limit,
+        )
+    }
+
+    /// Tells whether buckets of the time range can be selected with the
+    /// filter, that is whether its dimensions are kept for those minutes.
+    async fn check_filter(
+        &self,
+        _time_from: DateTime<Utc>,
+        _time_to: DateTime<Utc>,
+        filter: &Filter,
+    ) -> Result<(), String> {
+        self.dimensions().check(filter)
+    }
+
+    /// Buckets of the minutes in the time range. The filter has passed
+    /// [`System::check_filter`].
     async fn select_bucket_stats(
         &self,
         time_from: DateTime<Utc>,
         time_to: DateTime<Utc>,
-        action: Action,
-        origin: Option<&str>,
-        brand_id: Option<&str>,
-        category_id: Option<&str>,
+        filter: &Filter,
     ) -> Vec<Bucket>;
 
-    async fn clear(&self);
+    /// Removes data within `scope`. If `time_range` is given, only data
+    /// related to events from `[from, to)` is removed; aggregates are removed
+    /// with a whole-minute granularity.
+    async fn clear(&self, scope: DataScope, time_range: Option<TimeRange>);
+
+    /// Writes a snapshot of the whole state to the configured location.
+    /// Returns `None` if the system does not support (or is not configured
+    /// for) snapshots.
+    async fn snapshot(&self) -> Option<io::Result<SnapshotInfo>> {
+        None
+    }
+
+    /// Estimates the memory used by the stored data. Returns `None`
+    /// if the system does not keep its data in memory.
+    async fn memory_usage(&self) -> Option<MemoryUsage> {
+        None
+    }
 }
 
 #[cfg(test)]
@@ -218,6 +746,123 @@ mod tests {
             serde_json::from_str("\"2022-03-22T12:15:00.000_2022-03-22T12:30:00.000\"").unwrap();
     }
 
+    #[test]
+    fn time_range_accepts_offsets_relative_times_and_zones() {
+        let parse = |range: &str| {
+            serde_json::from_str::<TimeRange>(&format!("\"{}\"", range))
+                .map(|range| (range.from, range.to))
+        };
+        let from: DateTime<Utc> = "2022-03-22T12:15:00Z".parse().unwrap();
+        let to: DateTime<Utc> = "2022-03-22T12:30:00Z".parse().unwrap();
+
+        assert_eq!(
+            parse("2022-03-22T12:15:00_2022-03-22T12:30:00").unwrap(),
+            (from, to)
+        );
+        assert_eq!(
+            parse("2022-03-22T12:15:00Z_2022-03-22T14:30:00+02:00").unwrap(),
+            (from, to)
+        );
+        // As sent in a query string without escaping `+`.
+        assert_eq!(
+            parse("2022-03-22T12:15:00.000Z_2022-03-22T14:30:00 02:00").unwrap(),
+            (from, to)
+        );
+        assert!(parse("2022-03-22T12:15:00").is_err());
+        assert!(parse("yesterday_today").is_err());
+
+        let now = to;
+        let relative = |range| TimeRangeParam::parse(range, now)?.in_zone(Tz::UTC);
+        let range = relative("now-15m_now").unwrap();
+        assert_eq!((range.from, range.to), (from, to));
+        let range = relative("now-1h+45m_now+0s").unwrap();
+        assert_eq!((range.from, range.to), (from, to));
+        assert!(relative("now-1y_now").is_err());
+        assert!(relative("now-h_now").is_err());
+        // Parsed, but rejected once aligned to minutes.
+        for empty in ["now_now", "now+10s_now+50s", "now_now-15m"] {
+            let range = relative(empty).unwrap();
+            assert!(range.in_minutes(Precision::Normalize).is_err());
+        }
+
+        let local = TimeRangeParam::parse("2022-03-22T13:15:00_2022-03-22T12:30:00Z", now).unwrap();
+        let range = local.in_zone(Tz::Europe__Warsaw).unwrap();
+        assert_eq!((range.from, range.to), (from, to));
+        // Clocks went forward at 2:00 that day.
+        let skipped = TimeRangeParam::parse("2022-03-27T02:30:00_now", now).unwrap();
+        assert!(skipped.in_zone(Tz::Europe__Warsaw).is_err());
+        assert!(skipped.in_zone(Tz::UTC).is_ok());
+    }
+
+    #[test]
+    fn time_range_displays_in_spec_format() {
+        let range: TimeRange =
+            serde_json::from_str("\"2022-03-22T13:15:00+01:00_2022-03-22T12:30:00.000Z\"").unwrap();
+        assert_eq!(range.to_string(), "2022-03-22T12:15:00_2022-03-22T12:30:00");
+    }
+
+    #[test]
+    fn times_are_truncated_or_rejected_to_spec_precision() {
+        let time: UtcMillis = serde_json::from_str("\"2022-03-22T12:15:00.123456Z\"").unwrap();
+        assert_eq!(
+            serde_json::to_string(&time).unwrap(),
+            "\"2022-03-22T12:15:00.123Z\""
+        );
+        assert_eq!(time, "2022-03-22T12:15:00.123Z".parse().unwrap());
+
+        let exact: DateTime<Utc> = "2022-03-22T12:15:00.123Z".parse().unwrap();
+        let finer = exact + chrono::Duration::microseconds(456);
+        assert_eq!(Precision::Normalize.millis(finer), Ok(time));
+        assert_eq!(Precision::Reject.millis(exact), Ok(time));
+        assert!(Precision::Reject.millis(finer).is_err());
+
+        let range = TimeRange {
+            from: exact,
+            to: exact + chrono::Duration::minutes(1),
+        };
+        let minutes = range.in_minutes(Precision::Normalize).unwrap();
+        assert_eq!(
+            minutes.to_string(),
+            "2022-03-22T12:15:00_2022-03-22T12:16:00"
+        );
+        assert!(range.in_minutes(Precision::Reject).is_err());
+        assert!(range.in_millis(Precision::Reject).is_ok());
+    }
+
+    #[test]
+    fn tie_breaker_is_stable() {
+        let tag: UserTag = serde_json::from_str(
+            r#"{
+                "time": "2022-03-22T12:15:00.000Z",
+                "cookie": "user",
+                "country": "PL",
+                "device": "PC",
+                "action": "VIEW",
+                "origin": "Rawa",
+                "product_info": {
+                    "product_id": 2137,
+                    "brand_id": "apple",
+                    "category_id": "fruit",
+                    "price": 50
+                }
+            }"#,
+        )
+        .unwrap();
+        // Stored in Scylla, so a change would break ordering of existing data.
+        assert_eq!(tag.tie_breaker(), 4046685442583509161);
+    }
+
+    #[test]
+    fn profile_cursor_round_trips() {
+        let cursor = ProfileCursor {
+            views: Some(("2022-03-22T12:15:00.123Z".parse().unwrap(), -42)),
+            buys: None,
+        };
+        assert_eq!(ProfileCursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(ProfileCursor::decode(&cursor.encode()[2..]), None);
+        assert_eq!(ProfileCursor::decode("zz"), None);
+    }
+
     #[test]
     fn deserialize_user_tag() {
         let tag_str = r#"
@@ -1,3 +1,5 @@
+use std::{fs, io, path::Path};
+
 use chrono::{DateTime, Utc};
 
 use crate::types;
@@ -25,7 +27,35 @@ fn check_user_tags_vector(
     }
 
     for user_tag in user_tags {
-        assert!(user_tag.time >= time_from);
-        assert!(user_tag.time <= time_to);
+        assert!(user_tag.time.inner() >= time_from);
+        assert!(user_tag.time.inner() <= time_to);
     }
 }
+
+/// 64-bit FNV-1a hash. Unlike the standard library hashers, it is guaranteed
+/// to stay the same across processes and versions, so it can be persisted.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Compares the whole inputs regardless of where the first mismatch is,
+/// so that the time taken does not reveal a secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Replaces the file at `path` with `contents`, so that after a crash
+/// either the old or the new contents are there in whole.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
@@ -0,0 +1,247 @@
+//! Tracking of event-time progress of ingested tags.
+//!
+//! The watermark trails the newest event time seen by a fixed out-of-orderness
+//! bound. Each incoming tag is classified against it: tags not older than the
+//! watermark are on time, tags older by at most the allowed lateness are late
+//! (but still applied), and older ones are too late. Tags too far ahead of the
+//! wall clock would create bogus buckets, so they are not applied either.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::types::UserTag;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How far behind the newest event time the watermark is.
+    pub out_of_orderness: chrono::Duration,
+    /// How far behind the watermark a tag may be to still be applied.
+    pub allowed_lateness: chrono::Duration,
+    /// How far ahead of the wall clock a tag may be.
+    pub max_ahead: chrono::Duration,
+    /// File that tags which are not applied are appended to (as JSON lines).
+    /// When unset, such tags are rejected.
+    pub dead_letter_path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            out_of_orderness: chrono::Duration::seconds(5),
+            allowed_lateness: chrono::Duration::hours(1),
+            max_ahead: chrono::Duration::minutes(5),
+            dead_letter_path: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventClass {
+    OnTime,
+    Late,
+    TooLate,
+    TooFuture,
+}
+
+impl EventClass {
+    /// Whether tags of this class are applied to the system.
+    pub fn is_accepted(self) -> bool {
+        matches!(self, EventClass::OnTime | EventClass::Late)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub newest_event: Option<DateTime<Utc>>,
+    pub watermark: Option<DateTime<Utc>>,
+    pub on_time: u64,
+    pub late: u64,
+    pub too_late: u64,
+    pub too_future: u64,
+    pub dead_lettered: u64,
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    class: EventClass,
+    received_at: DateTime<Utc>,
+    tag: &'a UserTag,
+}
+
+#[derive(Debug)]
+pub struct Watermark {
+    config: Config,
+    /// Newest accepted event time in milliseconds since the epoch,
+    /// `i64::MIN` before the first one.
+    newest_event: AtomicI64,
+    /// Indexed by `EventClass as usize`.
+    counters: [AtomicU64; 4],
+    dead_lettered: AtomicU64,
+    dead_letters: Option<Mutex<tokio::fs::File>>,
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap()
+}
+
+impl Watermark {
+    pub fn new(config: Config) -> std::io::Result<Self> {
+        let dead_letters = match &config.dead_letter_path {
+            Some(path) => Some(Mutex::new(tokio::fs::File::from_std(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ))),
+            None => None,
+        };
+        Ok(Self {
+            config,
+            newest_event: AtomicI64::new(i64::MIN),
+            counters: Default::default(),
+            dead_lettered: AtomicU64::new(0),
+            dead_letters,
+        })
+    }
+
+    /// Whether tags which are not accepted are kept instead of being rejected.
+    pub fn diverts(&self) -> bool {
+        self.dead_letters.is_some()
+    }
+
+    /// The newest accepted event time minus the out-of-orderness, if any.
+    pub fn current(&self) -> Option<DateTime<Utc>> {
+        self.watermark(self.newest_event.load(Ordering::Relaxed))
+    }
+
+    fn watermark(&self, newest_event: i64) -> Option<DateTime<Utc>> {
+        (newest_event != i64::MIN).then(|| from_millis(newest_event) - self.config.out_of_orderness)
+    }
+
+    /// Classifies the event time, counts it and, if the tag is to be applied,
+    /// advances the watermark with it.
+    pub fn observe(&self, time: DateTime<Utc>, now: DateTime<Utc>) -> EventClass {
+        let class = if time > now + self.config.max_ahead {
+            EventClass::TooFuture
+        } else {
+            match self.watermark(self.newest_event.load(Ordering::Relaxed)) {
+                Some(watermark) if time < watermark - self.config.allowed_lateness => {
+                    EventClass::TooLate
+                }
+                Some(watermark) if time < watermark => EventClass::Late,
+                _ => EventClass::OnTime,
+            }
+        };
+        if class.is_accepted() {
+            self.newest_event
+                .fetch_max(time.timestamp_millis(), Ordering::Relaxed);
+        }
+        self.counters[class as usize].fetch_add(1, Ordering::Relaxed);
+        class
+    }
+
+    /// Appends a tag that was not applied to the dead-letter file.
+    /// Does nothing if there is none configured.
+    pub async fn divert(&self, tag: &UserTag, class: EventClass) -> std::io::Result<()> {
+        let Some(dead_letters) = &self.dead_letters else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(&DeadLetter {
+            class,
+            received_at: Utc::now(),
+            tag,
+        })?;
+        line.push(b'\n');
+        let mut dead_letters = dead_letters.lock().await;
+        dead_letters.write_all(&line).await?;
+        dead_letters.flush().await?;
+        self.dead_lettered.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let newest_event = self.newest_event.load(Ordering::Relaxed);
+        let count = |class: EventClass| self.counters[class as usize].load(Ordering::Relaxed);
+        Stats {
+            newest_event: (newest_event != i64::MIN).then(|| from_millis(newest_event)),
+            watermark: self.watermark(newest_event),
+            on_time: count(EventClass::OnTime),
+            late: count(EventClass::Late),
+            too_late: count(EventClass::TooLate),
+            too_future: count(EventClass::TooFuture),
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::tests::{default_tag, moment_middle};
+
+    use super::*;
+
+    #[test]
+    fn tags_are_classified_against_watermark() {
+        let watermark = Watermark::new(Config {
+            out_of_orderness: chrono::Duration::seconds(5),
+            allowed_lateness: chrono::Duration::minutes(1),
+            max_ahead: chrono::Duration::minutes(5),
+            dead_letter_path: None,
+        })
+        .unwrap();
+        let now = moment_middle();
+        let ago = |secs| now - chrono::Duration::seconds(secs);
+
+        assert_eq!(watermark.observe(ago(600), now), EventClass::OnTime);
+        assert_eq!(watermark.observe(ago(0), now), EventClass::OnTime);
+        assert_eq!(watermark.observe(ago(5), now), EventClass::OnTime);
+        assert_eq!(watermark.observe(ago(6), now), EventClass::Late);
+        assert_eq!(watermark.observe(ago(65), now), EventClass::Late);
+        assert_eq!(watermark.observe(ago(66), now), EventClass::TooLate);
+        assert_eq!(
+            watermark.observe(now + chrono::Duration::minutes(6), now),
+            EventClass::TooFuture
+        );
+
+        let stats = watermark.stats();
+        assert_eq!(stats.newest_event, Some(now));
+        assert_eq!(stats.watermark, Some(ago(5)));
+        assert_eq!(
+            (stats.on_time, stats.late, stats.too_late, stats.too_future),
+            (3, 2, 1, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_tags_are_dead_lettered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead_letters");
+        let watermark = Watermark::new(Config {
+            dead_letter_path: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(watermark.diverts());
+
+        let tag = UserTag {
+            time: moment_middle().into(),
+            ..default_tag()
+        };
+        watermark.divert(&tag, EventClass::TooLate).await.unwrap();
+        watermark.divert(&tag, EventClass::TooFuture).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["class"], "too_late");
+        assert_eq!(first["tag"]["cookie"], tag.cookie);
+        assert_eq!(watermark.stats().dead_lettered, 2);
+    }
+}